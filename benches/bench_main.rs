@@ -4,5 +4,6 @@ mod setup;
 use criterion::criterion_main;
 
 criterion_main! {
+    benchmarks::memtable_parallel::benches,
     benchmarks::put_parallel::benches,
 }
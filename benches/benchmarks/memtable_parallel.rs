@@ -0,0 +1,229 @@
+use criterion::{criterion_group, BatchSize, BenchmarkId, Criterion};
+use horreum::memtable::SkipList;
+use horreum::{serve, Backlog, MemTable, MemTableBackend, SyncMode, WriteController};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+/// Number of operations each thread or task applies in an iteration.
+const OPERATIONS: usize = 1000;
+
+/// Number of requests each task sends to the server in an iteration.
+const REQUESTS: usize = 100;
+
+/// Port of the server which the first backend serves at.
+const PORT: u16 = 8090;
+
+/// Number of keys operations are applied to.
+const KEYS: usize = 256;
+
+/// Limit of `MemTable` size, which is small enough for contents to be flushed in iterations.
+const MEMTABLE_LIMIT: usize = 64 * 1024;
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:05}", i % KEYS).into_bytes()
+}
+
+/// Every fourth operation is a write and the others are reads.
+fn is_write(i: usize) -> bool {
+    i.is_multiple_of(4)
+}
+
+/// The map `MemTable` used to hold behind a lock.
+fn locked_btreemap(threads: usize, map: &RwLock<BTreeMap<Vec<u8>, Vec<u8>>>) {
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                for i in 0..OPERATIONS {
+                    let key = key(thread * OPERATIONS + i);
+                    if is_write(i) {
+                        map.write().unwrap().insert(key, b"value".to_vec());
+                    } else {
+                        criterion::black_box(map.read().unwrap().get(&key).cloned());
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn skiplist(threads: usize, list: &SkipList, sequence: &AtomicU64) {
+    thread::scope(|scope| {
+        for thread in 0..threads {
+            scope.spawn(move || {
+                for i in 0..OPERATIONS {
+                    let key = key(thread * OPERATIONS + i);
+                    if is_write(i) {
+                        let sequence = sequence.fetch_add(1, Ordering::Relaxed) + 1;
+                        list.insert(&key, Some(b"value"), sequence);
+                    } else {
+                        criterion::black_box(list.get(&key, u64::MAX));
+                    }
+                }
+            });
+        }
+    });
+}
+
+async fn memtable(tasks: usize, table: &Arc<MemTable>) {
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let table = table.clone();
+            tokio::spawn(async move {
+                for i in 0..OPERATIONS {
+                    let key = key(task * OPERATIONS + i);
+                    if is_write(i) {
+                        table.put(key, b"value".to_vec()).await.unwrap();
+                    } else {
+                        criterion::black_box(table.read(&key, None).await.unwrap());
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+/// Apply operations like `memtable()` by HTTP requests, which `MemTable` receives from the server
+/// and applies on tasks of its own.
+async fn server(tasks: usize, client: &Client<HttpConnector>, port: u16) {
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                for i in 0..REQUESTS {
+                    let key = String::from_utf8(key(task * REQUESTS + i)).unwrap();
+                    let (method, query) = if is_write(i) {
+                        (Method::PUT, format!("key={}&value=value", key))
+                    } else {
+                        (Method::GET, format!("key={}", key))
+                    };
+                    let request = Request::builder()
+                        .method(method)
+                        .uri(format!("http://localhost:{}/?{}", port, query))
+                        .body(Body::empty())
+                        .unwrap();
+                    let response = client.request(request).await.unwrap();
+                    assert_eq!(StatusCode::OK, response.status());
+                    criterion::black_box(
+                        hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+pub fn bench_memtable(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Read and write MemTable in parallel");
+    // Both start from all keys, because the skip list keeps every version it inserts.
+    for threads in [1, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::new("locked_btreemap()", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched(
+                    || RwLock::new((0..KEYS).map(|i| (key(i), b"value".to_vec())).collect()),
+                    |map| {
+                        locked_btreemap(threads, &map);
+                        map
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("skiplist()", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_batched(
+                    || {
+                        let list = SkipList::new();
+                        for i in 0..KEYS {
+                            list.insert(&key(i), Some(b"value"), i as u64 + 1);
+                        }
+                        (list, AtomicU64::new(KEYS as u64))
+                    },
+                    |(list, sequence)| {
+                        skiplist(threads, &list, &sequence);
+                        list
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+
+    let runtime = Runtime::new().unwrap();
+    let _ = std::fs::remove_dir_all("bench_memtable");
+    let client = Client::new();
+    for (port, backend) in (PORT..).zip([MemTableBackend::BTreeMap, MemTableBackend::SkipList]) {
+        let directory = format!("bench_memtable/{:?}", backend);
+        std::fs::create_dir_all(&directory).unwrap();
+        let table = runtime.block_on(async {
+            let (memtable_tx, command_rx) = mpsc::channel(32);
+            let (sstable_tx, mut sstable_rx) = mpsc::channel(32);
+            let write_controller = Arc::new(WriteController::new(
+                Backlog::UNLIMITED,
+                Backlog {
+                    immutables: 2,
+                    ..Backlog::UNLIMITED
+                },
+                Duration::ZERO,
+                Duration::ZERO,
+            ));
+            let table = MemTable::new(
+                &directory,
+                backend,
+                MEMTABLE_LIMIT,
+                write_controller.clone(),
+                SyncMode::None,
+                command_rx,
+                sstable_tx,
+            )
+            .await
+            .unwrap();
+            // Flushed contents are discarded and SSTables have no data.
+            tokio::spawn(async move {
+                while let Some((_, tx)) = sstable_rx.recv().await {
+                    let _ = tx.send(Ok(None));
+                }
+            });
+            let table = Arc::new(table);
+            tokio::spawn(table.clone().listen());
+            tokio::spawn(serve(port, memtable_tx, write_controller));
+            // Wait for the server to accept connections.
+            let url = format!("http://localhost:{}/?key=key", port);
+            while client.get(url.parse().unwrap()).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            table
+        });
+        for tasks in [1, 4, 8] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("memtable({:?})", backend), tasks),
+                &tasks,
+                |b, &tasks| b.iter(|| runtime.block_on(memtable(tasks, &table))),
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("server({:?})", backend), tasks),
+                &tasks,
+                |b, &tasks| b.iter(|| runtime.block_on(server(tasks, &client, port))),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_memtable);
@@ -1 +1,2 @@
+pub mod memtable_parallel;
 pub mod put_parallel;
@@ -1,15 +1,20 @@
-use crate::setup::{launch_db, COUNT, PAIRS};
-use criterion::{criterion_group, BenchmarkId, Criterion};
-use futures::Future;
-use reqwest::Client;
+use crate::setup::PAIRS;
+use criterion::{criterion_group, Criterion};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
 use tokio::runtime::Runtime;
 
-async fn put_pairs(client: &Client, port: usize) {
+async fn put_pairs(client: &Client<HttpConnector>, port: usize) {
     let futures = futures::future::join_all(PAIRS.iter().map(|(key, value)| {
         let client = client.clone();
         tokio::spawn(async move {
             let url = format!("http://localhost:{}/?key={}&value={}", port, key, value);
-            if let Err(err) = client.post(&url).send().await {
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(url)
+                .body(Body::empty())
+                .unwrap();
+            if let Err(err) = client.request(request).await {
                 panic!("{}", err);
             }
         })
@@ -25,13 +30,13 @@ pub fn bench_put(c: &mut Criterion) {
 
     group.bench_function("put_pairs_to_one_threaded()", |b| {
         b.iter(|| {
-            let mut rt = Runtime::new().unwrap();
+            let rt = Runtime::new().unwrap();
             rt.block_on(put_pairs(&client, 8081));
         });
     });
     group.bench_function("put_pairs_to_multi_threaded()", |b| {
         b.iter(|| {
-            let mut rt = Runtime::new().unwrap();
+            let rt = Runtime::new().unwrap();
             rt.block_on(put_pairs(&client, 8082))
         });
     });
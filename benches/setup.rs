@@ -11,6 +11,7 @@ lazy_static! {
         .collect();
 }
 
+#[allow(dead_code)]
 pub fn launch_db(n: usize, port: usize) -> Child {
     let mut command = Command::new("./target/debug/main");
     command.arg(format!("-n {} -p {}", n, port));
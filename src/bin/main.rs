@@ -1,4 +1,5 @@
 use horreum::{serve, Config, MemTable, SSTableManager};
+use std::sync::Arc;
 use structopt::StructOpt;
 use tokio::sync::mpsc;
 
@@ -10,24 +11,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (sstable_tx, sstable_rx) = mpsc::channel(32);
     let config = Config::from_args();
     dbg!(&config);
-    let mut memtable = MemTable::new(config.memtable_limit, memtable_rx, sstable_tx.clone());
+    if let Err(err) = std::fs::create_dir_all(&config.directory) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    let write_controller = match config.write_controller() {
+        Ok(write_controller) => Arc::new(write_controller),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let memtable = match MemTable::new(
+        &config.directory,
+        config.memtable_backend,
+        config.memtable_limit,
+        write_controller.clone(),
+        config.sync,
+        memtable_rx,
+        sstable_tx,
+    )
+    .await
+    {
+        Ok(m) => m,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
     let mut manager = match SSTableManager::new(
-        config.directory,
+        &config.directory,
         config.block_stride,
+        config.bloom_bits_per_key,
         config.compaction_trigger_ratio,
+        config.block_cache_size,
+        config.sync,
         sstable_rx,
     )
     .await
     {
-        Ok(m) => m,
+        Ok(m) => m.with_write_controller(write_controller.clone()),
         Err(err) => {
             eprintln!("{}", err);
             std::process::exit(1);
         }
     };
 
-    tokio::spawn(async move { memtable.listen().await });
+    tokio::spawn(Arc::new(memtable).listen());
     tokio::spawn(async move { manager.listen().await });
-    serve(config.port, memtable_tx, sstable_tx).await?;
+    serve(config.port, memtable_tx, write_controller).await?;
     Ok(())
 }
@@ -0,0 +1,186 @@
+use crate::memtable::MemoryUsage;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes of memory held by each component.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct MemoryBreakdown {
+    /// Contents of `MemTable` which accept writes.
+    pub memtable: usize,
+
+    /// Contents of `MemTable` being flushed.
+    pub immutables: usize,
+
+    /// Indexes and Bloom filters of SSTables.
+    pub indexes: usize,
+
+    /// Blocks in the block cache.
+    pub block_cache: usize,
+}
+
+impl MemoryBreakdown {
+    pub fn total(&self) -> usize {
+        self.memtable + self.immutables + self.indexes + self.block_cache
+    }
+}
+
+/// Ceiling of memory shared by `MemTable` and SSTables.
+/// When the usage exceeds the limit, blocks are evicted from the block cache first, and
+/// `MemTable` is flushed early if evicting them is not enough.
+/// Indexes are needed as long as their tables live, so they are not freed by the budget.
+/// If the usage exceeds the limit even without the block cache, writes wait while flushes in
+/// progress free memory.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// Limit in bytes. If it is 0, memory is not limited.
+    limit: usize,
+
+    memtable: AtomicUsize,
+    immutables: AtomicUsize,
+    indexes: AtomicUsize,
+    block_cache: AtomicUsize,
+}
+
+impl Default for MemoryBudget {
+    /// Never limit memory.
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            memtable: AtomicUsize::new(0),
+            immutables: AtomicUsize::new(0),
+            indexes: AtomicUsize::new(0),
+            block_cache: AtomicUsize::new(0),
+        }
+    }
+
+    /// Limit in bytes, or `None` if memory is not limited.
+    pub fn limit(&self) -> Option<usize> {
+        Some(self.limit).filter(|limit| *limit > 0)
+    }
+
+    /// Current usage of each component.
+    pub fn usage(&self) -> MemoryBreakdown {
+        MemoryBreakdown {
+            memtable: self.memtable.load(Ordering::Relaxed),
+            immutables: self.immutables.load(Ordering::Relaxed),
+            indexes: self.indexes.load(Ordering::Relaxed),
+            block_cache: self.block_cache.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn set_memtable(&self, usage: MemoryUsage) {
+        self.memtable.store(usage.active, Ordering::Relaxed);
+        self.immutables.store(usage.immutables, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_indexes(&self, indexes: usize) {
+        self.indexes.store(indexes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_block_cache(&self, block_cache: usize) {
+        self.block_cache.store(block_cache, Ordering::Relaxed);
+    }
+
+    /// Bytes the block cache should shrink to so that the usage fits in the limit, or `None`
+    /// if it already fits.
+    pub(crate) fn block_cache_target(&self) -> Option<usize> {
+        let limit = self.limit()?;
+        let usage = self.usage();
+        let excess = usage
+            .total()
+            .checked_sub(limit)
+            .filter(|excess| *excess > 0)?;
+        Some(usage.block_cache.saturating_sub(excess))
+    }
+
+    /// Return `true` if `MemTable` and indexes alone exceed the limit, which evicting the block
+    /// cache cannot fix.
+    pub(crate) fn exceeds_limit(&self) -> bool {
+        match self.limit() {
+            Some(limit) => {
+                let usage = self.usage();
+                usage.memtable + usage.immutables + usage.indexes > limit
+            }
+            None => false,
+        }
+    }
+
+    /// Return `true` if `MemTable` does not fit in the room left by indexes, and its active
+    /// contents should be flushed before they are full.
+    /// Small active contents are not flushed while contents being flushed take most of the
+    /// room, because those are freed soon.
+    pub(crate) fn should_flush(&self) -> bool {
+        let limit = match self.limit() {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let usage = self.usage();
+        // Flushing does not help if indexes alone exceed the limit.
+        let room = limit.saturating_sub(usage.indexes);
+        room > 0 && usage.memtable + usage.immutables > room && usage.memtable * 2 >= room
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memtable(active: usize, immutables: usize) -> MemoryUsage {
+        MemoryUsage { active, immutables }
+    }
+
+    #[test]
+    fn unlimited() {
+        let budget = MemoryBudget::default();
+        budget.set_memtable(memtable(1000, 1000));
+        budget.set_block_cache(1000);
+        assert_eq!(None, budget.limit());
+        assert_eq!(3000, budget.usage().total());
+        assert_eq!(None, budget.block_cache_target());
+        assert!(!budget.should_flush());
+        assert!(!budget.exceeds_limit());
+    }
+
+    #[test]
+    fn evict_block_cache() {
+        let budget = MemoryBudget::new(1000);
+        budget.set_memtable(memtable(300, 0));
+        budget.set_indexes(100);
+        budget.set_block_cache(500);
+        assert_eq!(None, budget.block_cache_target());
+
+        budget.set_block_cache(800);
+        assert_eq!(Some(600), budget.block_cache_target());
+        assert!(!budget.should_flush());
+        assert!(!budget.exceeds_limit());
+    }
+
+    #[test]
+    fn flush_early() {
+        let budget = MemoryBudget::new(1000);
+        budget.set_indexes(200);
+        budget.set_block_cache(500);
+        budget.set_memtable(memtable(700, 0));
+        assert!(!budget.should_flush());
+
+        budget.set_memtable(memtable(900, 0));
+        assert_eq!(Some(0), budget.block_cache_target());
+        assert!(budget.should_flush());
+        assert!(budget.exceeds_limit());
+
+        // The contents being flushed will be freed soon.
+        budget.set_memtable(memtable(300, 600));
+        assert!(!budget.should_flush());
+
+        // Only indexes are left.
+        budget.set_indexes(1000);
+        budget.set_memtable(memtable(900, 0));
+        assert!(!budget.should_flush());
+    }
+}
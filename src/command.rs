@@ -1,13 +1,20 @@
+use crate::cursor::Cursor;
 use crate::error::Error;
-use crate::format::InternalPair;
+use crate::etag::Precondition;
+use crate::format::{InternalPair, FORMAT_VERSION};
 use hyper::Method;
 use qstring::QString;
+use serde::Deserialize;
+use std::time::Duration;
 
 /// Represents actions to key-value store and holds necessary data.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Command {
+    /// Read the value of `key`, or the value as of a snapshot if `snapshot` is given.
+    /// `MemTable` passes the sequence number of its view as `snapshot` to `SSTableManager`.
     Get {
         key: Vec<u8>,
+        snapshot: Option<u64>,
     },
     Put {
         key: Vec<u8>,
@@ -16,23 +23,107 @@ pub enum Command {
     Delete {
         key: Vec<u8>,
     },
+    /// Put `new` only if the current value of `key` is `expected`.
+    /// `expected` of `None` means the key must be absent, which is put-if-absent.
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    },
+    /// Read at most `limit` pairs whose keys are in `[start, end)` in order of keys.
+    /// If `reverse` is `true`, pairs are read from the end of the range in descending order.
+    /// If `end` is `None`, the range is not bounded above.
+    /// Stores send back the pairs serialized by `InternalPair::serialize_flatten()`.
+    /// `MemTable` merges its pairs with ones in SSTables and sends back pairs which are not
+    /// deleted. `SSTableManager` sends back pairs only in SSTables.
+    /// If `snapshot` is given, pairs as of the snapshot, or the sequence number given by
+    /// `MemTable`, are read.
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+        reverse: bool,
+        snapshot: Option<u64>,
+    },
+    /// Put `value`, or delete if it is `None`, only if the current value of `key` satisfies
+    /// `precondition` given by HTTP headers.
+    Conditional {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        precondition: Precondition,
+    },
+    /// Write all pairs atomically. A pair whose value is `None` deletes the key.
+    /// Pairs are applied in order, so a later pair for the same key wins.
+    Batch {
+        pairs: Vec<InternalPair>,
+    },
+    /// Take a snapshot of the current data which is released after `ttl` at the latest.
+    /// `MemTable` sends back the id of the snapshot encoded in little endian.
+    CreateSnapshot {
+        ttl: Duration,
+    },
+    /// Release a snapshot so that versions only it sees can be discarded.
+    ReleaseSnapshot {
+        id: u64,
+    },
+    /// Read counters of SSTable reads. `SSTableManager` sends back `TableStats` serialized by
+    /// bincode.
+    Stats,
     // `Command` includes `Flush` though this is not created from request.
     // Detailed description is available at `sstable::SSTableManager::listen()`.
+    // `snapshots` are sequence numbers of live snapshots, whose versions compaction keeps.
     Flush {
         pairs: Vec<InternalPair>,
         size: usize,
+        snapshots: Vec<u64>,
     },
 }
 
+/// An operation in a JSON body of a batch like
+/// `[{"op":"put","key":"abc","value":"def"},{"op":"delete","key":"xyz"}]`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOperation {
+    Put { key: String, value: String },
+    Delete { key: String },
+}
+
 impl Command {
+    /// Create a command from a method and a query like `key=abc&value=def`.
+    /// A GET with `snapshot=42` reads the value as of the snapshot.
+    /// A PUT with `expected=xyz` is applied only if the current value is `xyz`, and one with
+    /// `if_absent=true` is applied only if there is no value for the key.
     pub fn new(method: &Method, query: Option<&str>) -> Result<Command, Error> {
         match *method {
             Method::GET => Ok(Command::Get {
                 key: get_key(query)?,
+                snapshot: get_snapshot(&QString::from(query.unwrap_or("")))?,
             }),
             Method::PUT => {
                 let (key, value) = get_key_value(query)?;
-                Ok(Command::Put { key, value })
+                let query = QString::from(query.unwrap_or(""));
+                let if_absent = match query.get("if_absent") {
+                    Some(if_absent) => if_absent.parse().map_err(|_| {
+                        Error::InvalidQuery(format!("Invalid if_absent: {}", if_absent))
+                    })?,
+                    None => false,
+                };
+                match (query.get("expected"), if_absent) {
+                    (Some(_), true) => Err(Error::InvalidQuery(
+                        "expected and if_absent are exclusive".to_string(),
+                    )),
+                    (Some(expected), false) => Ok(Command::CompareAndSwap {
+                        key,
+                        expected: Some(expected.as_bytes().to_vec()),
+                        new: value,
+                    }),
+                    (None, true) => Ok(Command::CompareAndSwap {
+                        key,
+                        expected: None,
+                        new: value,
+                    }),
+                    (None, false) => Ok(Command::Put { key, value }),
+                }
             }
             Method::DELETE => Ok(Command::Delete {
                 key: get_key(query)?,
@@ -40,6 +131,198 @@ impl Command {
             _ => Err(Error::InvalidMethod),
         }
     }
+
+    /// Create `Command::Scan` from a query like `start=a&end=m&limit=100&reverse=true`.
+    /// `prefix=user:` limits the range to keys starting with `user:`.
+    /// `snapshot=42` reads pairs as of the snapshot.
+    /// `cursor` is a token returned with the previous page to resume the scan after it, and
+    /// it carries the snapshot of the previous page.
+    /// All parameters are optional and `limit` is `DEFAULT_SCAN_LIMIT` if omitted.
+    /// `limit` greater than `MAX_SCAN_LIMIT` is reduced to it.
+    pub fn scan(method: &Method, query: Option<&str>) -> Result<Command, Error> {
+        if *method != Method::GET {
+            return Err(Error::InvalidMethod);
+        }
+        let query = QString::from(query.unwrap_or(""));
+        let mut start = query
+            .get("start")
+            .map_or_else(Vec::new, |start| start.as_bytes().to_vec());
+        let mut end = query.get("end").map(|end| end.as_bytes().to_vec());
+        if let Some(prefix) = query.get("prefix") {
+            let prefix = prefix.as_bytes();
+            if start.as_slice() < prefix {
+                start = prefix.to_vec();
+            }
+            if let Some(prefix_end) = prefix_end(prefix) {
+                if end.as_ref().is_none_or(|end| *end > prefix_end) {
+                    end = Some(prefix_end);
+                }
+            }
+        }
+        let limit = match query.get("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidQuery(format!("Invalid limit: {}", limit)))?
+                .min(MAX_SCAN_LIMIT),
+            None => DEFAULT_SCAN_LIMIT,
+        };
+        let reverse = match query.get("reverse") {
+            Some(reverse) => reverse
+                .parse()
+                .map_err(|_| Error::InvalidQuery(format!("Invalid reverse: {}", reverse)))?,
+            None => false,
+        };
+        let mut snapshot = get_snapshot(&query)?;
+        if let Some(token) = query.get("cursor") {
+            let cursor = Cursor::decode(token)?;
+            if snapshot.is_some() && snapshot != cursor.snapshot {
+                return Err(Error::InvalidQuery(
+                    "snapshot differs from the one of the cursor".to_string(),
+                ));
+            }
+            snapshot = cursor.snapshot;
+            resume_range(&mut start, &mut end, cursor.last_key, reverse);
+        }
+        Ok(Command::Scan {
+            start,
+            end,
+            limit,
+            reverse,
+            snapshot,
+        })
+    }
+
+    /// Create a command from a request to `/snapshot`.
+    /// POST with an optional `ttl=<seconds>` takes a snapshot, which expires after
+    /// `DEFAULT_SNAPSHOT_TTL` if `ttl` is omitted. `ttl` greater than `MAX_SNAPSHOT_TTL` is
+    /// reduced to it.
+    /// DELETE with `id=42` releases the snapshot.
+    pub fn snapshot(method: &Method, query: Option<&str>) -> Result<Command, Error> {
+        let query = QString::from(query.unwrap_or(""));
+        match *method {
+            Method::POST => {
+                let ttl = match query.get("ttl") {
+                    Some(ttl) => Duration::from_secs(
+                        ttl.parse::<u64>()
+                            .map_err(|_| Error::InvalidQuery(format!("Invalid ttl: {}", ttl)))?,
+                    )
+                    .min(MAX_SNAPSHOT_TTL),
+                    None => DEFAULT_SNAPSHOT_TTL,
+                };
+                Ok(Command::CreateSnapshot { ttl })
+            }
+            Method::DELETE => match query.get("id") {
+                Some(id) => Ok(Command::ReleaseSnapshot {
+                    id: id
+                        .parse()
+                        .map_err(|_| Error::InvalidQuery(format!("Invalid id: {}", id)))?,
+                }),
+                None => Err(Error::InvalidQuery("Snapshot id not specified".to_string())),
+            },
+            _ => Err(Error::InvalidMethod),
+        }
+    }
+
+    /// Create `Command::Batch` from a body of a request.
+    /// If `content_type` is `application/octet-stream`, the body is pairs serialized by
+    /// `InternalPair::serialize_flatten()`. Otherwise, the body is a JSON array of operations.
+    pub async fn batch(
+        method: &Method,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<Command, Error> {
+        if *method != Method::POST {
+            return Err(Error::InvalidMethod);
+        }
+        let pairs = if content_type == Some("application/octet-stream") {
+            InternalPair::deserialize_from_bytes(body, FORMAT_VERSION)
+                .await
+                .map_err(|err| Error::InvalidQuery(format!("Invalid batch: {}", err)))?
+        } else {
+            serde_json::from_slice::<Vec<BatchOperation>>(body)
+                .map_err(|err| Error::InvalidQuery(format!("Invalid batch: {}", err)))?
+                .into_iter()
+                .map(|operation| match operation {
+                    BatchOperation::Put { key, value } => {
+                        InternalPair::new(key.as_bytes(), Some(value.as_bytes()))
+                    }
+                    BatchOperation::Delete { key } => InternalPair::new(key.as_bytes(), None),
+                })
+                .collect()
+        };
+        Ok(Command::Batch { pairs })
+    }
+
+    /// Return `true` if the command adds data to `MemTable`, which may have to wait for
+    /// flushes and compaction.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Put { .. }
+                | Command::Delete { .. }
+                | Command::CompareAndSwap { .. }
+                | Command::Conditional { .. }
+                | Command::Batch { .. }
+        )
+    }
+}
+
+/// Narrow a range of a scan to keys after `last_key`, or keys before it if `reverse` is `true`,
+/// to resume the scan which has read until `last_key`.
+pub fn resume_range(
+    start: &mut Vec<u8>,
+    end: &mut Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    reverse: bool,
+) {
+    if reverse {
+        // Keys less than the last one are left.
+        if end.as_ref().is_none_or(|end| *end > last_key) {
+            *end = Some(last_key);
+        }
+    } else {
+        // The smallest key greater than the last one.
+        let mut next_key = last_key;
+        next_key.push(0);
+        if *start < next_key {
+            *start = next_key;
+        }
+    }
+}
+
+/// The smallest key greater than all keys starting with `prefix`.
+/// Return `None` if there is no such key, that is, `prefix` consists only of `0xff`.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let position = prefix.iter().rposition(|byte| *byte != 0xff)?;
+    let mut end = prefix[..=position].to_vec();
+    end[position] += 1;
+    Some(end)
+}
+
+/// Number of pairs returned by a scan without `limit`.
+pub const DEFAULT_SCAN_LIMIT: usize = 100;
+
+/// Maximum number of pairs returned by a scan at once.
+/// More pairs are read by following cursors.
+pub const MAX_SCAN_LIMIT: usize = 1000;
+
+/// Lifetime of a snapshot taken without `ttl`.
+pub const DEFAULT_SNAPSHOT_TTL: Duration = Duration::from_secs(60);
+
+/// Maximum lifetime of a snapshot.
+/// Versions seen by a snapshot cannot be discarded, so a forgotten snapshot must not live long.
+pub const MAX_SNAPSHOT_TTL: Duration = Duration::from_secs(3600);
+
+/// Get a snapshot id from a query like `snapshot=42`.
+fn get_snapshot(query: &QString) -> Result<Option<u64>, Error> {
+    query
+        .get("snapshot")
+        .map(|snapshot| {
+            snapshot
+                .parse()
+                .map_err(|_| Error::InvalidQuery(format!("Invalid snapshot: {}", snapshot)))
+        })
+        .transpose()
 }
 
 /// Get key from a request URI.
@@ -75,9 +358,18 @@ mod tests {
         assert_eq!(
             Command::Get {
                 key: b"abc".to_vec(),
+                snapshot: None,
             },
             Command::new(&Method::GET, Some("key=abc")).unwrap()
         );
+        assert_eq!(
+            Command::Get {
+                key: b"abc".to_vec(),
+                snapshot: Some(42),
+            },
+            Command::new(&Method::GET, Some("key=abc&snapshot=42")).unwrap()
+        );
+        assert!(Command::new(&Method::GET, Some("key=abc&snapshot=-1")).is_err());
     }
 
     #[test]
@@ -91,6 +383,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn command_compare_and_swap() {
+        assert_eq!(
+            Command::CompareAndSwap {
+                key: b"abc".to_vec(),
+                expected: Some(b"old".to_vec()),
+                new: b"def".to_vec(),
+            },
+            Command::new(&Method::PUT, Some("key=abc&value=def&expected=old")).unwrap()
+        );
+        assert_eq!(
+            Command::CompareAndSwap {
+                key: b"abc".to_vec(),
+                expected: None,
+                new: b"def".to_vec(),
+            },
+            Command::new(&Method::PUT, Some("key=abc&value=def&if_absent=true")).unwrap()
+        );
+        assert_eq!(
+            Command::Put {
+                key: b"abc".to_vec(),
+                value: b"def".to_vec(),
+            },
+            Command::new(&Method::PUT, Some("key=abc&value=def&if_absent=false")).unwrap()
+        );
+        assert!(Command::new(&Method::PUT, Some("key=abc&value=def&if_absent=yes")).is_err());
+        assert!(Command::new(
+            &Method::PUT,
+            Some("key=abc&value=def&expected=x&if_absent=true")
+        )
+        .is_err());
+    }
+
     #[test]
     fn command_delete() {
         assert_eq!(
@@ -101,6 +426,226 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn command_batch() {
+        let body = br#"[{"op":"put","key":"abc","value":"def"},{"op":"delete","key":"xyz"}]"#;
+        let pairs = vec![
+            InternalPair::new(b"abc", Some(b"def")),
+            InternalPair::new(b"xyz", None),
+        ];
+        assert_eq!(
+            Ok(Command::Batch {
+                pairs: pairs.clone()
+            }),
+            Command::batch(&Method::POST, Some("application/json"), body).await
+        );
+        assert_eq!(
+            Ok(Command::Batch {
+                pairs: pairs.clone()
+            }),
+            Command::batch(
+                &Method::POST,
+                Some("application/octet-stream"),
+                &InternalPair::serialize_flatten(&pairs)
+            )
+            .await
+        );
+        assert_eq!(
+            Err(Error::InvalidMethod),
+            Command::batch(&Method::GET, None, body).await
+        );
+        assert!(
+            Command::batch(&Method::POST, None, br#"[{"op":"get","key":"abc"}]"#)
+                .await
+                .is_err()
+        );
+        assert!(
+            Command::batch(&Method::POST, Some("application/octet-stream"), b"\x00abc")
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn command_scan() {
+        assert_eq!(
+            Command::Scan {
+                start: b"a".to_vec(),
+                end: Some(b"m".to_vec()),
+                limit: 10,
+                reverse: true,
+                snapshot: None,
+            },
+            Command::scan(&Method::GET, Some("start=a&end=m&limit=10&reverse=true")).unwrap()
+        );
+        assert_eq!(
+            Command::Scan {
+                start: vec![],
+                end: None,
+                limit: DEFAULT_SCAN_LIMIT,
+                reverse: false,
+                snapshot: None,
+            },
+            Command::scan(&Method::GET, None).unwrap()
+        );
+        assert!(matches!(
+            Command::scan(&Method::GET, Some("reverse=yes")),
+            Err(Error::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            Command::scan(&Method::GET, Some("limit=x")),
+            Err(Error::InvalidQuery(_))
+        ));
+        assert_eq!(
+            Err(Error::InvalidMethod),
+            Command::scan(&Method::PUT, Some("start=a"))
+        );
+    }
+
+    #[test]
+    fn command_prefix_scan() {
+        assert_eq!(
+            Command::Scan {
+                start: b"user:123:".to_vec(),
+                end: Some(b"user:123;".to_vec()),
+                limit: DEFAULT_SCAN_LIMIT,
+                reverse: false,
+                snapshot: None,
+            },
+            Command::scan(&Method::GET, Some("prefix=user:123:")).unwrap()
+        );
+        // The range is narrowed by both the prefix and the bounds.
+        assert_eq!(
+            Command::Scan {
+                start: b"user:5".to_vec(),
+                end: Some(b"user;".to_vec()),
+                limit: DEFAULT_SCAN_LIMIT,
+                reverse: false,
+                snapshot: None,
+            },
+            Command::scan(&Method::GET, Some("prefix=user:&start=user:5&end=z")).unwrap()
+        );
+    }
+
+    #[test]
+    fn command_scan_with_cursor() {
+        let cursor = Cursor {
+            last_key: b"abc".to_vec(),
+            snapshot: None,
+            owns_snapshot: false,
+        }
+        .encode();
+        assert_eq!(
+            Command::Scan {
+                start: b"abc\0".to_vec(),
+                end: Some(b"m".to_vec()),
+                limit: 10,
+                reverse: false,
+                snapshot: None,
+            },
+            Command::scan(
+                &Method::GET,
+                Some(&format!("start=a&end=m&limit=10&cursor={}", cursor))
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Command::Scan {
+                start: b"a".to_vec(),
+                end: Some(b"abc".to_vec()),
+                limit: MAX_SCAN_LIMIT,
+                reverse: true,
+                snapshot: None,
+            },
+            Command::scan(
+                &Method::GET,
+                Some(&format!(
+                    "start=a&end=m&limit=100000&reverse=true&cursor={}",
+                    cursor
+                ))
+            )
+            .unwrap()
+        );
+        assert!(matches!(
+            Command::scan(&Method::GET, Some("cursor=xyz")),
+            Err(Error::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn command_scan_with_snapshot() {
+        let cursor = Cursor {
+            last_key: b"abc".to_vec(),
+            snapshot: Some(42),
+            owns_snapshot: false,
+        }
+        .encode();
+        let expected = Command::Scan {
+            start: b"abc\0".to_vec(),
+            end: None,
+            limit: DEFAULT_SCAN_LIMIT,
+            reverse: false,
+            snapshot: Some(42),
+        };
+        // The snapshot is carried by the cursor.
+        assert_eq!(
+            expected,
+            Command::scan(&Method::GET, Some(&format!("cursor={}", cursor))).unwrap()
+        );
+        assert_eq!(
+            expected,
+            Command::scan(
+                &Method::GET,
+                Some(&format!("snapshot=42&cursor={}", cursor))
+            )
+            .unwrap()
+        );
+        assert!(matches!(
+            Command::scan(&Method::GET, Some(&format!("snapshot=7&cursor={}", cursor))),
+            Err(Error::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn command_snapshot() {
+        assert_eq!(
+            Ok(Command::CreateSnapshot {
+                ttl: DEFAULT_SNAPSHOT_TTL
+            }),
+            Command::snapshot(&Method::POST, None)
+        );
+        assert_eq!(
+            Ok(Command::CreateSnapshot {
+                ttl: Duration::from_secs(10)
+            }),
+            Command::snapshot(&Method::POST, Some("ttl=10"))
+        );
+        assert_eq!(
+            Ok(Command::CreateSnapshot {
+                ttl: MAX_SNAPSHOT_TTL
+            }),
+            Command::snapshot(&Method::POST, Some("ttl=100000"))
+        );
+        assert_eq!(
+            Ok(Command::ReleaseSnapshot { id: 42 }),
+            Command::snapshot(&Method::DELETE, Some("id=42"))
+        );
+        assert!(Command::snapshot(&Method::POST, Some("ttl=x")).is_err());
+        assert!(Command::snapshot(&Method::DELETE, None).is_err());
+        assert_eq!(
+            Err(Error::InvalidMethod),
+            Command::snapshot(&Method::GET, None)
+        );
+    }
+
+    #[test]
+    fn compute_prefix_end() {
+        assert_eq!(Some(b"abd".to_vec()), prefix_end(b"abc"));
+        assert_eq!(Some(vec![b'a', 0xff]), prefix_end(&[b'a', 0xfe, 0xff]));
+        assert_eq!(None, prefix_end(&[0xff, 0xff]));
+        assert_eq!(None, prefix_end(b""));
+    }
+
     #[test]
     fn invalid_method() {
         assert_eq!(
@@ -1,4 +1,8 @@
+use crate::budget::MemoryBudget;
+use crate::stall::{Backlog, WriteController};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
 /// Structure for app configuration.
@@ -18,14 +22,39 @@ pub struct Config {
     )]
     pub port: u16,
 
-    /// Limit of MemTable size to flush its contents.
+    /// Limit of memory in bytes which MemTable contents use before they are flushed.
     #[structopt(
         long,
         default_value = "4096",
-        help = "Limit of MemTable size to flush its contents"
+        help = "Limit of memory in bytes which MemTable contents use before they are flushed"
     )]
     pub memtable_limit: usize,
 
+    /// Maximum number of full MemTables being flushed before writes wait for flushes.
+    /// This is the hard limit of immutable MemTables.
+    #[structopt(
+        long,
+        default_value = "2",
+        help = "Maximum number of full MemTables being flushed before writes wait"
+    )]
+    pub max_immutable_memtables: usize,
+
+    /// Number of full MemTables being flushed at which writes are slowed down.
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Number of full MemTables being flushed at which writes are slowed down"
+    )]
+    pub soft_immutable_limit: usize,
+
+    /// In-memory structure which holds contents of MemTable.
+    #[structopt(
+        long,
+        default_value = "btreemap",
+        help = "In-memory structure of MemTable: btreemap or skiplist"
+    )]
+    pub memtable_backend: MemTableBackend,
+
     /// Directory to store SSTable's files.
     #[structopt(
         short,
@@ -43,6 +72,54 @@ pub struct Config {
     )]
     pub compaction_trigger_ratio: u64,
 
+    /// Number of SSTables at which writes are slowed down.
+    #[structopt(
+        long,
+        default_value = "8",
+        help = "Number of SSTables at which writes are slowed down"
+    )]
+    pub soft_table_limit: usize,
+
+    /// Number of SSTables at which writes wait for compaction.
+    #[structopt(
+        long,
+        default_value = "16",
+        help = "Number of SSTables at which writes wait for compaction"
+    )]
+    pub hard_table_limit: usize,
+
+    /// Bytes of SSTables not yet compacted at which writes are slowed down.
+    #[structopt(
+        long,
+        default_value = "16777216",
+        help = "Bytes of SSTables newer than the oldest one at which writes are slowed down"
+    )]
+    pub soft_pending_compaction_bytes: usize,
+
+    /// Bytes of SSTables not yet compacted at which writes wait for compaction.
+    #[structopt(
+        long,
+        default_value = "67108864",
+        help = "Bytes of SSTables newer than the oldest one at which writes wait for compaction"
+    )]
+    pub hard_pending_compaction_bytes: usize,
+
+    /// Delay of each write in milliseconds while a soft limit is reached.
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Delay of each write in milliseconds while a soft limit is reached"
+    )]
+    pub write_slowdown_ms: u64,
+
+    /// How long a write waits in milliseconds while a hard limit is reached before it fails.
+    #[structopt(
+        long,
+        default_value = "1000",
+        help = "Milliseconds a write waits at a hard limit before failing with 503"
+    )]
+    pub write_stall_timeout_ms: u64,
+
     /// Every `block_stride` pair, `SSTable` creates an index entry.
     #[structopt(
         short = "s",
@@ -51,4 +128,229 @@ pub struct Config {
         help = "Size of block of SSTable index"
     )]
     pub block_stride: usize,
+
+    /// Number of bits for each key in Bloom filters of SSTables.
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Bits per key of Bloom filters of SSTables. 0 disables filters"
+    )]
+    pub bloom_bits_per_key: usize,
+
+    /// Capacity of the cache of SSTable blocks in bytes.
+    #[structopt(
+        long,
+        default_value = "8388608",
+        help = "Capacity of the cache of SSTable blocks in bytes. 0 disables the cache"
+    )]
+    pub block_cache_size: usize,
+
+    /// Limit of memory in bytes which MemTables, indexes of SSTables and the block cache use.
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Limit of memory in bytes shared by MemTables, SSTable indexes and the block cache. 0 disables the limit"
+    )]
+    pub memory_budget: usize,
+
+    /// When written data is synchronized to a disk.
+    #[structopt(
+        long,
+        default_value = "batch",
+        help = "Durability mode: always, batch, interval:<ms> or none"
+    )]
+    pub sync: SyncMode,
+}
+
+impl Config {
+    /// Create a gate of writes with the configured limits and memory budget.
+    /// Return an error if a soft limit is above its hard limit, because writes would stop
+    /// without being slowed down first, or if the memory budget cannot hold a full MemTable.
+    pub fn write_controller(&self) -> Result<WriteController, String> {
+        let soft_limit = Backlog {
+            tables: self.soft_table_limit,
+            pending_compaction_bytes: self.soft_pending_compaction_bytes,
+            immutables: self.soft_immutable_limit,
+        };
+        let hard_limit = Backlog {
+            tables: self.hard_table_limit,
+            pending_compaction_bytes: self.hard_pending_compaction_bytes,
+            immutables: self.max_immutable_memtables,
+        };
+        let limits = [
+            ("table", soft_limit.tables, hard_limit.tables),
+            (
+                "pending compaction bytes",
+                soft_limit.pending_compaction_bytes,
+                hard_limit.pending_compaction_bytes,
+            ),
+            ("immutable", soft_limit.immutables, hard_limit.immutables),
+        ];
+        if let Some((name, soft, hard)) = limits.iter().find(|(_, soft, hard)| soft > hard) {
+            return Err(format!(
+                "Soft {} limit {} exceeds the hard limit {}",
+                name, soft, hard
+            ));
+        }
+        if self.memory_budget > 0 && self.memory_budget < self.memtable_limit {
+            return Err(format!(
+                "Memory budget {} is below the MemTable limit {}",
+                self.memory_budget, self.memtable_limit
+            ));
+        }
+        Ok(WriteController::new(
+            soft_limit,
+            hard_limit,
+            Duration::from_millis(self.write_slowdown_ms),
+            Duration::from_millis(self.write_stall_timeout_ms),
+        )
+        .with_memory_budget(MemoryBudget::new(self.memory_budget)))
+    }
+}
+
+/// In-memory structure which holds contents of `MemTable`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MemTableBackend {
+    /// `BTreeMap` behind a lock.
+    #[default]
+    BTreeMap,
+
+    /// Lock-free skip list allocated in an arena, which serves reads and writes concurrently.
+    SkipList,
+}
+
+impl FromStr for MemTableBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "btreemap" => Ok(MemTableBackend::BTreeMap),
+            "skiplist" => Ok(MemTableBackend::SkipList),
+            _ => Err(format!("Invalid MemTable backend: {}", s)),
+        }
+    }
+}
+
+/// Policy to call `fsync` for SSTable files, the data directory and the write-ahead log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+    /// Synchronize the write-ahead log every write.
+    Always,
+
+    /// Synchronize the write-ahead log once for writes which arrive at the same time
+    /// (group commit).
+    Batch,
+
+    /// Synchronize the write-ahead log periodically.
+    /// Writes in the last period may be lost on a crash.
+    Interval(Duration),
+
+    /// Never synchronize and leave it to OS.
+    None,
+}
+
+impl SyncMode {
+    /// Whether SSTable files and the data directory are synchronized.
+    /// They are written only when `MemTable` is flushed, so they are synchronized
+    /// unless synchronization is disabled completely.
+    pub fn syncs_files(&self) -> bool {
+        *self != SyncMode::None
+    }
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncMode::Always),
+            "batch" => Ok(SyncMode::Batch),
+            "none" => Ok(SyncMode::None),
+            _ => {
+                let millis = s
+                    .strip_prefix("interval:")
+                    .and_then(|millis| millis.parse::<u64>().ok())
+                    .filter(|millis| *millis > 0)
+                    .ok_or_else(|| format!("Invalid sync mode: {}", s))?;
+                Ok(SyncMode::Interval(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sync_mode() {
+        assert_eq!(Ok(SyncMode::Always), "always".parse());
+        assert_eq!(Ok(SyncMode::Batch), "batch".parse());
+        assert_eq!(Ok(SyncMode::None), "none".parse());
+        assert_eq!(
+            Ok(SyncMode::Interval(Duration::from_millis(100))),
+            "interval:100".parse()
+        );
+    }
+
+    #[test]
+    fn parse_memtable_backend() {
+        assert_eq!(Ok(MemTableBackend::BTreeMap), "btreemap".parse());
+        assert_eq!(Ok(MemTableBackend::SkipList), "skiplist".parse());
+        assert!("hashmap".parse::<MemTableBackend>().is_err());
+    }
+
+    #[test]
+    fn default_limits() {
+        let config = Config::from_iter(&["horreum", "-r", "100"]);
+        let write_controller = config.write_controller().unwrap();
+        assert_eq!(2, write_controller.hard_limit().immutables);
+        assert_eq!(1, config.soft_immutable_limit);
+    }
+
+    #[test]
+    fn reject_soft_limit_above_hard_limit() {
+        let config = Config::from_iter(&[
+            "horreum",
+            "-r",
+            "100",
+            "--soft-table-limit",
+            "20",
+            "--hard-table-limit",
+            "10",
+        ]);
+        assert!(config.write_controller().is_err());
+    }
+
+    #[test]
+    fn reject_memory_budget_below_memtable_limit() {
+        let config = Config::from_iter(&[
+            "horreum",
+            "-r",
+            "100",
+            "--memtable-limit",
+            "4096",
+            "--memory-budget",
+            "4095",
+        ]);
+        assert!(config.write_controller().is_err());
+        let config = Config::from_iter(&[
+            "horreum",
+            "-r",
+            "100",
+            "--memtable-limit",
+            "4096",
+            "--memory-budget",
+            "4096",
+        ]);
+        assert!(config.write_controller().is_ok());
+    }
+
+    #[test]
+    fn parse_invalid_sync_mode() {
+        assert!("sometimes".parse::<SyncMode>().is_err());
+        assert!("interval:".parse::<SyncMode>().is_err());
+        assert!("interval:0".parse::<SyncMode>().is_err());
+        assert!("interval:-1".parse::<SyncMode>().is_err());
+    }
 }
@@ -0,0 +1,71 @@
+use crate::error::Error;
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+
+/// Position to resume a paginated scan, which is passed to clients as an opaque token.
+/// A scan is resumed from the key next to `last_key`, so pages are continuous even if contents
+/// of stores are moved by flushes and compactions between them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The last key in the previous page.
+    pub last_key: Vec<u8>,
+
+    /// Identifier of the snapshot the scan reads.
+    /// `None` means the scan reads the latest data.
+    pub snapshot: Option<u64>,
+
+    /// Whether the server took `snapshot` for the scan, so that it releases the snapshot at the
+    /// last page.
+    pub owns_snapshot: bool,
+}
+
+impl Cursor {
+    /// Encode the cursor as a token which can be embedded in a URL.
+    pub fn encode(&self) -> String {
+        serialize(self)
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Decode a token created by `encode()`.
+    pub fn decode(token: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidQuery(format!("Invalid cursor: {}", token));
+        if !token.len().is_multiple_of(2) || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        deserialize(&bytes).map_err(|_| invalid())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let cursor = Cursor {
+            last_key: "user:123:日本語".as_bytes().to_vec(),
+            snapshot: Some(42),
+            owns_snapshot: true,
+        };
+        let token = cursor.encode();
+        assert!(token.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(cursor, Cursor::decode(&token).unwrap());
+    }
+
+    #[test]
+    fn decode_invalid_token() {
+        assert!(Cursor::decode("").is_err());
+        assert!(Cursor::decode("abc").is_err());
+        assert!(Cursor::decode("zz").is_err());
+        assert!(Cursor::decode("ffffffffffffffff").is_err());
+        assert!(Cursor::decode("日本").is_err());
+    }
+}
@@ -1,6 +1,7 @@
+use std::io;
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq, Eq)]
+#[derive(Clone, Error, Debug, PartialEq, Eq)]
 pub enum Error {
     #[error("Key and value not specified")]
     EmptyQuery,
@@ -13,4 +14,82 @@ pub enum Error {
 
     #[error("Invalid HTTP method")]
     InvalidMethod,
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    /// A put-if-absent found an existing value.
+    #[error("Key already exists")]
+    AlreadyExists,
+
+    /// A compare-and-swap found a value different from the expected one.
+    #[error("Current value does not match the expected one")]
+    ValueMismatch,
+
+    /// The current value does not satisfy `If-Match` or `If-None-Match`.
+    #[error("Precondition failed")]
+    PreconditionFailed,
+
+    /// A read refers to a snapshot which is released, expired or never taken.
+    #[error("Unknown snapshot: {0}")]
+    UnknownSnapshot(u64),
+
+    /// Writes waited too long for flushes and compaction to catch up.
+    /// This holds seconds after which the write may be retried.
+    #[error("Writes are stalled until flushes and compaction catch up")]
+    WriteStalled(u64),
+
+    /// Failure of the underlying storage.
+    /// `io::Error` is not comparable, so only its message is kept.
+    #[error("I/O error: {0}")]
+    Io(String),
+
+    /// Stored data is broken.
+    #[error("Data corruption: {0}")]
+    Corruption(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        match err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Corruption>())
+        {
+            Some(Corruption(message)) => Error::Corruption(message.clone()),
+            None => Error::Io(err.to_string()),
+        }
+    }
+}
+
+/// Error raised when stored data fails verification.
+/// This is carried in `io::Error` while reading files and converted into
+/// `Error::Corruption` when it reaches a caller.
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct Corruption(pub String);
+
+impl From<Corruption> for io::Error {
+    fn from(err: Corruption) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_corruption() {
+        let err = io::Error::from(Corruption("checksum mismatch".to_string()));
+        assert_eq!(
+            Error::Corruption("checksum mismatch".to_string()),
+            Error::from(err)
+        );
+    }
+
+    #[test]
+    fn convert_io_error() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(Error::Io("no such file".to_string()), Error::from(err));
+    }
 }
@@ -0,0 +1,187 @@
+use crate::error::Error;
+use hyper::header::{self, HeaderMap};
+
+/// Offset basis of 64-bit FNV-1a.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Prime of 64-bit FNV-1a.
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Create a strong entity tag identifying `value`, like `"0123456789abcdef"`.
+/// The tag is derived from the content, so writing the same value again keeps the tag.
+pub fn etag(value: &[u8]) -> String {
+    let hash = value.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+/// List of entity tags in `If-Match` or `If-None-Match`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityTags {
+    /// `*`, which matches any existing value.
+    Any,
+
+    /// Tags including their quotes.
+    Tags(Vec<String>),
+}
+
+impl EntityTags {
+    /// Parse a header value like `"abc", W/"def"`.
+    /// Weak tags are kept only if `weak` is `true`, with their `W/` prefix removed.
+    fn parse(header: &str, weak: bool) -> Result<Self, Error> {
+        let header = header.trim();
+        if header == "*" {
+            return Ok(EntityTags::Any);
+        }
+        let mut tags = Vec::new();
+        for tag in header
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+        {
+            let (is_weak, tag) = match tag.strip_prefix("W/") {
+                Some(tag) => (true, tag),
+                None => (false, tag),
+            };
+            if tag.len() < 2 || !tag.starts_with('"') || !tag.ends_with('"') {
+                return Err(Error::InvalidQuery(format!("Invalid entity tag: {}", tag)));
+            }
+            if !is_weak || weak {
+                tags.push(tag.to_string());
+            }
+        }
+        Ok(EntityTags::Tags(tags))
+    }
+
+    /// Return `true` if the tag of `value` is in the list.
+    /// A missing value matches nothing, even `*`.
+    fn matches(&self, value: Option<&[u8]>) -> bool {
+        match (self, value) {
+            (_, None) => false,
+            (EntityTags::Any, Some(_)) => true,
+            (EntityTags::Tags(tags), Some(value)) => tags.contains(&etag(value)),
+        }
+    }
+}
+
+/// Conditions of a request given by `If-Match` and `If-None-Match` headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Precondition {
+    pub if_match: Option<EntityTags>,
+    pub if_none_match: Option<EntityTags>,
+}
+
+impl Precondition {
+    /// Read conditions from headers of a request.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, Error> {
+        let parse = |name: header::HeaderName, weak: bool| {
+            headers
+                .get(name)
+                .map(|value| {
+                    let value = value
+                        .to_str()
+                        .map_err(|_| Error::InvalidQuery("Invalid entity tag".to_string()))?;
+                    EntityTags::parse(value, weak)
+                })
+                .transpose()
+        };
+        Ok(Self {
+            // `If-Match` uses the strong comparison, so weak tags never match.
+            if_match: parse(header::IF_MATCH, false)?,
+            if_none_match: parse(header::IF_NONE_MATCH, true)?,
+        })
+    }
+
+    /// Return `true` if no condition is given.
+    pub fn is_empty(&self) -> bool {
+        self.if_match.is_none() && self.if_none_match.is_none()
+    }
+
+    /// Return `true` if `If-Match` is satisfied by the current value.
+    pub fn if_match_holds(&self, current: Option<&[u8]>) -> bool {
+        self.if_match
+            .as_ref()
+            .is_none_or(|tags| tags.matches(current))
+    }
+
+    /// Return `true` if `If-None-Match` is satisfied by the current value.
+    pub fn if_none_match_holds(&self, current: Option<&[u8]>) -> bool {
+        self.if_none_match
+            .as_ref()
+            .is_none_or(|tags| !tags.matches(current))
+    }
+
+    /// Return `true` if all conditions are satisfied by the current value.
+    pub fn holds(&self, current: Option<&[u8]>) -> bool {
+        self.if_match_holds(current) && self.if_none_match_holds(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn precondition(name: header::HeaderName, value: &'static str) -> Precondition {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        Precondition::from_headers(&headers).unwrap()
+    }
+
+    #[test]
+    fn create_etag() {
+        assert_eq!("\"cbf29ce484222325\"", etag(b""));
+        assert_eq!(etag(b"abc"), etag(b"abc"));
+        assert_ne!(etag(b"abc"), etag(b"abd"));
+    }
+
+    #[test]
+    fn parse_entity_tags() {
+        assert_eq!(Ok(EntityTags::Any), EntityTags::parse(" * ", false));
+        assert_eq!(
+            Ok(EntityTags::Tags(vec!["\"a\"".to_string()])),
+            EntityTags::parse("\"a\", W/\"b\"", false)
+        );
+        assert_eq!(
+            Ok(EntityTags::Tags(vec![
+                "\"a\"".to_string(),
+                "\"b\"".to_string()
+            ])),
+            EntityTags::parse("\"a\", W/\"b\"", true)
+        );
+        assert!(EntityTags::parse("abc", false).is_err());
+    }
+
+    #[test]
+    fn evaluate_if_match() {
+        let tag = etag(b"value");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, HeaderValue::from_str(&tag).unwrap());
+        let condition = Precondition::from_headers(&headers).unwrap();
+        assert!(condition.holds(Some(b"value")));
+        assert!(!condition.holds(Some(b"other")));
+        assert!(!condition.holds(None));
+
+        let condition = precondition(header::IF_MATCH, "*");
+        assert!(condition.holds(Some(b"other")));
+        assert!(!condition.holds(None));
+    }
+
+    #[test]
+    fn evaluate_if_none_match() {
+        let condition = precondition(header::IF_NONE_MATCH, "*");
+        assert!(condition.holds(None));
+        assert!(!condition.holds(Some(b"value")));
+
+        let tag = format!("W/{}", etag(b"value"));
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&tag).unwrap());
+        let condition = Precondition::from_headers(&headers).unwrap();
+        assert!(!condition.holds(Some(b"value")));
+        assert!(condition.holds(Some(b"other")));
+        assert!(condition.holds(None));
+        assert!(Precondition::default().is_empty());
+        assert!(!condition.is_empty());
+    }
+}
@@ -1,32 +1,133 @@
-use bincode::{deserialize, serialize, Error};
+use crate::checksum::crc32c;
+use crate::error::Corruption;
+use bincode::{deserialize, serialize, Error, ErrorKind};
+use std::convert::TryInto;
 use std::io::Cursor;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// Version of the encoding of `InternalPair` which is written now.
+pub const FORMAT_VERSION: u32 = 7;
+
+/// Version of the encoding without entry type.
+/// A value of length 0 in this version means the pair is deleted,
+/// so an empty value cannot be distinguished from a deletion.
+pub const LEGACY_FORMAT_VERSION: u32 = 1;
+
+/// Version since which each pair has its entry type.
+pub const ENTRY_TYPE_FORMAT_VERSION: u32 = 2;
+
+/// Version since which each block of an SSTable has its length and checksum.
+pub const BLOCK_CHECKSUM_FORMAT_VERSION: u32 = 3;
+
+/// Version since which an SSTable file ends with its index and a footer.
+pub const INDEX_FORMAT_VERSION: u32 = 4;
+
+/// Version since which an SSTable file has a Bloom filter for its keys.
+pub const FILTER_FORMAT_VERSION: u32 = 5;
+
+/// Version since which each pair has its sequence number.
+pub const SEQUENCE_FORMAT_VERSION: u32 = 6;
+
+/// Version since which every record of the write-ahead log has a checksum.
+/// Files of SSTables are encoded in the same way as the previous version.
+pub const RECORD_CHECKSUM_FORMAT_VERSION: u32 = 7;
+
+/// Magic number at the end of an SSTable file, which is "horreum!" in ASCII.
+const TABLE_MAGIC_NUMBER: u64 = 0x686f_7272_6575_6d21;
+
+/// Length of the header of a block, which stores the length of pairs in the block.
+const BLOCK_HEADER_LENGTH: usize = 8;
+
+/// Length of the trailer of a block, which stores CRC-32C of pairs in the block.
+const BLOCK_TRAILER_LENGTH: usize = 4;
+
+/// Kind of an entry, which is encoded at the head of a serialized pair.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum EntryType {
+    /// The pair holds a value.
+    Value = 0,
+
+    /// The pair is deleted.
+    Tombstone = 1,
+}
+
+impl EntryType {
+    fn from_u8(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(EntryType::Value),
+            1 => Some(EntryType::Tombstone),
+            _ => None,
+        }
+    }
+}
+
 /// Internal representation of a key-value pair in SSTable.
+///
+/// Serialized pair is laid out as follows:
+/// ```text
+/// +-------------------+------------------------+-------------------+---------------------+
+/// | entry type(1byte) | sequence number(8byte) | key length(8byte) | value length(8byte) |
+/// +-------------------+------------------------+-------------------+---------------------+
+/// | key | value |
+/// +-----+-------+
+/// ```
+/// In `LEGACY_FORMAT_VERSION`, there is no entry type.
+/// Before `SEQUENCE_FORMAT_VERSION`, there is no sequence number and it is read as 0.
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct InternalPair {
     pub(crate) key: Vec<u8>,
     /// If this pair is deleted, `value` is `None`.
     pub(crate) value: Option<Vec<u8>>,
+    /// Order of the write which created this pair among all writes.
+    /// A pair with a bigger sequence number is newer. 0 means the order is unknown.
+    pub(crate) sequence: u64,
 }
 
 impl InternalPair {
-    /// Initialize `InternalPair`.
+    /// Initialize `InternalPair` without a sequence number.
     pub fn new(key: &[u8], value: Option<&[u8]>) -> Self {
         Self {
             key: key.to_vec(),
             value: value.map(|v| v.to_vec()),
+            sequence: 0,
         }
     }
 
+    /// Set the sequence number of the pair.
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Number of bytes of the key and the value.
+    pub fn size(&self) -> usize {
+        self.key.len() + self.value.as_ref().map_or(0, Vec::len)
+    }
+
     /// Serialize struct's members into `Vec<u8>`.
     pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_with_version(FORMAT_VERSION)
+    }
+
+    /// Serialize struct's members in the encoding of `version`.
+    pub fn serialize_with_version(&self, version: u32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        if version >= ENTRY_TYPE_FORMAT_VERSION {
+            let entry_type = match self.value {
+                Some(_) => EntryType::Value,
+                None => EntryType::Tombstone,
+            };
+            buffer.push(entry_type as u8);
+        }
+        if version >= SEQUENCE_FORMAT_VERSION {
+            buffer.extend_from_slice(&self.sequence.to_le_bytes());
+        }
         let mut key_length = serialize(&self.key.len()).unwrap();
         let mut value_length = match &self.value {
             Some(value) => serialize(&value.len()).unwrap(),
             None => vec![0; 8],
         };
-        let mut buffer = Vec::new();
         buffer.append(&mut key_length);
         buffer.append(&mut value_length);
         buffer.append(&mut self.key.clone());
@@ -38,21 +139,37 @@ impl InternalPair {
 
     /// Serialize each elements in `pairs` and flatten vector of bytes.
     pub fn serialize_flatten(pairs: &[InternalPair]) -> Vec<u8> {
-        pairs.iter().flat_map(|pair| pair.serialize()).collect()
+        Self::serialize_flatten_with_version(pairs, FORMAT_VERSION)
+    }
+
+    /// Serialize each elements in `pairs` in the encoding of `version`.
+    pub fn serialize_flatten_with_version(pairs: &[InternalPair], version: u32) -> Vec<u8> {
+        pairs
+            .iter()
+            .flat_map(|pair| pair.serialize_with_version(version))
+            .collect()
     }
 
     /// Deserialize `Vec<u8>` into struct's members.
     pub async fn deserialize<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Error> {
-        InternalPair::deserialize_inner(reader).await
+        InternalPair::deserialize_inner(reader, FORMAT_VERSION).await
     }
 
-    /// Deserialize bytes of pairs.
-    pub async fn deserialize_from_bytes(bytes: &mut [u8]) -> Result<Vec<Self>, Error> {
+    /// Deserialize a pair encoded in `version`.
+    pub async fn deserialize_with_version<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        version: u32,
+    ) -> Result<Self, Error> {
+        InternalPair::deserialize_inner(reader, version).await
+    }
+
+    /// Deserialize bytes of pairs encoded in `version`.
+    pub async fn deserialize_from_bytes(bytes: &[u8], version: u32) -> Result<Vec<Self>, Error> {
         let mut pairs = vec![];
         let bytes_length = bytes.len() as u64;
         let mut cursor = Cursor::new(bytes);
         while cursor.position() < bytes_length {
-            let pair = Self::deserialize_inner(&mut cursor).await?;
+            let pair = Self::deserialize_inner(&mut cursor, version).await?;
             pairs.push(pair);
         }
         Ok(pairs)
@@ -60,20 +177,226 @@ impl InternalPair {
 
     // Deserialize key and value from something implemented `Read`
     // and return `Self` and the number of bytes read from.
-    async fn deserialize_inner<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, Error> {
+    async fn deserialize_inner<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        version: u32,
+    ) -> Result<Self, Error> {
+        let entry_type = if version >= ENTRY_TYPE_FORMAT_VERSION {
+            let tag = reader.read_u8().await?;
+            let entry_type = EntryType::from_u8(tag)
+                .ok_or_else(|| ErrorKind::Custom(format!("Unknown entry type: {}", tag)))?;
+            Some(entry_type)
+        } else {
+            None
+        };
+        let sequence = if version >= SEQUENCE_FORMAT_VERSION {
+            reader.read_u64_le().await?
+        } else {
+            0
+        };
         let mut length_buffer = vec![0; 16];
         reader.read_exact(&mut length_buffer).await?;
         let key_length: usize = deserialize(&length_buffer[..8])?;
         let value_length: usize = deserialize(&length_buffer[8..])?;
-        let mut content_buffer = vec![0; key_length + value_length];
-        reader.read_exact(&mut content_buffer).await?;
+        let content_length = key_length
+            .checked_add(value_length)
+            .ok_or_else(|| ErrorKind::Custom("Too long pair".to_string()))?;
+        // Lengths may be broken, so do not allocate a buffer of `content_length` in advance.
+        let mut content_buffer = Vec::new();
+        reader
+            .take(content_length as u64)
+            .read_to_end(&mut content_buffer)
+            .await?;
+        if content_buffer.len() < content_length {
+            return Err(ErrorKind::Io(std::io::ErrorKind::UnexpectedEof.into()).into());
+        }
         let key = content_buffer[..key_length].to_vec();
-        let value = if value_length > 0 {
-            Some(content_buffer[key_length..].to_vec())
+        let value = match entry_type {
+            Some(EntryType::Value) => Some(content_buffer[key_length..].to_vec()),
+            Some(EntryType::Tombstone) => None,
+            None if value_length > 0 => Some(content_buffer[key_length..].to_vec()),
+            None => None,
+        };
+        Ok(InternalPair {
+            key,
+            value,
+            sequence,
+        })
+    }
+}
+
+/// Encode `pairs` as a block of an SSTable file in the encoding of `version`.
+///
+/// Since `BLOCK_CHECKSUM_FORMAT_VERSION`, a block is laid out as follows:
+/// ```text
+/// +---------------------+-------------------------+-----------------+
+/// | pairs length(8byte) | serialized pairs        | CRC-32C(4byte)  |
+/// +---------------------+-------------------------+-----------------+
+/// ```
+/// In older versions, a block is just serialized pairs.
+pub fn encode_block(pairs: &[InternalPair], version: u32) -> Vec<u8> {
+    let data = InternalPair::serialize_flatten_with_version(pairs, version);
+    if version < BLOCK_CHECKSUM_FORMAT_VERSION {
+        return data;
+    }
+    frame_block(data)
+}
+
+/// Prepend the length of `data` and append its checksum, as a block is laid out since
+/// `BLOCK_CHECKSUM_FORMAT_VERSION`.
+pub fn frame_block(mut data: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32c(&data);
+    let mut block = serialize(&data.len()).unwrap();
+    block.append(&mut data);
+    block.extend_from_slice(&checksum.to_le_bytes());
+    block
+}
+
+/// Verify a block encoded by `encode_block()` and return serialized pairs in it.
+pub fn verify_block(block: &[u8], version: u32) -> Result<&[u8], Corruption> {
+    if version < BLOCK_CHECKSUM_FORMAT_VERSION {
+        return Ok(block);
+    }
+    if block.len() < BLOCK_HEADER_LENGTH + BLOCK_TRAILER_LENGTH {
+        return Err(Corruption(format!(
+            "Too short block: {} bytes",
+            block.len()
+        )));
+    }
+    let data_length = u64::from_le_bytes(block[..BLOCK_HEADER_LENGTH].try_into().unwrap());
+    let data = &block[BLOCK_HEADER_LENGTH..block.len() - BLOCK_TRAILER_LENGTH];
+    if data_length != data.len() as u64 {
+        return Err(Corruption(format!(
+            "Block length mismatch: expected {}, actual {}",
+            data_length,
+            data.len()
+        )));
+    }
+    let expected = u32::from_le_bytes(
+        block[block.len() - BLOCK_TRAILER_LENGTH..]
+            .try_into()
+            .unwrap(),
+    );
+    let actual = crc32c(data);
+    if expected != actual {
+        return Err(Corruption(format!(
+            "Checksum mismatch: expected {:#010x}, actual {:#010x}",
+            expected, actual
+        )));
+    }
+    Ok(data)
+}
+
+/// Split bytes of consecutive blocks, verify each of them and return serialized pairs in them.
+pub fn split_blocks(bytes: &[u8], version: u32) -> Result<Vec<&[u8]>, Corruption> {
+    if version < BLOCK_CHECKSUM_FORMAT_VERSION {
+        return Ok(vec![bytes]);
+    }
+    let mut blocks = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < BLOCK_HEADER_LENGTH {
+            return Err(Corruption("Truncated block header".to_string()));
+        }
+        let data_length = u64::from_le_bytes(rest[..BLOCK_HEADER_LENGTH].try_into().unwrap());
+        let block_length = (data_length as usize)
+            .checked_add(BLOCK_HEADER_LENGTH + BLOCK_TRAILER_LENGTH)
+            .filter(|length| *length <= rest.len())
+            .ok_or_else(|| Corruption(format!("Invalid block length: {}", data_length)))?;
+        blocks.push(verify_block(&rest[..block_length], version)?);
+        rest = &rest[block_length..];
+    }
+    Ok(blocks)
+}
+
+/// Footer at the end of an SSTable file since `INDEX_FORMAT_VERSION`.
+/// It locates the index block, which is framed like other blocks and placed just before the
+/// footer, so a table can be opened without reading its pairs.
+/// Since `FILTER_FORMAT_VERSION`, it also locates the filter block placed before the index.
+///
+/// Footer is laid out as follows:
+/// ```text
+/// +-----------------------+----------------------+------------------+
+/// | index position(8byte) | index length(8byte)  | data size(8byte) |
+/// +-----------------------+----------------------+------------------+
+/// | filter position(8byte)| filter length(8byte) | version(4byte)   | magic(8byte) |
+/// +-----------------------+----------------------+------------------+--------------+
+/// ```
+/// In `INDEX_FORMAT_VERSION`, there are no filter position and length.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Footer {
+    /// Position of the index block in the file.
+    pub index_position: u64,
+
+    /// Length of the index block.
+    pub index_length: u64,
+
+    /// Size of keys and values in the table.
+    pub size: u64,
+
+    /// Position of the filter block in the file.
+    pub filter_position: u64,
+
+    /// Length of the filter block. 0 if the file has no filter.
+    pub filter_length: u64,
+
+    /// Version of the encoding of the file.
+    pub version: u32,
+}
+
+impl Footer {
+    /// Length of a footer encoded in `version`.
+    pub fn length(version: u32) -> usize {
+        if version >= FILTER_FORMAT_VERSION {
+            52
         } else {
-            None
+            36
+        }
+    }
+
+    /// Encode the footer into `Footer::length(self.version)` bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(Self::length(self.version));
+        buffer.extend_from_slice(&self.index_position.to_le_bytes());
+        buffer.extend_from_slice(&self.index_length.to_le_bytes());
+        buffer.extend_from_slice(&self.size.to_le_bytes());
+        if self.version >= FILTER_FORMAT_VERSION {
+            buffer.extend_from_slice(&self.filter_position.to_le_bytes());
+            buffer.extend_from_slice(&self.filter_length.to_le_bytes());
+        }
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&TABLE_MAGIC_NUMBER.to_le_bytes());
+        buffer
+    }
+
+    /// Decode a footer encoded by `encode()` in `version`.
+    pub fn decode(bytes: &[u8], version: u32) -> Result<Self, Corruption> {
+        let length = Self::length(version);
+        if bytes.len() != length {
+            return Err(Corruption(format!(
+                "Invalid footer length: {} bytes",
+                bytes.len()
+            )));
+        }
+        let magic = u64::from_le_bytes(bytes[length - 8..].try_into().unwrap());
+        if magic != TABLE_MAGIC_NUMBER {
+            return Err(Corruption(format!("Invalid magic number: {:#018x}", magic)));
+        }
+        let read_u64 =
+            |position: usize| u64::from_le_bytes(bytes[position..position + 8].try_into().unwrap());
+        let (filter_position, filter_length) = if version >= FILTER_FORMAT_VERSION {
+            (read_u64(24), read_u64(32))
+        } else {
+            (0, 0)
         };
-        Ok(InternalPair { key, value })
+        Ok(Self {
+            index_position: read_u64(0),
+            index_length: read_u64(8),
+            size: read_u64(16),
+            filter_position,
+            filter_length,
+            version: u32::from_le_bytes(bytes[length - 12..length - 8].try_into().unwrap()),
+        })
     }
 }
 
@@ -89,9 +412,12 @@ mod tests {
 
     #[test]
     fn serialize() {
-        let pair = InternalPair::new("abc".as_bytes(), Some("defg".as_bytes()));
+        let pair = InternalPair::new("abc".as_bytes(), Some("defg".as_bytes())).with_sequence(42);
         assert_eq!(
-            vec![3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 100, 101, 102, 103,],
+            vec![
+                0, 42, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98,
+                99, 100, 101, 102, 103,
+            ],
             pair.serialize()
         );
     }
@@ -100,19 +426,43 @@ mod tests {
     fn serialize_lacking_value() {
         let pair = InternalPair::new("abc".as_bytes(), None);
         assert_eq!(
-            vec![3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99],
+            vec![
+                1, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98,
+                99
+            ],
             pair.serialize()
         );
     }
 
+    #[test]
+    fn serialize_empty_value() {
+        let pair = InternalPair::new("abc".as_bytes(), Some(b""));
+        assert_eq!(
+            vec![
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98,
+                99
+            ],
+            pair.serialize()
+        );
+    }
+
+    #[test]
+    fn serialize_legacy() {
+        let pair = InternalPair::new("abc".as_bytes(), Some("defg".as_bytes()));
+        assert_eq!(
+            vec![3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 100, 101, 102, 103,],
+            pair.serialize_with_version(LEGACY_FORMAT_VERSION)
+        );
+    }
+
     #[test]
     fn serialize_non_ascii() {
         let pair = InternalPair::new("日本語💖".as_bytes(), Some("ржавчина".as_bytes()));
         assert_eq!(
             vec![
-                13, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 230, 151, 165, 230, 156, 172,
-                232, 170, 158, 240, 159, 146, 150, 209, 128, 208, 182, 208, 176, 208, 178, 209,
-                135, 208, 184, 208, 189, 208, 176,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 230,
+                151, 165, 230, 156, 172, 232, 170, 158, 240, 159, 146, 150, 209, 128, 208, 182,
+                208, 176, 208, 178, 209, 135, 208, 184, 208, 189, 208, 176,
             ],
             pair.serialize()
         );
@@ -127,9 +477,10 @@ mod tests {
         ];
         assert_eq!(
             vec![
-                5, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 48, 100, 101, 102,
-                5, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 49, 100, 101, 102,
-                103, 5, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 50, 100, 101,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 97, 98,
+                99, 48, 48, 100, 101, 102, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 4, 0,
+                0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 49, 100, 101, 102, 103, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 5, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 50, 100, 101,
             ],
             InternalPair::serialize_flatten(&pairs)
         );
@@ -138,32 +489,68 @@ mod tests {
     #[tokio::test]
     async fn deserialize() {
         let bytes = vec![
-            3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 100, 101, 102, 103,
+            0, 7, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99,
+            100, 101, 102, 103,
         ];
         let pair = InternalPair::deserialize(&mut bytes.as_slice())
             .await
             .unwrap();
         assert_eq!(
             pair,
-            InternalPair::new("abc".as_bytes(), Some("defg".as_bytes()))
+            InternalPair::new("abc".as_bytes(), Some("defg".as_bytes())).with_sequence(7)
         );
     }
 
     #[tokio::test]
     async fn deserialize_lacking_value() {
-        let bytes = vec![3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99];
+        let bytes = vec![
+            1, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99,
+        ];
         let pair = InternalPair::deserialize(&mut bytes.as_slice())
             .await
             .unwrap();
         assert_eq!(InternalPair::new("abc".as_bytes(), None), pair);
     }
 
+    #[tokio::test]
+    async fn deserialize_empty_value() {
+        let bytes = vec![
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99,
+        ];
+        let pair = InternalPair::deserialize(&mut bytes.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(InternalPair::new("abc".as_bytes(), Some(b"")), pair);
+    }
+
+    #[tokio::test]
+    async fn deserialize_without_sequence() {
+        let pair = InternalPair::new(b"abc", Some(b"defg")).with_sequence(7);
+        let bytes = pair.serialize_with_version(FILTER_FORMAT_VERSION);
+        assert_eq!(
+            vec![InternalPair::new(b"abc", Some(b"defg"))],
+            InternalPair::deserialize_from_bytes(&bytes, FILTER_FORMAT_VERSION)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn deserialize_unknown_entry_type() {
+        let bytes = vec![
+            9, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99,
+        ];
+        assert!(InternalPair::deserialize(&mut bytes.as_slice())
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn deserialize_non_ascii() {
         let bytes = vec![
-            13, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 230, 151, 165, 230, 156, 172, 232,
-            170, 158, 240, 159, 146, 150, 209, 128, 208, 182, 208, 176, 208, 178, 209, 135, 208,
-            184, 208, 189, 208, 176,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 13, 0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 230, 151,
+            165, 230, 156, 172, 232, 170, 158, 240, 159, 146, 150, 209, 128, 208, 182, 208, 176,
+            208, 178, 209, 135, 208, 184, 208, 189, 208, 176,
         ];
         let pair = InternalPair::deserialize(&mut bytes.as_slice())
             .await
@@ -186,16 +573,133 @@ mod tests {
     async fn deserialize_from_bytes() {
         let pairs = vec![
             InternalPair::new("abc00".as_bytes(), Some("def".as_bytes())),
-            InternalPair::new("abc01".as_bytes(), Some("defg".as_bytes())),
-            InternalPair::new("abc02".as_bytes(), Some("de".as_bytes())),
+            InternalPair::new("abc01".as_bytes(), Some("".as_bytes())),
+            InternalPair::new("abc02".as_bytes(), None),
             InternalPair::new("abc03".as_bytes(), Some("defgh".as_bytes())),
         ];
-        let mut bytes: Vec<u8> = pairs.iter().flat_map(|pair| pair.serialize()).collect();
+        let bytes: Vec<u8> = pairs.iter().flat_map(|pair| pair.serialize()).collect();
         assert_eq!(
             pairs,
-            InternalPair::deserialize_from_bytes(&mut bytes)
+            InternalPair::deserialize_from_bytes(&bytes, FORMAT_VERSION)
                 .await
                 .unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn deserialize_from_legacy_bytes() {
+        let pairs = vec![
+            InternalPair::new("abc00".as_bytes(), Some("def".as_bytes())),
+            InternalPair::new("abc01".as_bytes(), None),
+            InternalPair::new("abc02".as_bytes(), Some("defgh".as_bytes())),
+        ];
+        let bytes = InternalPair::serialize_flatten_with_version(&pairs, LEGACY_FORMAT_VERSION);
+        assert_eq!(
+            pairs,
+            InternalPair::deserialize_from_bytes(&bytes, LEGACY_FORMAT_VERSION)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn encode_and_verify_block() {
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc01", None),
+        ];
+        let block = encode_block(&pairs, FORMAT_VERSION);
+        let data = InternalPair::serialize_flatten(&pairs);
+        assert_eq!(data.len() + 12, block.len());
+        assert_eq!(
+            Ok(data.as_slice()),
+            verify_block(&block, FORMAT_VERSION).map_err(|e| e.0)
+        );
+    }
+
+    #[test]
+    fn detect_corrupted_block() {
+        let pairs = vec![InternalPair::new(b"abc00", Some(b"def"))];
+        let mut block = encode_block(&pairs, FORMAT_VERSION);
+        block[12] ^= 0x01;
+        assert!(verify_block(&block, FORMAT_VERSION).is_err());
+        assert!(verify_block(&block[..block.len() - 1], FORMAT_VERSION).is_err());
+        assert!(verify_block(&block[..4], FORMAT_VERSION).is_err());
+    }
+
+    #[test]
+    fn split_consecutive_blocks() {
+        let first = vec![InternalPair::new(b"abc00", Some(b"def"))];
+        let second = vec![
+            InternalPair::new(b"abc01", Some(b"")),
+            InternalPair::new(b"abc02", None),
+        ];
+        let mut bytes = encode_block(&first, FORMAT_VERSION);
+        bytes.append(&mut encode_block(&second, FORMAT_VERSION));
+        assert_eq!(
+            vec![
+                InternalPair::serialize_flatten(&first),
+                InternalPair::serialize_flatten(&second)
+            ],
+            split_blocks(&bytes, FORMAT_VERSION)
+                .unwrap()
+                .into_iter()
+                .map(|data| data.to_vec())
+                .collect::<Vec<_>>()
+        );
+
+        // Broken length of the second block.
+        let position = encode_block(&first, FORMAT_VERSION).len();
+        bytes[position] = 0xff;
+        assert!(split_blocks(&bytes, FORMAT_VERSION).is_err());
+    }
+
+    #[test]
+    fn encode_and_decode_footer() {
+        let footer = Footer {
+            index_position: 123,
+            index_length: 45,
+            size: 67,
+            filter_position: 89,
+            filter_length: 10,
+            version: FORMAT_VERSION,
+        };
+        let mut bytes = footer.encode();
+        assert_eq!(Footer::length(FORMAT_VERSION), bytes.len());
+        assert_eq!(footer, Footer::decode(&bytes, FORMAT_VERSION).unwrap());
+
+        assert!(Footer::decode(&bytes[1..], FORMAT_VERSION).is_err());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        assert!(Footer::decode(&bytes, FORMAT_VERSION).is_err());
+    }
+
+    #[test]
+    fn decode_footer_without_filter() {
+        let footer = Footer {
+            index_position: 123,
+            index_length: 45,
+            size: 67,
+            filter_position: 0,
+            filter_length: 0,
+            version: INDEX_FORMAT_VERSION,
+        };
+        let bytes = footer.encode();
+        assert_eq!(36, bytes.len());
+        assert_eq!(
+            footer,
+            Footer::decode(&bytes, INDEX_FORMAT_VERSION).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn deserialize_broken_length() {
+        // Key length is broken to be extremely large.
+        let bytes = vec![
+            0, 3, 0, 0, 0, 0, 0, 0, 128, 4, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 100, 101, 102, 103,
+        ];
+        assert!(InternalPair::deserialize(&mut bytes.as_slice())
+            .await
+            .is_err());
+    }
 }
@@ -1,23 +1,36 @@
-use crate::command::Command;
+use crate::budget::MemoryBreakdown;
+use crate::command::{self, Command};
+use crate::cursor::Cursor;
+use crate::error::Error;
+use crate::etag::{etag, Precondition};
+use crate::format::{InternalPair, FORMAT_VERSION};
+use crate::sstable::manager::TableStats;
+use crate::stall::WriteController;
 use crate::Message;
+use hyper::body::{Bytes, Sender};
 use hyper::server::Server;
-use hyper::{service, Body, Request, Response, StatusCode};
+use hyper::{header, service, Body, Method, Request, Response, StatusCode};
 use log::{debug, info, warn};
-use std::convert::Infallible;
+use qstring::QString;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::convert::{Infallible, TryInto};
 use std::net;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
 /// Start running server.
 /// Clone handler for each request and spawn job for it.
+/// Writes are admitted by `write_controller`, which `MemTable` and `SSTableManager` report to.
 pub async fn serve(
     port: u16,
     memtable_tx: mpsc::Sender<Message>,
-    sstable_tx: mpsc::Sender<Message>,
+    write_controller: Arc<WriteController>,
 ) -> Result<(), hyper::Error> {
     let addr = net::IpAddr::from([127, 0, 0, 1]);
     let addr = net::SocketAddr::new(addr, port);
-    let handler = Handler::new(memtable_tx, sstable_tx);
+    let handler = Handler::new(memtable_tx, write_controller);
     let service = service::make_service_fn(move |_| {
         let handler = handler.clone();
         async move {
@@ -37,67 +50,538 @@ pub async fn serve(
     Ok(())
 }
 
-/// Structure to handle command and communicate with `MemTable` and `SSTableManager`.
+/// Structure to handle command and communicate with `MemTable`, which reads `SSTableManager`
+/// if needed.
 #[derive(Clone)]
 pub(crate) struct Handler {
     memtable_tx: mpsc::Sender<Message>,
-    sstable_tx: mpsc::Sender<Message>,
+
+    /// Gate which slows down or stops writes while flushes and compaction fall behind.
+    write_controller: Arc<WriteController>,
 }
 
 impl Handler {
     pub(crate) fn new(
         memtable_tx: mpsc::Sender<Message>,
-        sstable_tx: mpsc::Sender<Message>,
+        write_controller: Arc<WriteController>,
     ) -> Self {
         Self {
             memtable_tx,
-            sstable_tx,
+            write_controller,
         }
     }
 
-    /// Apply a command parsed from request to the stores.
-    async fn handle(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
-        if request.uri().path() != "/" {
-            return Ok(Response::builder()
+    /// Route a request by its path.
+    pub(crate) async fn handle(
+        &self,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        match request.uri().path() {
+            "/" => self.handle_command(request).await,
+            "/scan" => self.handle_scan(request).await,
+            "/batch" => self.handle_batch(request).await,
+            "/snapshot" => self.handle_snapshot(request).await,
+            "/admin/memory" => self.handle_memory(request).await,
+            "/admin/stats" => self.handle_stats(request).await,
+            _ => Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Body::empty())
-                .unwrap());
+                .unwrap()),
         }
+    }
+
+    /// Apply a command parsed from request to the stores.
+    /// A GET response carries an `ETag` of the value, and `If-Match` / `If-None-Match` headers
+    /// make GET, PUT and DELETE conditional on the tag of the current value.
+    async fn handle_command(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let precondition = match Precondition::from_headers(request.headers()) {
+            Ok(precondition) => precondition,
+            Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+        };
         let command = match Command::new(request.method(), request.uri().query()) {
             Ok(command) => command,
+            Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+        };
+        let command = match command {
+            command if precondition.is_empty() => command,
+            Command::Put { key, value } => Command::Conditional {
+                key,
+                value: Some(value),
+                precondition: precondition.clone(),
+            },
+            Command::Delete { key } => Command::Conditional {
+                key,
+                value: None,
+                precondition: precondition.clone(),
+            },
+            Command::CompareAndSwap { .. } => {
+                let err = Error::InvalidQuery(
+                    "expected and if_absent cannot be used with conditional headers".to_string(),
+                );
+                return Ok(error_response(StatusCode::BAD_REQUEST, err));
+            }
+            command => command,
+        };
+        let is_get = matches!(command, Command::Get { .. });
+        // Tag of the value written by this request.
+        let written_etag = match &command {
+            Command::Put { value, .. }
+            | Command::CompareAndSwap { new: value, .. }
+            | Command::Conditional {
+                value: Some(value), ..
+            } => Some(etag(value)),
+            _ => None,
+        };
+        let entry = match self.apply(command).await {
+            Ok(entry) => entry,
+            Err(Error::AlreadyExists) => {
+                return Ok(error_response(StatusCode::CONFLICT, Error::AlreadyExists))
+            }
+            Err(err @ (Error::ValueMismatch | Error::PreconditionFailed)) => {
+                return Ok(error_response(StatusCode::PRECONDITION_FAILED, err))
+            }
+            Err(err @ Error::UnknownSnapshot(_)) => {
+                return Ok(error_response(StatusCode::NOT_FOUND, err))
+            }
+            Err(Error::WriteStalled(retry_after)) => return Ok(stalled_response(retry_after)),
+            Err(err) => {
+                warn!("{}", err);
+                return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err));
+            }
+        };
+        let mut response = Response::builder();
+        if is_get {
+            if !precondition.if_match_holds(entry.as_deref()) {
+                return Ok(error_response(
+                    StatusCode::PRECONDITION_FAILED,
+                    Error::PreconditionFailed,
+                ));
+            }
+            if let Some(value) = entry.as_ref() {
+                response = response.header(header::ETAG, etag(value));
+            }
+            if !precondition.if_none_match_holds(entry.as_deref()) {
+                return Ok(response
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        } else if let Some(tag) = written_etag {
+            response = response.header(header::ETAG, tag);
+        }
+        Ok(response
+            .status(StatusCode::OK)
+            .body(Body::from(
+                entry.unwrap_or_else(|| b"Entry Not Found".to_vec()),
+            ))
+            .unwrap())
+    }
+
+    /// Apply puts and deletes in the body of a request atomically.
+    /// See `Command::batch()` for the format of the body.
+    async fn handle_batch(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let (parts, body) = request.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(err) => {
+                let err = Error::InvalidQuery(format!("Failed to read the body: {}", err));
+                return Ok(error_response(StatusCode::BAD_REQUEST, err));
+            }
+        };
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let command = match Command::batch(&parts.method, content_type, &body).await {
+            Ok(command) => command,
+            Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+        };
+        match self.apply(command).await {
+            Ok(_) => (),
+            Err(Error::WriteStalled(retry_after)) => return Ok(stalled_response(retry_after)),
             Err(err) => {
+                warn!("{}", err);
+                return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err));
+            }
+        }
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    /// Take a snapshot with POST and respond its id as JSON like `{"snapshot":42}`, or release
+    /// a snapshot with DELETE. See `Command::snapshot()` for the query.
+    /// The id is passed to GET and scan requests as `snapshot=42` to read data as of the
+    /// snapshot.
+    async fn handle_snapshot(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let command = match Command::snapshot(request.method(), request.uri().query()) {
+            Ok(command) => command,
+            Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+        };
+        let id = match self.apply(command).await {
+            Ok(Some(id)) => u64::from_le_bytes(id.as_slice().try_into().unwrap()),
+            Ok(None) => {
                 return Ok(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::from(format!("{}", err)))
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
                     .unwrap())
             }
+            Err(err @ Error::UnknownSnapshot(_)) => {
+                return Ok(error_response(StatusCode::NOT_FOUND, err))
+            }
+            Err(err) => {
+                warn!("{}", err);
+                return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err));
+            }
         };
-        let response = self
-            .apply(command)
-            .await
-            .unwrap_or_else(|| b"Entry Not Found".to_vec());
         Ok(Response::builder()
             .status(StatusCode::OK)
-            .body(Body::from(response))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&SnapshotCreated { snapshot: id }).unwrap(),
+            ))
             .unwrap())
     }
 
-    /// Communicate with the stores to apply a command
-    pub(crate) async fn apply(&self, command: Command) -> Option<Vec<u8>> {
-        let (tx, rx) = oneshot::channel();
-        self.memtable_tx.send((command.clone(), tx)).await.unwrap();
-        let entry = rx.await.unwrap();
-        if entry.is_some() {
-            entry
-        } else if let Command::Get { .. } = command {
-            // If there is no entry for the key, search SSTables
-            let (tx, rx) = oneshot::channel();
-            if self.sstable_tx.send((command, tx)).await.is_err() {
-                warn!("The receiver dropped");
+    /// Respond memory held by each component and the limit of the memory budget as JSON like
+    /// `{"limit":1048576,"total":5120,"memtable":1024,"immutables":0,"indexes":1024,
+    /// "block_cache":3072}`. `limit` is `null` if memory is not limited.
+    async fn handle_memory(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if request.method() != Method::GET {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                Error::InvalidMethod,
+            ));
+        }
+        let memory_budget = self.write_controller.memory_budget();
+        let usage = memory_budget.usage();
+        let report = MemoryReport {
+            limit: memory_budget.limit(),
+            total: usage.total(),
+            usage,
+        };
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&report).unwrap()))
+            .unwrap())
+    }
+
+    /// Respond counters of SSTable reads as JSON like
+    /// `{"filter_useful":3,"block_cache_hits":10,"block_cache_misses":2}`.
+    async fn handle_stats(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if request.method() != Method::GET {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                Error::InvalidMethod,
+            ));
+        }
+        let stats = match self.apply(Command::Stats).await {
+            Ok(Some(bytes)) => {
+                bincode::deserialize::<TableStats>(&bytes).map_err(|err| Error::Io(err.to_string()))
+            }
+            Ok(None) => Err(Error::Io("SSTableManager sent back no stats".to_string())),
+            Err(err) => Err(err),
+        };
+        match stats {
+            Ok(stats) => Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&stats).unwrap()))
+                .unwrap()),
+            Err(err) => Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err)),
+        }
+    }
+
+    /// Scan pairs in a range and respond a page of them as JSON like
+    /// `{"pairs":[{"key":"abc","value":"def"}],"cursor":"0300..."}`.
+    /// `cursor` is given if the page is full, and the next page is read by passing it in the
+    /// query with the same parameters. It is `null` at the last page.
+    /// With `snapshot=42`, pairs as of the snapshot are read, and the cursor keeps reading it.
+    /// Without it, the first page takes a snapshot which expires after `DEFAULT_SNAPSHOT_TTL`,
+    /// and the cursor carries it so that all pages are consistent with each other. The snapshot
+    /// is released at the last page.
+    ///
+    /// With `format=ndjson`, all pairs in the range are streamed instead as newline-delimited
+    /// JSON like `{"key":"abc","value":"def"}`, reading `limit` pairs from the stores at a time.
+    async fn handle_scan(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let query = QString::from(request.uri().query().unwrap_or(""));
+        let stream = match query.get("format") {
+            None | Some("json") => false,
+            Some("ndjson") => true,
+            Some(format) => {
+                let err = Error::InvalidQuery(format!("Invalid format: {}", format));
+                return Ok(error_response(StatusCode::BAD_REQUEST, err));
+            }
+        };
+        let (start, end, limit, reverse, snapshot) =
+            match Command::scan(request.method(), request.uri().query()) {
+                Ok(Command::Scan {
+                    start,
+                    end,
+                    limit,
+                    reverse,
+                    snapshot,
+                }) => (start, end, limit, reverse, snapshot),
+                Ok(_) => unreachable!(),
+                Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+            };
+        if stream {
+            let (sender, body) = Body::channel();
+            let handler = self.clone();
+            tokio::spawn(async move {
+                handler
+                    .stream_scan(sender, start, end, limit, reverse, snapshot)
+                    .await
+            });
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/x-ndjson")
+                .body(body)
+                .unwrap());
+        }
+        // A cursor was validated by `Command::scan()`.
+        let owned_by_cursor = query
+            .get("cursor")
+            .and_then(|token| Cursor::decode(token).ok())
+            .is_some_and(|cursor| cursor.owns_snapshot);
+        let (snapshot, owned) = match snapshot {
+            Some(snapshot) => (snapshot, owned_by_cursor),
+            None => match self.take_snapshot().await {
+                Ok(snapshot) => (snapshot, true),
+                Err(err) => {
+                    warn!("{}", err);
+                    return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err));
+                }
+            },
+        };
+        let result = self.scan(start, end, limit, reverse, Some(snapshot)).await;
+        // The snapshot is no longer needed if there is no next page.
+        let has_next = matches!(&result, Ok(pairs) if !pairs.is_empty() && pairs.len() == limit);
+        if owned && !has_next {
+            self.release_snapshot(snapshot).await;
+        }
+        let pairs = match result {
+            Ok(pairs) => pairs,
+            Err(err @ Error::UnknownSnapshot(_)) => {
+                return Ok(error_response(StatusCode::NOT_FOUND, err))
+            }
+            Err(err) => {
+                warn!("{}", err);
+                return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, err));
             }
-            rx.await.unwrap()
-        } else {
-            None
+        };
+        let cursor = match pairs.last() {
+            Some(pair) if pairs.len() == limit => Some(
+                Cursor {
+                    last_key: pair.key.clone(),
+                    snapshot: Some(snapshot),
+                    owns_snapshot: owned,
+                }
+                .encode(),
+            ),
+            _ => None,
+        };
+        let page = ScanPage {
+            pairs: pairs.iter().map(ScanEntry::from).collect(),
+            cursor,
+        };
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&page).unwrap()))
+            .unwrap())
+    }
+
+    /// Send all pairs in `[start, end)` to `sender` as newline-delimited JSON, one chunk for
+    /// each `limit` pairs. Only a chunk is held in memory at a time, and the next chunk is not
+    /// read until the client consumes the previous one.
+    /// If reading the stores fails, the body is aborted so that the client notices the export
+    /// is incomplete. All chunks are read from `snapshot`, or from a snapshot taken for the
+    /// stream if it is `None`.
+    async fn stream_scan(
+        &self,
+        mut sender: Sender,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+        reverse: bool,
+        snapshot: Option<u64>,
+    ) {
+        if limit == 0 {
+            return;
+        }
+        let result = match snapshot {
+            Some(snapshot) => {
+                self.stream_chunks(&mut sender, start, end, limit, reverse, snapshot)
+                    .await
+            }
+            None => match self.take_snapshot().await {
+                Ok(snapshot) => {
+                    let result = self
+                        .stream_chunks(&mut sender, start, end, limit, reverse, snapshot)
+                        .await;
+                    self.release_snapshot(snapshot).await;
+                    result
+                }
+                Err(err) => Err(err),
+            },
+        };
+        if let Err(err) = result {
+            warn!("{}", err);
+            sender.abort();
+        }
+    }
+
+    /// Send chunks of pairs for `stream_scan()` as of `snapshot`.
+    /// Return an error if reading the stores fails.
+    async fn stream_chunks(
+        &self,
+        sender: &mut Sender,
+        mut start: Vec<u8>,
+        mut end: Option<Vec<u8>>,
+        limit: usize,
+        reverse: bool,
+        snapshot: u64,
+    ) -> Result<(), Error> {
+        loop {
+            let pairs = self
+                .scan(start.clone(), end.clone(), limit, reverse, Some(snapshot))
+                .await?;
+            let mut chunk = Vec::new();
+            for pair in &pairs {
+                serde_json::to_writer(&mut chunk, &ScanEntry::from(pair)).unwrap();
+                chunk.push(b'\n');
+            }
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                debug!("The client disconnected during a scan");
+                return Ok(());
+            }
+            if pairs.len() < limit {
+                return Ok(());
+            }
+            let last_key = pairs.into_iter().last().unwrap().key;
+            command::resume_range(&mut start, &mut end, last_key, reverse);
+        }
+    }
+
+    /// Take a snapshot for a scan of several pages or chunks, which expires after
+    /// `DEFAULT_SNAPSHOT_TTL`.
+    async fn take_snapshot(&self) -> Result<u64, Error> {
+        let command = Command::CreateSnapshot {
+            ttl: command::DEFAULT_SNAPSHOT_TTL,
+        };
+        let id = self.apply(command).await?.unwrap_or_default();
+        id.as_slice()
+            .try_into()
+            .map(u64::from_le_bytes)
+            .map_err(|_| Error::Io("Invalid snapshot id".to_string()))
+    }
+
+    /// Release a snapshot taken by `take_snapshot()`.
+    /// A failure is only logged because the snapshot expires anyway.
+    async fn release_snapshot(&self, id: u64) {
+        if let Err(err) = self.apply(Command::ReleaseSnapshot { id }).await {
+            debug!("Failed to release snapshot {}: {}", id, err);
+        }
+    }
+
+    /// Communicate with the stores to apply a command.
+    /// `MemTable` reads SSTables by itself if they are needed.
+    /// A write is delayed or fails with `Error::WriteStalled` while flushes and compaction fall
+    /// behind.
+    pub(crate) async fn apply(&self, command: Command) -> Result<Option<Vec<u8>>, Error> {
+        if command.is_write() {
+            self.write_controller.admit().await?;
         }
+        let (tx, rx) = oneshot::channel();
+        self.memtable_tx.send((command, tx)).await.unwrap();
+        rx.await.unwrap()
     }
+
+    /// Get at most `limit` pairs whose keys are in `[start, end)` from the stores in order of
+    /// keys, or in descending order if `reverse` is `true`.
+    /// If `snapshot` is given, pairs as of the snapshot are read.
+    /// See `MemTable::read_range()` for how pairs are merged.
+    pub(crate) async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+        reverse: bool,
+        snapshot: Option<u64>,
+    ) -> Result<Vec<InternalPair>, Error> {
+        let command = Command::Scan {
+            start,
+            end,
+            limit,
+            reverse,
+            snapshot,
+        };
+        let bytes = self.apply(command).await?.unwrap_or_default();
+        InternalPair::deserialize_from_bytes(&bytes, FORMAT_VERSION)
+            .await
+            .map_err(|err| Error::Io(err.to_string()))
+    }
+}
+
+/// A response of taking a snapshot.
+#[derive(Serialize)]
+struct SnapshotCreated {
+    snapshot: u64,
+}
+
+/// A response of memory usage.
+#[derive(Serialize)]
+struct MemoryReport {
+    limit: Option<usize>,
+    total: usize,
+
+    #[serde(flatten)]
+    usage: MemoryBreakdown,
+}
+
+/// A response of a scan.
+#[derive(Serialize)]
+struct ScanPage<'a> {
+    pairs: Vec<ScanEntry<'a>>,
+
+    /// Token to read the next page.
+    cursor: Option<String>,
+}
+
+/// A pair in a response of a scan.
+#[derive(Serialize)]
+struct ScanEntry<'a> {
+    key: Cow<'a, str>,
+    value: Cow<'a, str>,
+}
+
+impl<'a> From<&'a InternalPair> for ScanEntry<'a> {
+    fn from(pair: &'a InternalPair) -> Self {
+        Self {
+            key: String::from_utf8_lossy(&pair.key),
+            value: String::from_utf8_lossy(pair.value.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+/// Respond that a write is stalled and may be retried after `retry_after` seconds.
+fn stalled_response(retry_after: u64) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        Error::WriteStalled(retry_after),
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after.into());
+    response
+}
+
+fn error_response(status: StatusCode, err: Error) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("{}", err)))
+        .unwrap()
 }
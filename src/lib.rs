@@ -1,89 +1,168 @@
+mod budget;
+mod checksum;
 mod command;
 mod config;
+mod cursor;
 mod error;
+mod etag;
 mod format;
 pub mod http;
 pub mod memtable;
+mod snapshot;
 pub mod sstable;
+mod stall;
 
-pub use crate::config::Config;
+pub use crate::budget::{MemoryBreakdown, MemoryBudget};
+pub use crate::config::{Config, MemTableBackend, SyncMode};
 pub use crate::http::server::serve;
 pub use memtable::MemTable;
 pub use sstable::manager::SSTableManager;
+pub use stall::{Backlog, WriteController};
 
 use command::Command;
+use error::Error;
 use tokio::sync::oneshot;
 
 /// Message sent to a store(`MemTable` or `SSTableManager`).
 /// This holds `mpsc::Sender` because the store have to send back response
 /// to sender of the `Message`.
-type Message = (Command, oneshot::Sender<Option<Vec<u8>>>);
+type Message = (Command, oneshot::Sender<Result<Option<Vec<u8>>, Error>>);
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::format::InternalPair;
     use crate::http::server::Handler;
+    use crate::stall::tests::limit_immutables;
     use std::io;
+    use std::sync::Arc;
     use tokio::sync::mpsc;
 
-    const MEMTABLE_SIZE: usize = 128;
+    const MEMTABLE_SIZE: usize = 1024;
+    const MAX_IMMUTABLES: usize = 2;
 
-    #[tokio::test]
-    async fn put_and_get_integrated() -> io::Result<()> {
-        let (memtable_tx, memtable_rx) = mpsc::channel(1);
-        let (sstable_tx, sstable_rx) = mpsc::channel(32);
-        let mut memtable = MemTable::new(MEMTABLE_SIZE, memtable_rx, sstable_tx.clone());
-
-        let directory = "test_put_and_get";
-        let _ = std::fs::create_dir(directory);
-        let mut manager = SSTableManager::new(directory, 3, 1000, sstable_rx).await?;
-        manager
-            .create(
-                vec![
-                    InternalPair::new(b"rust", Some(b"wonderful")),
-                    InternalPair::new(b"xxx", Some(b"sstable")),
-                ],
-                23,
+    /// Options of a `MemTable` and an `SSTableManager` which tests run.
+    struct Setup {
+        backend: MemTableBackend,
+        sync_mode: SyncMode,
+        write_controller: Arc<WriteController>,
+        compaction_trigger_ratio: u64,
+        block_cache_size: usize,
+
+        /// Contents of SSTables created before the stores start.
+        tables: Vec<Vec<InternalPair>>,
+    }
+
+    impl Default for Setup {
+        fn default() -> Self {
+            Self {
+                backend: MemTableBackend::default(),
+                sync_mode: SyncMode::Batch,
+                write_controller: limit_immutables(MAX_IMMUTABLES),
+                compaction_trigger_ratio: 1000,
+                block_cache_size: 1024,
+                tables: Vec::new(),
+            }
+        }
+    }
+
+    impl Setup {
+        /// Create the stores in an empty `directory` and a handler sending commands to them.
+        /// The stores are not listening yet.
+        async fn open(self, directory: &str) -> io::Result<(Handler, MemTable, SSTableManager)> {
+            let (memtable_tx, memtable_rx) = mpsc::channel(32);
+            let (sstable_tx, sstable_rx) = mpsc::channel(32);
+            crate::sstable::tests::prepare_directory(directory);
+            let memtable = MemTable::new(
+                directory,
+                self.backend,
+                MEMTABLE_SIZE,
+                self.write_controller.clone(),
+                self.sync_mode,
+                memtable_rx,
+                sstable_tx,
             )
             .await?;
+            let mut manager = SSTableManager::new(
+                directory,
+                3,
+                10,
+                self.compaction_trigger_ratio,
+                self.block_cache_size,
+                self.sync_mode,
+                sstable_rx,
+            )
+            .await?;
+            for pairs in self.tables {
+                let size = pairs.iter().map(InternalPair::size).sum();
+                manager.create(pairs, size).await?;
+            }
+            let manager = manager.with_write_controller(self.write_controller.clone());
+            Ok((
+                Handler::new(memtable_tx, self.write_controller),
+                memtable,
+                manager,
+            ))
+        }
 
-        tokio::spawn(async move { memtable.listen().await });
-        tokio::spawn(async move { manager.listen().await });
+        /// Same as `open()` but the stores start listening.
+        async fn start(self, directory: &str) -> io::Result<Handler> {
+            let (handler, memtable, mut manager) = self.open(directory).await?;
+            tokio::spawn(Arc::new(memtable).listen());
+            tokio::spawn(async move { manager.listen().await });
+            Ok(handler)
+        }
+    }
 
-        let handler = Handler::new(memtable_tx, sstable_tx);
+    #[tokio::test]
+    async fn put_and_get_integrated() -> io::Result<()> {
+        let handler = Setup {
+            tables: vec![vec![
+                InternalPair::new(b"rust", Some(b"wonderful")),
+                InternalPair::new(b"xxx", Some(b"sstable")),
+            ]],
+            ..Setup::default()
+        }
+        .start("test_put_and_get")
+        .await?;
         handler
             .apply(Command::Put {
                 key: b"abc".to_vec(),
                 value: b"def".to_vec(),
             })
-            .await;
+            .await
+            .unwrap();
         handler
             .apply(Command::Put {
                 key: b"xxx".to_vec(),
                 value: b"memtable".to_vec(),
             })
-            .await;
+            .await
+            .unwrap();
 
         // Simply read from MemTable
         assert_eq!(
             b"def".to_vec(),
             handler
                 .apply(Command::Get {
-                    key: b"abc".to_vec()
+                    key: b"abc".to_vec(),
+                    snapshot: None,
                 })
                 .await
                 .unwrap()
+                .unwrap()
         );
         // Exists the same entry in SSTable, but read from MemTable
         assert_eq!(
             b"memtable".to_vec(),
             handler
                 .apply(Command::Get {
-                    key: b"xxx".to_vec()
+                    key: b"xxx".to_vec(),
+                    snapshot: None,
                 })
                 .await
                 .unwrap()
+                .unwrap()
         );
         // Simply read from SSTable
         assert_eq!(
@@ -91,10 +170,703 @@ mod tests {
             handler
                 .apply(Command::Get {
                     key: b"rust".to_vec(),
+                    snapshot: None,
                 })
                 .await
                 .unwrap()
+                .unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_integrated() -> io::Result<()> {
+        let handler = Setup {
+            tables: vec![vec![
+                InternalPair::new(b"a", Some(b"sstable")),
+                InternalPair::new(b"b", Some(b"sstable")),
+                InternalPair::new(b"c", Some(b"sstable")),
+                InternalPair::new(b"d", Some(b"sstable")),
+            ]],
+            ..Setup::default()
+        }
+        .start("test_scan_integrated")
+        .await?;
+        handler
+            .apply(Command::Delete { key: b"a".to_vec() })
+            .await
+            .unwrap();
+        handler
+            .apply(Command::Put {
+                key: b"c".to_vec(),
+                value: b"memtable".to_vec(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                InternalPair::new(b"b", Some(b"sstable")),
+                InternalPair::new(b"c", Some(b"memtable")).with_sequence(2),
+            ],
+            handler.scan(vec![], None, 2, false, None).await.unwrap()
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"c", Some(b"memtable")).with_sequence(2),
+                InternalPair::new(b"d", Some(b"sstable")),
+            ],
+            handler
+                .scan(b"bb".to_vec(), Some(b"e".to_vec()), 10, false, None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"d", Some(b"sstable")),
+                InternalPair::new(b"c", Some(b"memtable")).with_sequence(2),
+                InternalPair::new(b"b", Some(b"sstable")),
+            ],
+            handler.scan(vec![], None, 10, true, None).await.unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stall_writes_while_tables_pile_up() -> io::Result<()> {
+        let hard_limit = Backlog {
+            tables: 2,
+            ..Backlog::UNLIMITED
+        };
+        let write_controller = Arc::new(WriteController::new(
+            Backlog::UNLIMITED,
+            hard_limit,
+            std::time::Duration::ZERO,
+            std::time::Duration::from_millis(100),
+        ));
+        let (handler, memtable, mut manager) = Setup {
+            sync_mode: SyncMode::None,
+            write_controller: write_controller.clone(),
+            // The amplification ratio never triggers compaction.
+            compaction_trigger_ratio: u64::MAX,
+            // Tables left by a run with higher limits.
+            tables: (0..2)
+                .map(|i| vec![InternalPair::new(format!("k{}", i).as_bytes(), Some(b"v"))])
+                .collect(),
+            ..Setup::default()
+        }
+        .open("test_stall_writes_while_tables_pile_up")
+        .await?;
+        tokio::spawn(Arc::new(memtable).listen());
+
+        // Writes stall until the manager starts compaction.
+        let request = hyper::Request::put("/?key=k2&value=v")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("1", response.headers()[hyper::header::RETRY_AFTER]);
+
+        // Tables are compacted without any flush, and writes resume.
+        tokio::spawn(async move { manager.listen().await });
+        while write_controller.backlog().tables >= 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let request = hyper::Request::put("/?key=k2&value=v")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        for i in 0..3 {
+            let value = handler
+                .apply(Command::Get {
+                    key: format!("k{}", i).into_bytes(),
+                    snapshot: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(Some(b"v".to_vec()), value);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_memory_in_budget() -> io::Result<()> {
+        const BUDGET: usize = 600;
+
+        let write_controller =
+            Arc::new(WriteController::default().with_memory_budget(MemoryBudget::new(BUDGET)));
+        let handler = Setup {
+            sync_mode: SyncMode::None,
+            write_controller: write_controller.clone(),
+            compaction_trigger_ratio: u64::MAX,
+            block_cache_size: 1 << 20,
+            ..Setup::default()
+        }
+        .start("test_keep_memory_in_budget")
+        .await?;
+        // Contents are smaller than `MEMTABLE_SIZE` but do not fit in the budget.
+        for i in 0..5 {
+            handler
+                .apply(Command::Put {
+                    key: format!("k{}", i).into_bytes(),
+                    value: vec![b'v'; 100],
+                })
+                .await
+                .unwrap();
+        }
+        let memory_budget = write_controller.memory_budget();
+        while write_controller.backlog().tables == 0 || memory_budget.usage().immutables > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(memory_budget.usage().indexes > 0);
+
+        for i in 0..5 {
+            let value = handler
+                .apply(Command::Get {
+                    key: format!("k{}", i).into_bytes(),
+                    snapshot: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(Some(vec![b'v'; 100]), value);
+        }
+        // Blocks are evicted to fit in the budget.
+        let usage = memory_budget.usage();
+        assert!(usage.total() <= BUDGET, "{:?}", usage);
+
+        let request = hyper::Request::get("/admin/memory")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(BUDGET, report["limit"].as_u64().unwrap() as usize);
+        let usage = memory_budget.usage();
+        assert_eq!(usage.indexes, report["indexes"].as_u64().unwrap() as usize);
+        assert_eq!(usage.total(), report["total"].as_u64().unwrap() as usize);
+
+        // The reads above went to the flushed table.
+        let request = hyper::Request::get("/admin/stats")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(stats["block_cache_misses"].as_u64().unwrap() > 0);
+        assert!(stats["block_cache_hits"].is_u64());
+        assert!(stats["filter_useful"].is_u64());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_writes_over_memory_budget() -> io::Result<()> {
+        let write_controller = Arc::new(
+            WriteController::new(
+                Backlog::UNLIMITED,
+                Backlog {
+                    immutables: MAX_IMMUTABLES,
+                    ..Backlog::UNLIMITED
+                },
+                std::time::Duration::ZERO,
+                std::time::Duration::from_secs(1),
+            )
+            .with_memory_budget(MemoryBudget::new(MEMTABLE_SIZE)),
         );
+        let handler = Setup {
+            sync_mode: SyncMode::None,
+            write_controller: write_controller.clone(),
+            compaction_trigger_ratio: u64::MAX,
+            tables: vec![(0..200)
+                .map(|i| InternalPair::new(format!("old{:03}", i).as_bytes(), Some(b"v")))
+                .collect()],
+            ..Setup::default()
+        }
+        .start("test_resume_writes_over_memory_budget")
+        .await?;
+        // Indexes alone exceed the budget, so `MemTable` is not flushed early.
+        let memory_budget = write_controller.memory_budget();
+        assert!(memory_budget.usage().indexes > MEMTABLE_SIZE);
+
+        // Writes go on until `MemTable` is full, and resume after it is flushed.
+        for i in 0..20 {
+            let command = Command::Put {
+                key: format!("k{:02}", i).into_bytes(),
+                value: vec![b'v'; 100],
+            };
+            assert_eq!(Ok(None), handler.apply(command).await);
+        }
+        assert!(write_controller.backlog().tables > 1);
+        for i in 0..20 {
+            let value = handler
+                .apply(Command::Get {
+                    key: format!("k{:02}", i).into_bytes(),
+                    snapshot: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(Some(vec![b'v'; 100]), value);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paginate_scan_across_flush() -> io::Result<()> {
+        let (handler, memtable, mut manager) = Setup::default()
+            .open("test_paginate_scan_across_flush")
+            .await?;
+        let memtable = Arc::new(memtable);
+        tokio::spawn(memtable.clone().listen());
+        tokio::spawn(async move { manager.listen().await });
+        for i in 0..10 {
+            handler
+                .apply(Command::Put {
+                    key: format!("k{:02}", i).into_bytes(),
+                    value: b"v".to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut keys = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let uri = match &cursor {
+                Some(cursor) => format!("/scan?limit=3&cursor={}", cursor),
+                None => "/scan?limit=3".to_string(),
+            };
+            let request = hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+            let response = handler.handle(request).await.unwrap();
+            assert_eq!(hyper::StatusCode::OK, response.status());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            for pair in page["pairs"].as_array().unwrap() {
+                keys.push(pair["key"].as_str().unwrap().to_string());
+            }
+            cursor = page["cursor"].as_str().map(|cursor| cursor.to_string());
+            if cursor.is_none() {
+                break;
+            }
+            if keys.len() == 3 {
+                // Flush contents of `MemTable` between pages.
+                handler
+                    .apply(Command::Put {
+                        key: b"k99".to_vec(),
+                        value: vec![b'v'; MEMTABLE_SIZE],
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        // The cursor carries the snapshot taken for the first page, so the put between pages
+        // is not seen.
+        let expected: Vec<_> = (0..10).map(|i| format!("k{:02}", i)).collect();
+        assert_eq!(expected, keys);
+        assert!(std::path::Path::new("test_paginate_scan_across_flush/table_0").exists());
+        // The snapshot is released at the last page.
+        assert!(memtable.snapshot_sequences().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_scan_as_ndjson() -> io::Result<()> {
+        let handler = Setup::default().start("test_stream_scan_as_ndjson").await?;
+        for i in 0..7 {
+            handler
+                .apply(Command::Put {
+                    key: format!("k{}", i).into_bytes(),
+                    value: format!("v{}", i).into_bytes(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let request = hyper::Request::get("/scan?format=ndjson&limit=2&start=k1&reverse=true")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        let request = hyper::Request::get("/scan?format=ndjson&limit=2&start=k1")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let forward = handler.handle(request).await.unwrap();
+        assert_eq!(
+            "application/x-ndjson",
+            forward.headers()[hyper::header::CONTENT_TYPE]
+        );
+        let body = hyper::body::to_bytes(forward.into_body()).await.unwrap();
+        let expected: String = (1..7)
+            .map(|i| format!("{{\"key\":\"k{}\",\"value\":\"v{}\"}}\n", i, i))
+            .collect();
+        assert_eq!(expected.as_bytes(), &body[..]);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let expected: String = (1..7)
+            .rev()
+            .map(|i| format!("{{\"key\":\"k{}\",\"value\":\"v{}\"}}\n", i, i))
+            .collect();
+        assert_eq!(expected.as_bytes(), &body[..]);
+
+        let request = hyper::Request::get("/scan?format=xml")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::BAD_REQUEST, response.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_batch_integrated() -> io::Result<()> {
+        let handler = Setup::default()
+            .start("test_write_batch_integrated")
+            .await?;
+        handler
+            .apply(Command::Put {
+                key: b"abc".to_vec(),
+                value: b"old".to_vec(),
+            })
+            .await
+            .unwrap();
+        let body = r#"[{"op":"put","key":"xyz","value":"new"},{"op":"delete","key":"abc"}]"#;
+        let request = hyper::Request::post("/batch")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body))
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::NO_CONTENT, response.status());
+        let get = |key: &[u8]| Command::Get {
+            key: key.to_vec(),
+            snapshot: None,
+        };
+        assert_eq!(Ok(None), handler.apply(get(b"abc")).await);
+        assert_eq!(Ok(Some(b"new".to_vec())), handler.apply(get(b"xyz")).await);
+
+        let request = hyper::Request::post("/batch")
+            .body(hyper::Body::from("[{\"op\":\"put\"}]"))
+            .unwrap();
+        let response = handler.handle(request).await.unwrap();
+        assert_eq!(hyper::StatusCode::BAD_REQUEST, response.status());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap_integrated() -> io::Result<()> {
+        let handler = Setup::default()
+            .start("test_compare_and_swap_integrated")
+            .await?;
+        handler
+            .apply(Command::Put {
+                key: b"leader".to_vec(),
+                value: b"node1".to_vec(),
+            })
+            .await
+            .unwrap();
+        // Flush "leader" to an SSTable.
+        handler
+            .apply(Command::Put {
+                key: b"padding".to_vec(),
+                value: vec![b'v'; MEMTABLE_SIZE],
+            })
+            .await
+            .unwrap();
+
+        let put = |query: &str| {
+            let request = hyper::Request::put(format!("/?{}", query))
+                .body(hyper::Body::empty())
+                .unwrap();
+            handler.handle(request)
+        };
+        let status = |response: hyper::Response<hyper::Body>| response.status();
+        assert_eq!(
+            hyper::StatusCode::CONFLICT,
+            status(put("key=leader&value=node2&if_absent=true").await.unwrap())
+        );
+        assert_eq!(
+            hyper::StatusCode::PRECONDITION_FAILED,
+            status(put("key=leader&value=node2&expected=node3").await.unwrap())
+        );
+        assert_eq!(
+            hyper::StatusCode::OK,
+            status(put("key=leader&value=node2&expected=node1").await.unwrap())
+        );
+        assert_eq!(
+            hyper::StatusCode::OK,
+            status(put("key=counter&value=1&if_absent=true").await.unwrap())
+        );
+        let get = |key: &[u8]| Command::Get {
+            key: key.to_vec(),
+            snapshot: None,
+        };
+        assert_eq!(
+            Ok(Some(b"node2".to_vec())),
+            handler.apply(get(b"leader")).await
+        );
+        assert_eq!(
+            Ok(Some(b"1".to_vec())),
+            handler.apply(get(b"counter")).await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn conditional_requests_integrated() -> io::Result<()> {
+        use hyper::{Method, StatusCode};
+
+        let handler = Setup::default()
+            .start("test_conditional_requests_integrated")
+            .await?;
+        let request = |method: hyper::Method, query: &str, header: Option<(&str, &str)>| {
+            let mut request = hyper::Request::builder()
+                .method(method)
+                .uri(format!("/?{}", query));
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            handler.handle(request.body(hyper::Body::empty()).unwrap())
+        };
+        // Create only if absent.
+        let response = request(
+            Method::PUT,
+            "key=abc&value=v1",
+            Some(("If-None-Match", "*")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let tag = response.headers()[hyper::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = request(
+            Method::PUT,
+            "key=abc&value=v2",
+            Some(("If-None-Match", "*")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+
+        let response = request(Method::GET, "key=abc", None).await.unwrap();
+        assert_eq!(tag, response.headers()[hyper::header::ETAG]);
+        let response = request(Method::GET, "key=abc", Some(("If-None-Match", &tag)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        // Update only if unchanged.
+        let response = request(Method::PUT, "key=abc&value=v2", Some(("If-Match", &tag)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_ne!(tag, response.headers()[hyper::header::ETAG]);
+        let response = request(Method::DELETE, "key=abc", Some(("If-Match", &tag)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+        let response = request(Method::GET, "key=abc", Some(("If-Match", &tag)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+        let response = request(Method::DELETE, "key=abc", Some(("If-Match", "*")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            Ok(None),
+            handler
+                .apply(Command::Get {
+                    key: b"abc".to_vec(),
+                    snapshot: None,
+                })
+                .await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn snapshot_integrated() -> io::Result<()> {
+        use hyper::{Method, StatusCode};
+
+        let handler = Setup::default().start("test_snapshot_integrated").await?;
+        let request = |method: Method, uri: String| {
+            let request = hyper::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(hyper::Body::empty())
+                .unwrap();
+            handler.handle(request)
+        };
+        let body = |response: hyper::Response<hyper::Body>| async move {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+        let put = |key: &[u8], value: &[u8]| {
+            handler.apply(Command::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })
+        };
+        put(b"abc", b"1").await.unwrap();
+        put(b"abd", b"1").await.unwrap();
+
+        let response = request(Method::POST, "/snapshot?ttl=60".to_string())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let created: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        let id = created["snapshot"].as_u64().unwrap();
+
+        put(b"abc", b"2").await.unwrap();
+        put(b"abe", b"2").await.unwrap();
+        // Flush all versions to an SSTable.
+        put(b"padding", &[b'v'; MEMTABLE_SIZE]).await.unwrap();
+        put(b"abc", b"3").await.unwrap();
+
+        let response = request(Method::GET, format!("/?key=abc&snapshot={}", id))
+            .await
+            .unwrap();
+        assert_eq!(b"1"[..], body(response).await);
+        let response = request(Method::GET, "/?key=abc".to_string()).await.unwrap();
+        assert_eq!(b"3"[..], body(response).await);
+        let response = request(Method::GET, format!("/?key=abe&snapshot={}", id))
+            .await
+            .unwrap();
+        assert_eq!(b"Entry Not Found"[..], body(response).await);
+
+        // The cursor keeps reading the snapshot.
+        let response = request(Method::GET, format!("/scan?limit=1&snapshot={}", id))
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(
+            serde_json::json!([{"key": "abc", "value": "1"}]),
+            page["pairs"]
+        );
+        let cursor = page["cursor"].as_str().unwrap();
+        let response = request(Method::GET, format!("/scan?limit=10&cursor={}", cursor))
+            .await
+            .unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(
+            serde_json::json!([{"key": "abd", "value": "1"}]),
+            page["pairs"]
+        );
+
+        let response = request(Method::DELETE, format!("/snapshot?id={}", id))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let response = request(Method::GET, format!("/?key=abc&snapshot={}", id))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = request(Method::GET, format!("/scan?cursor={}", cursor))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = request(Method::DELETE, format!("/snapshot?id={}", id))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        Ok(())
+    }
+
+    /// Writers put increasing values and delete them while flushes and compactions run, and
+    /// readers check that no value goes back to an older one or revives after a deletion.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn consistent_reads_under_flush_stress() -> io::Result<()> {
+        const KEYS: usize = 8;
+        const ROUNDS: usize = 40;
+
+        let handler = Setup {
+            // Reads and writes run concurrently.
+            backend: MemTableBackend::SkipList,
+            sync_mode: SyncMode::None,
+            // Writes often wait for flushes.
+            write_controller: limit_immutables(1),
+            compaction_trigger_ratio: 25,
+            ..Setup::default()
+        }
+        .start("test_consistent_reads_under_flush_stress")
+        .await?;
+
+        let get = |handler: &Handler, key: &str| {
+            let handler = handler.clone();
+            let key = key.as_bytes().to_vec();
+            async move {
+                handler
+                    .apply(Command::Get {
+                        key,
+                        snapshot: None,
+                    })
+                    .await
+                    .unwrap()
+            }
+        };
+        let mut writers = Vec::new();
+        for k in 0..KEYS {
+            let handler = handler.clone();
+            writers.push(tokio::spawn(async move {
+                let key = format!("key:{}", k);
+                for round in 0..ROUNDS {
+                    let value = format!("{:04}", round).into_bytes();
+                    handler
+                        .apply(Command::Put {
+                            key: key.as_bytes().to_vec(),
+                            value: value.clone(),
+                        })
+                        .await
+                        .unwrap();
+                    // Fill `MemTable` to flush it frequently.
+                    handler
+                        .apply(Command::Put {
+                            key: format!("padding:{}:{}", k, round).into_bytes(),
+                            value: vec![b'v'; 16],
+                        })
+                        .await
+                        .unwrap();
+                    assert_eq!(Some(value), get(&handler, &key).await);
+                    handler
+                        .apply(Command::Delete {
+                            key: key.as_bytes().to_vec(),
+                        })
+                        .await
+                        .unwrap();
+                    assert_eq!(None, get(&handler, &key).await, "{} revived", key);
+                }
+            }));
+        }
+        let mut readers = Vec::new();
+        for _ in 0..4 {
+            let handler = handler.clone();
+            readers.push(tokio::spawn(async move {
+                let mut last_seen = vec![Vec::new(); KEYS];
+                for _ in 0..ROUNDS {
+                    for (k, last) in last_seen.iter_mut().enumerate() {
+                        if let Some(value) = get(&handler, &format!("key:{}", k)).await {
+                            assert!(value >= *last, "key:{} went back", k);
+                            *last = value;
+                        }
+                    }
+                    let pairs = handler
+                        .scan(b"key:".to_vec(), Some(b"key;".to_vec()), 100, false, None)
+                        .await
+                        .unwrap();
+                    for pair in pairs {
+                        let k: usize = String::from_utf8_lossy(&pair.key[4..]).parse().unwrap();
+                        let value = pair.value.unwrap();
+                        assert!(value >= last_seen[k], "key:{} went back in a scan", k);
+                        last_seen[k] = value;
+                    }
+                }
+            }));
+        }
+        for task in writers.into_iter().chain(readers) {
+            task.await.unwrap();
+        }
+        for k in 0..KEYS {
+            assert_eq!(None, get(&handler, &format!("key:{}", k)).await);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,363 @@
+use super::SkipList;
+use crate::config::MemTableBackend;
+use crate::format::InternalPair;
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// In-memory structure which holds versions of keys for `MemTable`.
+/// Versions are only added: a deletion is added as a version without a value, and versions
+/// nobody sees are discarded when contents are flushed.
+/// `MemTable` adds versions in order of sequence numbers, one at a time, while reads may run
+/// concurrently.
+pub trait Backend: Send + Sync {
+    /// Add a version of `key` at `sequence` whose value is `value`.
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64);
+
+    /// Add a deletion of `key` at `sequence`.
+    fn delete(&self, key: &[u8], sequence: u64);
+
+    /// The newest version of `key` whose sequence number is not greater than `sequence`.
+    fn get(&self, key: &[u8], sequence: u64) -> Option<InternalPair>;
+
+    /// Get pairs whose keys are in `[start, end)` in order of keys, or in descending order if
+    /// `reverse` is `true`, until `limit` pairs which are not deleted are found.
+    /// The newest version of each key whose sequence number is not greater than `sequence` is
+    /// returned, including deletions.
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> Vec<InternalPair>;
+
+    /// All versions in order of keys, from the newest version of each key.
+    /// This is used to flush contents after they become immutable.
+    fn iter(&self) -> Box<dyn Iterator<Item = InternalPair> + '_>;
+
+    /// Approximate number of bytes of memory which all versions occupy, including keys of
+    /// deletions and the bookkeeping of each version.
+    fn memory_usage(&self) -> usize;
+}
+
+impl MemTableBackend {
+    /// Create empty contents of this kind.
+    pub(crate) fn create(self) -> Arc<dyn Backend> {
+        match self {
+            MemTableBackend::BTreeMap => Arc::new(BTreeMapBackend::default()),
+            MemTableBackend::SkipList => Arc::new(SkipList::new()),
+        }
+    }
+}
+
+/// Value of a key with the sequence number of the write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    sequence: u64,
+
+    /// If the key is deleted, `value` is `None`.
+    value: Option<Vec<u8>>,
+}
+
+/// Bytes a key occupies in `BTreeMapBackend` besides the key itself.
+const KEY_OVERHEAD: usize = mem::size_of::<Vec<u8>>() + mem::size_of::<Vec<Entry>>();
+
+/// Bytes a version occupies in `BTreeMapBackend` besides the value.
+const VERSION_OVERHEAD: usize = mem::size_of::<Entry>();
+
+/// `BTreeMap` behind a lock, where versions of each key are sorted from the newest one.
+#[derive(Default)]
+pub struct BTreeMapBackend {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<Entry>>>,
+
+    /// Approximate number of bytes of memory which all versions occupy.
+    memory_usage: AtomicUsize,
+}
+
+impl BTreeMapBackend {
+    fn insert(&self, key: &[u8], value: Option<&[u8]>, sequence: u64) {
+        let mut map = self.map.write().unwrap();
+        let mut usage = VERSION_OVERHEAD + value.map_or(0, <[u8]>::len);
+        if !map.contains_key(key) {
+            usage += KEY_OVERHEAD + key.len();
+        }
+        let versions = map.entry(key.to_vec()).or_default();
+        let position = versions.partition_point(|entry| entry.sequence > sequence);
+        versions.insert(
+            position,
+            Entry {
+                sequence,
+                value: value.map(<[u8]>::to_vec),
+            },
+        );
+        self.memory_usage.fetch_add(usage, Ordering::Relaxed);
+    }
+}
+
+impl Backend for BTreeMapBackend {
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) {
+        self.insert(key, Some(value), sequence);
+    }
+
+    fn delete(&self, key: &[u8], sequence: u64) {
+        self.insert(key, None, sequence);
+    }
+
+    fn get(&self, key: &[u8], sequence: u64) -> Option<InternalPair> {
+        let map = self.map.read().unwrap();
+        map.get(key)
+            .and_then(|versions| visible_version(versions, sequence))
+            .map(|entry| {
+                InternalPair::new(key, entry.value.as_deref()).with_sequence(entry.sequence)
+            })
+    }
+
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> Vec<InternalPair> {
+        let mut pairs = Vec::new();
+        if matches!(end, Some(end) if end <= start) {
+            // `BTreeMap::range()` panics with such a range.
+            return pairs;
+        }
+        let map = self.map.read().unwrap();
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let range = map.range::<[u8], _>((Bound::Included(start), end));
+        let entries: Box<dyn Iterator<Item = _>> = if reverse {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        };
+        let mut found = 0;
+        for (key, versions) in entries {
+            if found == limit {
+                break;
+            }
+            let entry = match visible_version(versions, sequence) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if entry.value.is_some() {
+                found += 1;
+            }
+            pairs
+                .push(InternalPair::new(key, entry.value.as_deref()).with_sequence(entry.sequence));
+        }
+        pairs
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = InternalPair> + '_> {
+        let map = self.map.read().unwrap();
+        let pairs: Vec<_> = map
+            .iter()
+            .flat_map(|(key, versions)| {
+                versions.iter().map(move |entry| {
+                    InternalPair::new(key, entry.value.as_deref()).with_sequence(entry.sequence)
+                })
+            })
+            .collect();
+        Box::new(pairs.into_iter())
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+}
+
+impl Backend for SkipList {
+    fn put(&self, key: &[u8], value: &[u8], sequence: u64) {
+        self.insert(key, Some(value), sequence);
+    }
+
+    fn delete(&self, key: &[u8], sequence: u64) {
+        self.insert(key, None, sequence);
+    }
+
+    fn get(&self, key: &[u8], sequence: u64) -> Option<InternalPair> {
+        SkipList::get(self, key, sequence)
+    }
+
+    fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> Vec<InternalPair> {
+        SkipList::scan(self, start, end, limit, reverse, sequence)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = InternalPair> + '_> {
+        Box::new(SkipList::iter(self))
+    }
+
+    fn memory_usage(&self) -> usize {
+        SkipList::memory_usage(self)
+    }
+}
+
+/// The newest version in `versions` whose sequence number is not greater than `sequence`.
+fn visible_version(versions: &[Entry], sequence: u64) -> Option<&Entry> {
+    versions.iter().find(|entry| entry.sequence <= sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const KINDS: [MemTableBackend; 2] = [MemTableBackend::BTreeMap, MemTableBackend::SkipList];
+
+    #[test]
+    fn versions() {
+        for kind in KINDS {
+            let backend = kind.create();
+            backend.put(b"abc", b"v1", 1);
+            backend.put(b"xyz", b"v2", 2);
+            backend.delete(b"abc", 3);
+            backend.put(b"abc", b"v4", 4);
+            assert_eq!(
+                Some(InternalPair::new(b"abc", Some(b"v4")).with_sequence(4)),
+                backend.get(b"abc", u64::MAX),
+                "{:?}",
+                kind
+            );
+            assert_eq!(
+                Some(InternalPair::new(b"abc", None).with_sequence(3)),
+                backend.get(b"abc", 3)
+            );
+            assert_eq!(None, backend.get(b"abc", 0));
+            assert_eq!(None, backend.get(b"ab", u64::MAX));
+            assert_eq!(
+                vec![
+                    InternalPair::new(b"abc", Some(b"v4")).with_sequence(4),
+                    InternalPair::new(b"abc", None).with_sequence(3),
+                    InternalPair::new(b"abc", Some(b"v1")).with_sequence(1),
+                    InternalPair::new(b"xyz", Some(b"v2")).with_sequence(2),
+                ],
+                backend.iter().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn scan() {
+        for kind in KINDS {
+            let backend = kind.create();
+            for (i, key) in ["abc", "abd", "abe", "abf", "xyz"].iter().enumerate() {
+                backend.put(key.as_bytes(), b"v", i as u64 + 1);
+            }
+            backend.delete(b"abd", 6);
+            assert_eq!(
+                vec![
+                    InternalPair::new(b"abd", None).with_sequence(6),
+                    InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+                ],
+                backend.scan(b"abd", None, 1, false, u64::MAX),
+                "{:?}",
+                kind
+            );
+            assert_eq!(
+                vec![
+                    InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+                    InternalPair::new(b"abd", Some(b"v")).with_sequence(2),
+                    InternalPair::new(b"abc", Some(b"v")).with_sequence(1),
+                ],
+                backend.scan(b"ab", Some(b"abf"), 10, true, 5)
+            );
+            assert!(backend
+                .scan(b"x", Some(b"a"), 10, false, u64::MAX)
+                .is_empty());
+        }
+    }
+
+    /// Random versions of a few keys, where some of them are deletions.
+    fn random_versions(rng: &mut StdRng) -> Vec<InternalPair> {
+        let count = rng.gen_range(0, 200);
+        (1..=count)
+            .map(|sequence| {
+                let key = vec![b'a' + rng.gen_range(0, 16); rng.gen_range(0, 8)];
+                let value = vec![b'v'; rng.gen_range(0, 64)];
+                let value = Some(value.as_slice()).filter(|_| rng.gen_bool(0.8));
+                InternalPair::new(&key, value).with_sequence(sequence)
+            })
+            .collect()
+    }
+
+    fn insert(backend: &dyn Backend, pair: &InternalPair) {
+        match &pair.value {
+            Some(value) => backend.put(&pair.key, value, pair.sequence),
+            None => backend.delete(&pair.key, pair.sequence),
+        }
+    }
+
+    #[test]
+    fn memory_usage_counts_every_version() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let versions = random_versions(&mut rng);
+            for kind in KINDS {
+                let backend = kind.create();
+                let mut data_size = 0;
+                for pair in &versions {
+                    let before = backend.memory_usage();
+                    insert(&*backend, pair);
+                    data_size += pair.size();
+                    // Deletions and empty keys also occupy memory.
+                    assert!(
+                        backend.memory_usage() > before + pair.value.as_ref().map_or(0, Vec::len),
+                        "{:?}",
+                        kind
+                    );
+                }
+                assert!(backend.memory_usage() >= data_size);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_usage_of_btreemap() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let versions = random_versions(&mut rng);
+            let backend = BTreeMapBackend::default();
+            let mut keys = BTreeMap::new();
+            for pair in &versions {
+                insert(&backend, pair);
+                keys.insert(pair.key.clone(), ());
+            }
+            let key_usage: usize = keys.keys().map(|key| KEY_OVERHEAD + key.len()).sum();
+            let version_usage: usize = versions
+                .iter()
+                .map(|pair| VERSION_OVERHEAD + pair.value.as_ref().map_or(0, Vec::len))
+                .sum();
+            assert_eq!(key_usage + version_usage, backend.memory_usage());
+        }
+    }
+
+    #[test]
+    fn memory_usage_of_skiplist() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..100 {
+            let versions = random_versions(&mut rng);
+            let list = SkipList::new();
+            for pair in &versions {
+                insert(&list, pair);
+            }
+            // Every node is in the arena, which may have unused space.
+            assert!(list.memory_usage() <= list.allocated());
+            assert_eq!(versions.len(), list.iter().count());
+        }
+    }
+}
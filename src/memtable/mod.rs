@@ -1,204 +1,1607 @@
+mod backend;
+mod skiplist;
+mod wal;
+
 use crate::command::Command;
-use crate::format::InternalPair;
+use crate::config::{MemTableBackend, SyncMode};
+use crate::error::Error;
+use crate::etag::Precondition;
+use crate::format::{InternalPair, FORMAT_VERSION};
+use crate::snapshot::{self, ReadPin, ReadPins, Snapshots};
+use crate::sstable::sync_directory;
+use crate::stall::WriteController;
 use crate::Message;
+pub use backend::{BTreeMapBackend, Backend};
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::{debug, info, warn};
-use std::collections::BTreeMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::sync::{mpsc, oneshot, RwLock};
+pub use skiplist::SkipList;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex, Semaphore};
+use tokio::time;
+use wal::WriteAheadLog;
+
+/// Active contents are flushed early for the memory budget only when they reach
+/// `1 / MIN_EARLY_FLUSH_FRACTION` of the size limit, so that a tight budget does not turn every
+/// write into a tiny SSTable.
+const MIN_EARLY_FLUSH_FRACTION: usize = 4;
+
+/// Interval to retry a flush which failed.
+const FLUSH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Full contents of `MemTable` which are being flushed.
+/// They are still read until they are persisted in an SSTable.
+struct Immutable {
+    list: Arc<dyn Backend>,
+
+    /// The sequence number of the last write before the contents became immutable.
+    last_sequence: u64,
+}
+
+/// Approximate number of bytes of memory which contents of `MemTable` use.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryUsage {
+    /// Contents which accept writes.
+    pub active: usize,
+
+    /// Contents which are being flushed.
+    pub immutables: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.active + self.immutables
+    }
+}
+
+/// Contents of `MemTable` which a read sees.
+#[derive(Clone)]
+struct View {
+    /// Contents which accept writes.
+    active: Arc<dyn Backend>,
+
+    /// Contents being flushed, from the oldest one.
+    immutables: VecDeque<Arc<Immutable>>,
+}
+
+impl View {
+    /// All contents from the newest one, in order of precedence.
+    fn newest_first(&self) -> impl Iterator<Item = &dyn Backend> {
+        iter::once(&*self.active).chain(
+            self.immutables
+                .iter()
+                .rev()
+                .map(|immutable| &*immutable.list),
+        )
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            active: self.active.memory_usage(),
+            immutables: self
+                .immutables
+                .iter()
+                .map(|immutable| immutable.list.memory_usage())
+                .sum(),
+        }
+    }
+}
 
 /// `MemTable` is an in-memory key-value store.
-/// Imbound data is accumulated in `BTreeMap` this struct holds.
-/// `MemTable` records deletion histories because `SSTable` needs them.
+/// Imbound data is accumulated in a `Backend` this struct holds, which is `BTreeMap` by default.
+/// With `SkipList`, reads and writes do not wait for each other.
+/// Every write inserts a new version, and a deletion is recorded as a version without a value
+/// because `SSTable` needs it. Versions nobody sees are discarded when they are flushed.
+/// Every mutation is recorded in a write-ahead log before it is applied,
+/// and the log is replayed when `MemTable` is created.
+/// When the contents get full, they become immutable and are flushed in the background while
+/// new contents accept writes.
 pub struct MemTable {
-    // Because `MemTable` receives asynchronous request,
-    // a map of key and value is wrapped in `RwLock`.
-    inner: RwLock<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+    /// Current contents. The lock is held only to copy or replace `View`, never during a read.
+    view: Arc<RwLock<View>>,
+
+    /// Kind of `Backend` which holds contents.
+    backend: MemTableBackend,
+
+    /// Number of immutable contents which can still be added.
+    /// If contents get full while there is none, the write waits for a flush.
+    immutable_slots: Arc<Semaphore>,
+
+    /// Gate of writes which is told the number of immutable contents.
+    write_controller: Arc<WriteController>,
 
-    /// Limit of the contents size.
-    /// If actual contents size exceeds this limit after write,
+    /// Limit of memory the active contents use.
+    /// If their memory usage exceeds this limit after write,
     /// Whole contents in a `MemTable` is flushed.
     size_limit: usize,
 
-    /// Number of bytes `MemTable` currently stores.
-    actual_size: AtomicUsize,
+    /// The sequence number assigned to the last write visible to reads.
+    /// It is updated after all pairs of the write are inserted, so a read sees a write entirely
+    /// or not at all. In `SyncMode::Batch`, it is updated only after the write is synchronized
+    /// to a disk, so a read never sees a write which may be lost.
+    last_sequence: AtomicU64,
+
+    /// The sequence number assigned to the last write recorded in the log.
+    /// This is ahead of `last_sequence` while writes wait for synchronization.
+    /// It is updated only while the lock of the log is held.
+    logged_sequence: AtomicU64,
+
+    /// Log of mutations not yet persisted in an SSTable.
+    /// Writes are applied while its lock is held, so the order of sequence numbers is the order
+    /// in which writes are logged and applied.
+    wal: Arc<Mutex<WriteAheadLog>>,
+
+    /// Snapshots taken by clients, which are lost on restart.
+    snapshots: Mutex<Snapshots>,
+
+    /// Sequence numbers which latest reads in progress see.
+    read_pins: ReadPins,
+
+    /// When the log is synchronized to a disk.
+    sync_mode: SyncMode,
 
     /// Receiver to receive command.
-    command_rx: mpsc::Receiver<Message>,
+    command_rx: Mutex<mpsc::Receiver<Message>>,
 
-    /// Sender to send flushed data to `SSTableManager`.
+    /// Sender to send read requests to `SSTableManager`.
     flushing_tx: mpsc::Sender<Message>,
+
+    /// Sender to pass immutable contents to the background flusher.
+    flush_tx: mpsc::UnboundedSender<Command>,
 }
 
 impl MemTable {
-    /// Create a new instance.
-    pub fn new(
+    /// Create a new instance and start a background flusher.
+    /// Contents which had not been flushed before the last shutdown are restored from the
+    /// write-ahead log in `directory`.
+    /// The number of immutable contents is limited by the hard limit of `write_controller`, but
+    /// at least one is allowed.
+    pub async fn new<P: AsRef<Path>>(
+        directory: P,
+        backend: MemTableBackend,
         size_limit: usize,
+        write_controller: Arc<WriteController>,
+        sync_mode: SyncMode,
         command_rx: mpsc::Receiver<Message>,
         flushing_tx: mpsc::Sender<Message>,
-    ) -> Self {
-        Self {
-            inner: RwLock::new(BTreeMap::new()),
+    ) -> io::Result<Self> {
+        let (wal, pairs) = WriteAheadLog::open(&directory, sync_mode.syncs_files()).await?;
+        if sync_mode.syncs_files() {
+            // Make the log file itself durable in case it has just been created.
+            sync_directory(directory).await?;
+        }
+        if !pairs.is_empty() {
+            info!("Restored {} entries from the write-ahead log", pairs.len());
+        }
+        let last_sequence = pairs
+            .iter()
+            .map(|pair| pair.sequence)
+            .fold(wal.last_sequence(), u64::max);
+        // No snapshot survives a restart, so only the newest version of each key is needed.
+        let mut newest = BTreeMap::new();
+        for pair in pairs {
+            newest.insert(pair.key.clone(), pair);
+        }
+        let active = backend.create();
+        for pair in newest.values() {
+            insert(&*active, pair);
+        }
+
+        let view = Arc::new(RwLock::new(View {
+            active,
+            immutables: VecDeque::new(),
+        }));
+        let wal = Arc::new(Mutex::new(wal));
+        let max_immutables = write_controller.hard_limit().immutables;
+        let immutable_slots = Arc::new(Semaphore::new(
+            max_immutables.clamp(1, Semaphore::MAX_PERMITS),
+        ));
+        let (flush_tx, flush_rx) = mpsc::unbounded_channel();
+        tokio::spawn(flush_in_background(
+            flush_rx,
+            flushing_tx.clone(),
+            view.clone(),
+            wal.clone(),
+            immutable_slots.clone(),
+            write_controller.clone(),
+        ));
+
+        Ok(Self {
+            view,
+            backend,
+            immutable_slots,
+            write_controller,
             size_limit,
-            actual_size: AtomicUsize::new(0),
-            command_rx,
+            last_sequence: AtomicU64::new(last_sequence),
+            logged_sequence: AtomicU64::new(last_sequence),
+            wal,
+            snapshots: Mutex::new(Snapshots::default()),
+            read_pins: ReadPins::default(),
+            sync_mode,
+            command_rx: Mutex::new(command_rx),
             flushing_tx,
-        }
+            flush_tx,
+        })
     }
 
     /// Listen to requests and send back results.
-    pub async fn listen(&mut self) {
-        while let Some((command, tx)) = self.command_rx.recv().await {
-            let entry = self.apply(command).await;
-            if let Err(_) = tx.send(entry) {
+    /// Each command is applied on its own task, so commands run in parallel and a command does
+    /// not wait for ones which arrived earlier.
+    /// In `SyncMode::Batch`, commands which have already arrived are applied together and the
+    /// log is synchronized once for all of them before results are sent back.
+    /// In `SyncMode::Interval`, the log is synchronized periodically while listening.
+    pub async fn listen(self: Arc<Self>) {
+        let period = match self.sync_mode {
+            SyncMode::Interval(period) => Some(period),
+            _ => None,
+        };
+        // The interval is not polled unless `period` is specified.
+        let mut ticker = time::interval(period.unwrap_or_else(|| Duration::from_secs(1)));
+        let mut command_rx = self.command_rx.lock().await;
+        let mut processing = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                message = command_rx.recv() => match message {
+                    Some(message) => {
+                        let mut messages = vec![message];
+                        if self.sync_mode == SyncMode::Batch {
+                            while let Ok(message) = command_rx.try_recv() {
+                                messages.push(message);
+                            }
+                        }
+                        processing.push(self.clone().process(messages));
+                    }
+                    None => break,
+                },
+                Some(()) = processing.next(), if !processing.is_empty() => (),
+                _ = ticker.tick(), if period.is_some() => {
+                    if let Err(err) = self.sync_log().await {
+                        warn!("Failed to synchronize the write-ahead log: {}", err);
+                    }
+                }
+            }
+        }
+        while processing.next().await.is_some() {}
+    }
+
+    /// Apply commands in `messages` in parallel, then send back results.
+    /// In `SyncMode::Batch`, the log is synchronized once for all of them before results are
+    /// sent back, unless none of them writes. If the synchronization fails, writes fail and
+    /// stay invisible to reads, while results of reads are sent back as they are.
+    async fn process(self: Arc<Self>, messages: Vec<Message>) {
+        let (commands, senders): (Vec<_>, Vec<_>) = messages.into_iter().unzip();
+        let is_write: Vec<_> = commands.iter().map(Command::is_write).collect();
+        let tasks = commands.into_iter().map(|command| {
+            let table = self.clone();
+            tokio::spawn(async move { table.apply(command).await })
+        });
+        let mut results: Vec<_> = futures::future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|err| Err(Error::Io(err.to_string()))))
+            .collect();
+        if self.sync_mode == SyncMode::Batch && is_write.contains(&true) {
+            debug!("Synchronize the log for {} commands", results.len());
+            if let Err(err) = self.sync_log().await {
+                let err = Error::from(err);
+                for (result, _) in results
+                    .iter_mut()
+                    .zip(is_write)
+                    .filter(|(result, is_write)| *is_write && result.is_ok())
+                {
+                    *result = Err(err.clone());
+                }
+            }
+        }
+        for (result, tx) in results.into_iter().zip(senders) {
+            if tx.send(result).is_err() {
                 warn!("The receiver already dropped");
             };
         }
     }
 
     /// Extract contents of `command` and apply them.
-    pub async fn apply<'a>(&self, command: Command) -> Option<Vec<u8>> {
+    pub async fn apply(&self, command: Command) -> Result<Option<Vec<u8>>, Error> {
         match command {
-            Command::Get { key } => self.get(&key).await,
-            Command::Put { key, value } => self.put(key, value).await,
-            Command::Delete { key } => self.delete(&key).await,
+            Command::Get { key, snapshot } => self.read(&key, snapshot).await,
+            Command::Put { key, value } => Ok(self.put(key, value).await?),
+            Command::Delete { key } => Ok(self.delete(&key).await?),
+            Command::CompareAndSwap { key, expected, new } => {
+                self.compare_and_swap(key, expected, new).await
+            }
+            Command::Conditional {
+                key,
+                value,
+                precondition,
+            } => self.conditional_write(key, value, &precondition).await,
+            Command::Batch { pairs } => {
+                self.write_batch(pairs).await?;
+                Ok(None)
+            }
+            Command::Scan {
+                start,
+                end,
+                limit,
+                reverse,
+                snapshot,
+            } => {
+                let pairs = self
+                    .read_range(&start, end.as_deref(), limit, reverse, snapshot)
+                    .await?;
+                Ok(Some(InternalPair::serialize_flatten(&pairs)))
+            }
+            Command::CreateSnapshot { ttl } => {
+                let id = self.create_snapshot(ttl).await;
+                Ok(Some(id.to_le_bytes().to_vec()))
+            }
+            Command::ReleaseSnapshot { id } => {
+                if self.snapshots.lock().await.release(id, Instant::now()) {
+                    Ok(None)
+                } else {
+                    Err(Error::UnknownSnapshot(id))
+                }
+            }
+            Command::Stats => self.request_sstables(Command::Stats).await,
             Command::Flush { .. } => unreachable!("Flush command is not called in MemTable"),
         }
     }
 
-    /// Get value corresponding to a given key.
+    /// Read the value of `key` as of `snapshot`, or the latest value if it is `None`.
+    /// If `MemTable` has a version of the key, including a deletion, it hides older values in
+    /// SSTables. Newer contents take precedence over immutable ones being flushed.
+    /// SSTables are read at the same sequence number as `MemTable`, so the read sees a single
+    /// consistent view.
+    pub async fn read(&self, key: &[u8], snapshot: Option<u64>) -> Result<Option<Vec<u8>>, Error> {
+        let (view, sequence, _pin) = self.pin(snapshot).await?;
+        self.lookup(&view, key, sequence).await
+    }
+
+    /// Read at most `limit` pairs whose keys are in `[start, end)` from `MemTable` and SSTables
+    /// in order of keys, or in descending order if `reverse` is `true`, as of `snapshot` or the
+    /// latest ones if it is `None`.
+    /// Pairs in `MemTable` take precedence over ones in SSTables and deleted pairs are omitted.
+    /// Like `read()`, both are read in a single consistent view.
+    pub async fn read_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        snapshot: Option<u64>,
+    ) -> Result<Vec<InternalPair>, Error> {
+        let (view, sequence, pin) = self.pin(snapshot).await?;
+        let memtable_pairs = scan_view(&view, start, end, limit, reverse, sequence);
+        // Each deleted pair in `MemTable` may hide a pair in SSTables, so read more pairs from
+        // SSTables to fill `limit`.
+        let deleted = count_deleted(&memtable_pairs);
+        let command = Command::Scan {
+            start: start.to_vec(),
+            end: end.map(<[u8]>::to_vec),
+            limit: limit.saturating_add(deleted),
+            reverse,
+            snapshot: Some(sequence),
+        };
+        let result = self.request_sstables(command).await;
+        drop(pin);
+        let bytes = result?.unwrap_or_default();
+        let sstable_pairs = InternalPair::deserialize_from_bytes(&bytes, FORMAT_VERSION)
+            .await
+            .map_err(|err| Error::Io(err.to_string()))?;
+        Ok(merge_pairs(memtable_pairs, sstable_pairs, limit, reverse))
+    }
+
+    /// Take the contents and the sequence number which a read as of `snapshot` sees, or the
+    /// latest ones if it is `None`.
+    /// A latest read is pinned without a lock until the returned `ReadPin` is dropped, so that
+    /// compaction of contents flushed during the read keeps versions it sees.
+    async fn pin(&self, snapshot: Option<u64>) -> Result<(View, u64, Option<ReadPin<'_>>), Error> {
+        let (sequence, pin) = match snapshot {
+            Some(id) => (self.snapshot_sequence(id).await?, None),
+            None => {
+                let pin = self.read_pins.pin(|| self.last_sequence());
+                (pin.sequence(), Some(pin))
+            }
+        };
+        // Contents made immutable after the sequence number is taken are still in the view.
+        Ok((self.view(), sequence, pin))
+    }
+
+    /// Copy the current contents.
+    fn view(&self) -> View {
+        self.view.read().unwrap().clone()
+    }
+
+    /// Number of bytes of memory the active contents use.
+    fn active_size(&self) -> usize {
+        self.view.read().unwrap().active.memory_usage()
+    }
+
+    /// Approximate number of bytes of memory which contents use, including contents being
+    /// flushed.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.view.read().unwrap().memory_usage()
+    }
+
+    /// Tell the memory budget how much memory contents use.
+    fn report_memory(&self) {
+        self.write_controller
+            .memory_budget()
+            .set_memtable(self.memory_usage());
+    }
+
+    /// Return `true` if the active contents exceed the size limit, or they should be flushed
+    /// early to fit in the memory budget.
+    /// Small contents are not flushed early, and writes go on until they are large enough.
+    fn is_full(&self) -> bool {
+        let active_size = self.active_size();
+        active_size > self.size_limit
+            || (active_size >= self.size_limit / MIN_EARLY_FLUSH_FRACTION
+                && self.write_controller.memory_budget().should_flush())
+    }
+
+    /// Get value corresponding to a given key in `MemTable`.
     pub async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let map = self.inner.read().await;
-        map.get(key).cloned().flatten()
+        self.get_at(key, u64::MAX).await
+    }
+
+    /// Get value of the newest version of a key whose sequence number is not greater than
+    /// `sequence`.
+    pub async fn get_at(&self, key: &[u8], sequence: u64) -> Option<Vec<u8>> {
+        self.view()
+            .newest_first()
+            .find_map(|list| list.get(key, sequence))
+            .and_then(|pair| pair.value)
+    }
+
+    /// Take a snapshot of the current contents which expires after `ttl`, and return its id.
+    pub async fn create_snapshot(&self, ttl: Duration) -> u64 {
+        // `last_sequence` is updated only after a write is applied entirely.
+        self.snapshots
+            .lock()
+            .await
+            .create(self.last_sequence(), ttl, Instant::now())
+    }
+
+    /// The sequence number of the snapshot `id`, or an error if it is not live.
+    async fn snapshot_sequence(&self, id: u64) -> Result<u64, Error> {
+        self.snapshots
+            .lock()
+            .await
+            .sequence(id, Instant::now())
+            .ok_or(Error::UnknownSnapshot(id))
+    }
+
+    /// Sequence numbers of live snapshots and reads in progress in ascending order.
+    pub(crate) async fn snapshot_sequences(&self) -> Vec<u64> {
+        let mut sequences = self.snapshots.lock().await.sequences(Instant::now());
+        sequences.extend(self.read_pins.sequences());
+        sequences.sort_unstable();
+        sequences.dedup();
+        sequences
+    }
+
+    /// The sequence number assigned to the last write.
+    /// This and updates of it are sequentially consistent, which `ReadPins::pin()` relies on.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence.load(Ordering::SeqCst)
+    }
+
+    /// Get pairs whose keys are in `[start, end)` in order of keys, or in descending order if
+    /// `reverse` is `true`, until `limit` pairs which are not deleted are found.
+    /// Deleted pairs are also returned to hide older pairs in SSTables.
+    pub async fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Vec<InternalPair> {
+        self.scan_at(start, end, limit, reverse, u64::MAX).await
+    }
+
+    /// Same as `scan()` but reads the newest versions whose sequence numbers are not greater
+    /// than `sequence`. Keys which have no such version are skipped.
+    pub async fn scan_at(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> Vec<InternalPair> {
+        scan_view(&self.view(), start, end, limit, reverse, sequence)
     }
 
     /// Create a new key-value entry.
-    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
-        let mut map = self.inner.write().await;
-
-        let new_key_len = key.len();
-        let new_value_len = value.len();
-        let prev_value = map.insert(key, Some(value));
-        match prev_value.as_ref() {
-            // There already exists key-value pair.
-            // Add diff between new and old value length.
-            Some(Some(value)) => self
-                .actual_size
-                .fetch_add(new_value_len - value.len(), Ordering::Release),
-            // There exists deleted key-value pair.
-            // Just add value length.
-            Some(None) => self.actual_size.fetch_add(new_value_len, Ordering::Release),
-            // New key-value pair.
-            None => self
-                .actual_size
-                .fetch_add(new_key_len + new_value_len, Ordering::Release),
-        };
-        // Drop lock here to acquire lock in `flush()` which may be called after.
-        drop(map);
+    pub async fn put(&self, key: Vec<u8>, value: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        let mut wal = self.wal.lock().await;
+        let prev_value = self.active_value(&key);
+        self.write(&mut wal, vec![InternalPair::new(&key, Some(&value))])
+            .await?;
+        // Drop lock here to acquire lock in `flush_if_full()`.
+        drop(wal);
 
-        debug!("{}", self.actual_size.load(Ordering::Acquire));
-        if self.actual_size.load(Ordering::Acquire) > self.size_limit {
-            info!("MemTable data flushing has started");
-            self.flush().await;
-            self.actual_size.store(0, Ordering::Release);
-        }
-        prev_value.flatten()
+        self.flush_if_full().await;
+        Ok(prev_value)
     }
 
     /// Mark value corresponding to a key as deleted.
     /// Return `true` if there was an entry to delete.
-    pub async fn delete(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let mut map = self.inner.write().await;
-
-        // Check entry for the key to avoid mark a key which is not registered as `Deleted`.
-        let prev_value = map.insert(key.to_vec(), None).flatten();
-        if let Some(prev_value) = prev_value.as_ref() {
-            self.actual_size
-                .fetch_sub(prev_value.len(), Ordering::Acquire);
-        }
-        debug!("{}", self.actual_size.load(Ordering::Acquire));
-        prev_value
-    }
-
-    /// Read whole data in `MemTable` and send to `SSTableManager`.
-    async fn flush(&self) {
-        // Acquire write lock to prevent other tasks update `MemTable` contents.
-        // If the contents is updated while flushing, flushed data(passed to `SSTable`)
-        // and desired one will be different.
-        let map = self.inner.write().await;
-        let pairs = map
-            .iter()
-            .map(|(key, entry)| match entry {
-                Some(value) => InternalPair::new(key, Some(value)),
-                None => InternalPair::new(key, None),
-            })
-            .collect();
+    pub async fn delete(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut wal = self.wal.lock().await;
+        let prev_value = self.active_value(key);
+        self.write(&mut wal, vec![InternalPair::new(key, None)])
+            .await?;
+        drop(wal);
+
+        self.flush_if_full().await;
+        Ok(prev_value)
+    }
+
+    /// Put `new` if the current value of `key` is `expected`, and return the previous value.
+    /// If `key` is not in `MemTable`, its current value is read from SSTables while the lock of
+    /// the log is held, so that no write can interleave.
+    pub async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut wal = self.wal.lock().await;
+        let current = self.current_value(&key).await?;
+        if current != expected {
+            return Err(match expected {
+                Some(_) => Error::ValueMismatch,
+                None => Error::AlreadyExists,
+            });
+        }
+        self.write(&mut wal, vec![InternalPair::new(&key, Some(&new))])
+            .await?;
+        drop(wal);
+
+        self.flush_if_full().await;
+        Ok(current)
+    }
 
+    /// Put `value`, or delete if it is `None`, if the current value of `key` satisfies
+    /// `precondition`, and return the previous value.
+    /// This is atomic in the same way as `compare_and_swap()`.
+    pub async fn conditional_write(
+        &self,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+        precondition: &Precondition,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut wal = self.wal.lock().await;
+        let current = self.current_value(&key).await?;
+        if !precondition.holds(current.as_deref()) {
+            return Err(Error::PreconditionFailed);
+        }
+        self.write(&mut wal, vec![InternalPair::new(&key, value.as_deref())])
+            .await?;
+        drop(wal);
+
+        self.flush_if_full().await;
+        Ok(current)
+    }
+
+    /// The newest value of `key` in the active contents.
+    fn active_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.view
+            .read()
+            .unwrap()
+            .active
+            .get(key, u64::MAX)
+            .and_then(|pair| pair.value)
+    }
+
+    /// Read the current value of `key` from `MemTable`, or from SSTables if it does not have
+    /// the key.
+    /// The caller holds the lock of the log so that the value is not changed until it writes.
+    /// Writes not yet synchronized are also seen, because they are ordered before this write.
+    async fn current_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let sequence = self.logged_sequence.load(Ordering::Acquire);
+        self.lookup(&self.view(), key, sequence).await
+    }
+
+    /// Read the newest value of `key` whose sequence number is not greater than `sequence` from
+    /// contents in `view`, or from SSTables if none of them has such a version.
+    async fn lookup(
+        &self,
+        view: &View,
+        key: &[u8],
+        sequence: u64,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match view.newest_first().find_map(|list| list.get(key, sequence)) {
+            // A deleted entry hides older values in SSTables.
+            Some(pair) => Ok(pair.value),
+            None => {
+                let command = Command::Get {
+                    key: key.to_vec(),
+                    snapshot: Some(sequence),
+                };
+                self.request_sstables(command).await
+            }
+        }
+    }
+
+    /// Send a read command to `SSTableManager` and wait for the result.
+    async fn request_sstables(&self, command: Command) -> Result<Option<Vec<u8>>, Error> {
         let (tx, rx) = oneshot::channel();
-        if self
-            .flushing_tx
-            .send((
-                Command::Flush {
-                    pairs,
-                    size: self.actual_size.load(Ordering::SeqCst),
-                },
-                tx,
-            ))
+        let not_running = || Error::Io("SSTableManager is not running".to_string());
+        self.flushing_tx
+            .send((command, tx))
             .await
-            .is_err()
-        {
-            warn!("The receiver dropped");
+            .map_err(|_| not_running())?;
+        rx.await.map_err(|_| not_running())?
+    }
+
+    /// Apply all `pairs` under a single lock of the log, recording them in a single log record.
+    /// Readers never observe a part of the batch, and either all or none of the batch is
+    /// restored after a crash.
+    pub async fn write_batch(&self, pairs: Vec<InternalPair>) -> io::Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
         }
-        // Wait for finishing flush
-        if rx.await.is_err() {
-            warn!("The sender dropped");
+        let mut wal = self.wal.lock().await;
+        self.write(&mut wal, pairs).await?;
+        drop(wal);
+
+        self.flush_if_full().await;
+        Ok(())
+    }
+
+    /// Assign sequence numbers to `pairs`, record them in `wal` and insert them into the active
+    /// contents. They become visible to reads all together, after the log is synchronized in
+    /// `SyncMode::Batch`.
+    /// The caller holds the lock of `wal`.
+    async fn write(&self, wal: &mut WriteAheadLog, pairs: Vec<InternalPair>) -> io::Result<()> {
+        let mut sequence = self.logged_sequence.load(Ordering::Acquire);
+        let pairs: Vec<_> = pairs
+            .into_iter()
+            .map(|pair| {
+                sequence += 1;
+                pair.with_sequence(sequence)
+            })
+            .collect();
+        // Multiple pairs are recorded as a batch so that they are replayed all together.
+        match pairs.as_slice() {
+            [pair] => wal.append(pair).await?,
+            pairs => wal.append_batch(pairs).await?,
+        }
+        if self.sync_mode == SyncMode::Always {
+            wal.sync().await?;
+        }
+        let active = self.view.read().unwrap().active.clone();
+        for pair in &pairs {
+            insert(&*active, pair);
+        }
+        debug!("{}", active.memory_usage());
+        self.logged_sequence.store(sequence, Ordering::Release);
+        if self.sync_mode != SyncMode::Batch {
+            self.last_sequence.store(sequence, Ordering::SeqCst);
+        }
+        self.report_memory();
+        Ok(())
+    }
+
+    /// Make contents immutable and flush them in the background if their size exceeds the
+    /// limit or they do not fit in the memory budget. If there are already too many immutable
+    /// contents, wait for the oldest ones to be flushed first.
+    async fn flush_if_full(&self) {
+        if !self.is_full() {
+            return;
+        }
+        let permit = match self.immutable_slots.acquire().await {
+            Ok(permit) => permit,
+            Err(_) => return,
+        };
+        let mut wal = self.wal.lock().await;
+        // Another write may have made the contents immutable meanwhile.
+        if !self.is_full() {
+            return;
+        }
+        // Versions are discarded from flushed contents based on reads of visible writes, so
+        // writes waiting for synchronization are made visible first.
+        if self.sync_mode == SyncMode::Batch {
+            if let Err(err) = wal.sync().await {
+                warn!("Failed to synchronize the write-ahead log: {}", err);
+                return;
+            }
+            self.last_sequence.fetch_max(
+                self.logged_sequence.load(Ordering::Acquire),
+                Ordering::SeqCst,
+            );
+        }
+        // The slot is given back when the contents are flushed.
+        permit.forget();
+        info!("MemTable data flushing has started");
+        self.make_immutable(&wal).await;
+    }
+
+    /// Synchronize the write-ahead log to a disk unless synchronization is disabled, and make
+    /// synchronized writes visible to reads.
+    pub async fn sync_log(&self) -> io::Result<()> {
+        if !self.sync_mode.syncs_files() {
+            return Ok(());
+        }
+        let mut wal = self.wal.lock().await;
+        let sequence = self.logged_sequence.load(Ordering::Acquire);
+        wal.sync().await?;
+        self.last_sequence.fetch_max(sequence, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Move the active contents to immutable ones and pass them to the background flusher.
+    /// The caller holds the lock of the log so that no write is applied meanwhile.
+    async fn make_immutable(&self, _wal: &WriteAheadLog) {
+        // Versions seen by snapshots released after they were written are discarded here.
+        // `last_sequence` does not change while the log is locked, so a read which pins after
+        // this sees it and needs only the newest versions.
+        let snapshots = self.snapshot_sequences().await;
+        let active = {
+            let mut view = self.view.write().unwrap();
+            let active = std::mem::replace(&mut view.active, self.backend.create());
+            view.immutables.push_back(Arc::new(Immutable {
+                list: active.clone(),
+                last_sequence: self.last_sequence(),
+            }));
+            self.write_controller.set_immutables(view.immutables.len());
+            active
+        };
+        self.report_memory();
+        let mut pairs = Vec::new();
+        let mut newer: Option<(Vec<u8>, u64)> = None;
+        for pair in active.iter() {
+            let newer_sequence = newer
+                .as_ref()
+                .filter(|(key, _)| *key == pair.key)
+                .map(|(_, sequence)| *sequence);
+            newer = Some((pair.key.clone(), pair.sequence));
+            if snapshot::is_visible_version(pair.sequence, newer_sequence, &snapshots) {
+                pairs.push(pair);
+            }
+        }
+        let command = Command::Flush {
+            size: pairs.iter().map(InternalPair::size).sum(),
+            pairs,
+            snapshots,
+        };
+        if self.flush_tx.send(command).is_err() {
+            warn!("The background flusher is not running");
+        }
+    }
+}
+
+/// Send immutable contents received from `flush_rx` to `SSTableManager` one by one, and drop
+/// them from `view` each time they are persisted.
+/// A failed flush is retried because newer contents must not be persisted before it.
+async fn flush_in_background(
+    mut flush_rx: mpsc::UnboundedReceiver<Command>,
+    flushing_tx: mpsc::Sender<Message>,
+    view: Arc<RwLock<View>>,
+    wal: Arc<Mutex<WriteAheadLog>>,
+    immutable_slots: Arc<Semaphore>,
+    write_controller: Arc<WriteController>,
+) {
+    while let Some(command) = flush_rx.recv().await {
+        loop {
+            let (tx, rx) = oneshot::channel();
+            if flushing_tx.send((command.clone(), tx)).await.is_err() {
+                warn!("The receiver dropped");
+                return;
+            }
+            match rx.await {
+                Ok(Ok(_)) => break,
+                Ok(Err(err)) => {
+                    warn!("Failed to flush MemTable: {}", err);
+                    time::sleep(FLUSH_RETRY_INTERVAL).await;
+                }
+                Err(_) => {
+                    warn!("The sender dropped");
+                    return;
+                }
+            }
+        }
+        if let Err(err) = discard_flushed(&view, &wal, &write_controller).await {
+            warn!("Failed to truncate the write-ahead log: {}", err);
+        }
+        immutable_slots.add_permits(1);
+    }
+}
+
+/// Drop the oldest immutable contents in `view`, which have been persisted in an SSTable, and
+/// discard their records from `wal`.
+async fn discard_flushed(
+    view: &RwLock<View>,
+    wal: &Mutex<WriteAheadLog>,
+    write_controller: &WriteController,
+) -> io::Result<()> {
+    // No write is applied while the lock of the log is held.
+    let mut wal = wal.lock().await;
+    let (flushed, remaining) = {
+        let mut view = view.write().unwrap();
+        match view.immutables.pop_front() {
+            Some(flushed) => (flushed, view.clone()),
+            None => return Ok(()),
+        }
+    };
+    // Memory is updated first because writes waiting for it are woken by `set_immutables()`.
+    write_controller
+        .memory_budget()
+        .set_memtable(remaining.memory_usage());
+    write_controller.set_immutables(remaining.immutables.len());
+    // No snapshot survives a restart, so only the newest version of each key is kept.
+    let mut pairs: Vec<_> = remaining.newest_first().flat_map(newest_versions).collect();
+    pairs.sort_by_key(|pair| pair.sequence);
+    wal.truncate(flushed.last_sequence, &pairs).await
+}
+
+/// Add `pair` to `list` as a new version.
+fn insert(list: &dyn Backend, pair: &InternalPair) {
+    match &pair.value {
+        Some(value) => list.put(&pair.key, value, pair.sequence),
+        None => list.delete(&pair.key, pair.sequence),
+    }
+}
+
+/// The newest version of each key in `list`.
+fn newest_versions(list: &dyn Backend) -> Vec<InternalPair> {
+    let mut pairs: Vec<InternalPair> = Vec::new();
+    for pair in list.iter() {
+        if pairs.last().is_none_or(|last| last.key != pair.key) {
+            pairs.push(pair);
         }
+    }
+    pairs
+}
 
-        let mut map = map;
-        map.clear();
+/// Get pairs in contents of `view` whose keys are in `[start, end)` in order of keys, or in
+/// descending order if `reverse` is `true`, until `limit` pairs which are not deleted are found.
+/// The newest version of each key whose sequence number is not greater than `sequence` is
+/// returned, including deletions. Newer contents take precedence over older ones.
+fn scan_view(
+    view: &View,
+    start: &[u8],
+    end: Option<&[u8]>,
+    limit: usize,
+    reverse: bool,
+    sequence: u64,
+) -> Vec<InternalPair> {
+    let mut lists = view.newest_first();
+    let mut pairs = match lists.next() {
+        Some(list) => list.scan(start, end, limit, reverse, sequence),
+        None => return Vec::new(),
+    };
+    for list in lists {
+        // Each deleted pair may hide a pair in older contents, so read more pairs from them.
+        let older = list.scan(
+            start,
+            end,
+            limit.saturating_add(count_deleted(&pairs)),
+            reverse,
+            sequence,
+        );
+        pairs = merge_versions(pairs, older, limit, reverse);
     }
+    pairs
+}
+
+/// Number of deleted pairs in `pairs`.
+fn count_deleted(pairs: &[InternalPair]) -> usize {
+    pairs.iter().filter(|pair| pair.value.is_none()).count()
+}
+
+/// Merge pairs sorted by keys, or sorted in descending order if `reverse` is `true`,
+/// preferring `newer` if both have the same key, and return at most `limit` pairs which are not
+/// deleted.
+fn merge_pairs(
+    newer: Vec<InternalPair>,
+    older: Vec<InternalPair>,
+    limit: usize,
+    reverse: bool,
+) -> Vec<InternalPair> {
+    let mut pairs = merge_versions(newer, older, limit, reverse);
+    pairs.retain(|pair| pair.value.is_some());
+    pairs
+}
+
+/// Same as `merge_pairs()` but keeps deleted pairs, which do not count toward `limit`.
+fn merge_versions(
+    newer: Vec<InternalPair>,
+    older: Vec<InternalPair>,
+    limit: usize,
+    reverse: bool,
+) -> Vec<InternalPair> {
+    let mut newer = newer.into_iter().peekable();
+    let mut older = older.into_iter().peekable();
+    let mut pairs = Vec::new();
+    let mut found = 0;
+    while found < limit {
+        let pair = match (newer.peek(), older.peek()) {
+            (Some(new), Some(old)) => {
+                // The pair which comes first is taken.
+                let ordering = if reverse {
+                    old.key.cmp(&new.key)
+                } else {
+                    new.key.cmp(&old.key)
+                };
+                match ordering {
+                    cmp::Ordering::Less => newer.next(),
+                    cmp::Ordering::Equal => {
+                        older.next();
+                        newer.next()
+                    }
+                    cmp::Ordering::Greater => older.next(),
+                }
+            }
+            (Some(_), None) => newer.next(),
+            (None, Some(_)) => older.next(),
+            (None, None) => break,
+        };
+        if let Some(pair) = pair {
+            if pair.value.is_some() {
+                found += 1;
+            }
+            pairs.push(pair);
+        }
+    }
+    pairs
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::budget::MemoryBudget;
+    use crate::sstable::tests::prepare_directory;
+    use crate::stall::tests::limit_immutables;
 
-    const MEMTABLE_SIZE: usize = 128;
+    const MEMTABLE_SIZE: usize = 1024;
 
-    #[tokio::test]
-    async fn put_and_get() {
+    async fn prepare_memtable(directory: &str) -> MemTable {
+        prepare_directory(directory);
+        prepare_memtable_in(directory).await
+    }
+
+    /// Open `MemTable` in an existing directory.
+    async fn prepare_memtable_in(directory: &str) -> MemTable {
         let (_, rx) = mpsc::channel(1);
         let (tx, _) = mpsc::channel(1);
-        let table = MemTable::new(MEMTABLE_SIZE, rx, tx);
-        assert_eq!(None, table.put(b"abc".to_vec(), b"def".to_vec()).await);
-        assert_eq!(None, table.put(b"xyz".to_vec(), b"xxx".to_vec()).await);
+        MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Wait until all immutable contents are flushed and the log is truncated.
+    async fn wait_for_flushes(table: &MemTable) {
+        while !table.view().immutables.is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        // The log is truncated while its lock is held.
+        drop(table.wal.lock().await);
+    }
+
+    #[tokio::test]
+    async fn put_and_get() -> io::Result<()> {
+        let table = prepare_memtable("test_memtable_put_and_get").await;
+        assert_eq!(None, table.put(b"abc".to_vec(), b"def".to_vec()).await?);
+        assert_eq!(None, table.put(b"xyz".to_vec(), b"xxx".to_vec()).await?);
         assert_eq!(
             Some(b"xxx".to_vec()),
-            table.put(b"xyz".to_vec(), b"qwerty".to_vec()).await
+            table.put(b"xyz".to_vec(), b"qwerty".to_vec()).await?
         );
         assert_eq!(Some(b"def".to_vec()), table.get(b"abc").await);
         assert_eq!(Some(b"qwerty".to_vec()), table.get(b"xyz").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete() -> io::Result<()> {
+        let table = prepare_memtable("test_memtable_delete").await;
+        table.put(b"abc".to_vec(), b"def".to_vec()).await?;
+        table.put(b"xyz".to_vec(), b"xxx".to_vec()).await?;
+        assert_eq!(Some(b"def".to_vec()), table.delete(b"abc").await?);
+        assert_eq!(None, table.delete(b"abcdef").await?);
+        assert_eq!(None, table.get(b"abc").await);
+        assert_eq!(None, table.get(b"111").await);
+        assert_eq!(Some(b"xxx".to_vec()), table.get(b"xyz").await);
+        Ok(())
     }
 
     #[tokio::test]
-    async fn delete() {
+    async fn delete_non_existing() -> io::Result<()> {
+        let table = prepare_memtable("test_memtable_delete_non_existing").await;
+        assert_eq!(None, table.delete(b"abc").await?);
+        assert_eq!(None, table.get(b"abc").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_from_log() -> io::Result<()> {
+        let directory = "test_memtable_restore_from_log";
+        let table = prepare_memtable(directory).await;
+        table.put(b"abc".to_vec(), b"def".to_vec()).await?;
+        table.put(b"xyz".to_vec(), b"xxx".to_vec()).await?;
+        table.delete(b"abc").await?;
+        drop(table);
+
         let (_, rx) = mpsc::channel(1);
         let (tx, _) = mpsc::channel(1);
-        let table = MemTable::new(MEMTABLE_SIZE, rx, tx);
-        table.put(b"abc".to_vec(), b"def".to_vec()).await;
-        table.put(b"xyz".to_vec(), b"xxx".to_vec()).await;
-        assert_eq!(Some(b"def".to_vec()), table.delete(b"abc").await);
-        assert_eq!(None, table.delete(b"abcdef").await);
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
         assert_eq!(None, table.get(b"abc").await);
-        assert_eq!(None, table.get(b"111").await);
         assert_eq!(Some(b"xxx".to_vec()), table.get(b"xyz").await);
+        // Only the newest versions are restored.
+        let expected = BTreeMapBackend::default();
+        expected.delete(b"abc", 3);
+        expected.put(b"xyz", b"xxx", 2);
+        assert_eq!(expected.memory_usage(), table.active_size());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_sequence_after_flush() -> io::Result<()> {
+        let directory = "test_memtable_keep_sequence_after_flush";
+        prepare_directory(directory);
+        let (_, rx) = mpsc::channel(1);
+        let (tx, mut sstable_rx) = mpsc::channel::<Message>(1);
+        let flushed = tokio::spawn(async move {
+            let mut flushed = Vec::new();
+            while let Some((command, tx)) = sstable_rx.recv().await {
+                if let Command::Flush { pairs, .. } = command {
+                    flushed.extend(pairs);
+                }
+                tx.send(Ok(None)).unwrap();
+            }
+            flushed
+        });
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
+        table.put(b"abc".to_vec(), b"def".to_vec()).await?;
+        table.put(b"xyz".to_vec(), vec![0; MEMTABLE_SIZE]).await?;
+        assert_eq!(2, table.last_sequence());
+        wait_for_flushes(&table).await;
+        drop(table);
+        let flushed = flushed.await.unwrap();
+        assert_eq!(
+            vec![1, 2],
+            flushed.iter().map(|pair| pair.sequence).collect::<Vec<_>>()
+        );
+
+        let table = prepare_memtable_in(directory).await;
+        assert_eq!(2, table.last_sequence());
+        table.delete(b"abc").await?;
+        assert_eq!(
+            vec![InternalPair::new(b"abc", None).with_sequence(3)],
+            table.scan(b"", None, 10, false).await
+        );
+        Ok(())
     }
 
     #[tokio::test]
-    async fn delete_non_existing() {
+    async fn discard_versions_on_flush() -> io::Result<()> {
+        prepare_directory("test_memtable_discard_versions_on_flush");
+        let (_, rx) = mpsc::channel(1);
+        let (tx, mut sstable_rx) = mpsc::channel::<Message>(1);
+        let (flushed_tx, mut flushed_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some((command, tx)) = sstable_rx.recv().await {
+                if let Command::Flush { pairs, .. } = command {
+                    flushed_tx.send(pairs).await.unwrap();
+                }
+                tx.send(Ok(None)).unwrap();
+            }
+        });
+        let table = MemTable::new(
+            "test_memtable_discard_versions_on_flush",
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
+        table.put(b"abc".to_vec(), b"v1".to_vec()).await?;
+        table.create_snapshot(Duration::from_secs(60)).await;
+        table.put(b"abc".to_vec(), b"v2".to_vec()).await?;
+        table.put(b"abc".to_vec(), b"v3".to_vec()).await?;
+        table.put(b"xyz".to_vec(), vec![0; MEMTABLE_SIZE]).await?;
+        // "v2" is seen by no snapshot.
+        assert_eq!(
+            vec![3, 1, 4],
+            flushed_rx
+                .recv()
+                .await
+                .unwrap()
+                .iter()
+                .map(|pair| pair.sequence)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_in_background() -> io::Result<()> {
+        let directory = "test_memtable_flush_in_background";
+        prepare_directory(directory);
+        let (_, rx) = mpsc::channel(1);
+        let (tx, mut sstable_rx) = mpsc::channel::<Message>(1);
+        let (release_tx, mut release_rx) = mpsc::channel::<()>(1);
+        // Each flush is held until it is released, and SSTables have no data.
+        tokio::spawn(async move {
+            let mut held = VecDeque::new();
+            let mut released = 0;
+            loop {
+                tokio::select! {
+                    message = sstable_rx.recv() => match message {
+                        Some((Command::Flush { .. }, tx)) => held.push_back(tx),
+                        Some((_, tx)) => tx.send(Ok(None)).unwrap(),
+                        None => break,
+                    },
+                    Some(()) = release_rx.recv() => released += 1,
+                }
+                while released > 0 && !held.is_empty() {
+                    held.pop_front().unwrap().send(Ok(None)).unwrap();
+                    released -= 1;
+                }
+            }
+        });
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(1),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
+        table.put(b"abc".to_vec(), vec![0; MEMTABLE_SIZE]).await?;
+        assert_eq!(1, table.view().immutables.len());
+
+        // Writes and reads proceed while the full contents are being flushed.
+        table.put(b"abc".to_vec(), b"new".to_vec()).await?;
+        table.put(b"xyz".to_vec(), b"xxx".to_vec()).await?;
+        assert_eq!(Some(vec![0; MEMTABLE_SIZE]), table.get_at(b"abc", 1).await);
+        assert_eq!(Ok(Some(b"new".to_vec())), table.read(b"abc", None).await);
+        assert_eq!(
+            Ok(vec![
+                InternalPair::new(b"abc", Some(b"new")).with_sequence(2),
+                InternalPair::new(b"xyz", Some(b"xxx")).with_sequence(3),
+            ]),
+            table.read_range(b"", None, 10, false, None).await
+        );
+
+        // Contents get full again, but the write waits until the first flush completes.
+        {
+            let full = table.put(b"pqr".to_vec(), vec![1; MEMTABLE_SIZE]);
+            tokio::pin!(full);
+            assert!(time::timeout(Duration::from_millis(100), &mut full)
+                .await
+                .is_err());
+            release_tx.send(()).await.unwrap();
+            full.await?;
+        }
+        assert_eq!(1, table.view().immutables.len());
+        assert_eq!(Some(b"new".to_vec()), table.get(b"abc").await);
+        assert_eq!(None, table.get_at(b"abc", 1).await);
+
+        release_tx.send(()).await.unwrap();
+        wait_for_flushes(&table).await;
+        assert!(table.view().immutables.is_empty());
+        drop(table);
+        // Flushed contents are not restored.
+        let table = prepare_memtable_in(directory).await;
+        assert_eq!(4, table.last_sequence());
+        assert_eq!(None, table.get(b"abc").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_by_memory_usage() -> io::Result<()> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for backend in [MemTableBackend::BTreeMap, MemTableBackend::SkipList] {
+            let directory = format!("test_memtable_flush_by_memory_usage_{:?}", backend);
+            prepare_directory(&directory);
+            let (_, rx) = mpsc::channel(1);
+            // Flushes never finish, so every write stays in memory.
+            let (tx, _) = mpsc::channel(1);
+            let table = MemTable::new(
+                &directory,
+                backend,
+                MEMTABLE_SIZE,
+                limit_immutables(100),
+                SyncMode::None,
+                rx,
+                tx,
+            )
+            .await?;
+            let mut data_size = 0;
+            for _ in 0..200 {
+                let key = vec![b'a' + rng.gen_range(0, 16); rng.gen_range(1, 8)];
+                let before = table.memory_usage();
+                if rng.gen_bool(0.8) {
+                    let value = vec![b'v'; rng.gen_range(0, 256)];
+                    data_size += key.len() + value.len();
+                    table.put(key, value).await?;
+                } else {
+                    data_size += key.len();
+                    table.delete(&key).await?;
+                }
+                let usage = table.memory_usage();
+                assert!(usage.total() > before.total(), "{:?}", backend);
+                assert!(usage.total() >= data_size);
+                // Full contents become immutable right after the write.
+                assert!(usage.active <= MEMTABLE_SIZE);
+            }
+            assert!(!table.view().immutables.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flush_early_at_minimum_size() -> io::Result<()> {
+        let directory = "test_memtable_flush_early_at_minimum_size";
+        prepare_directory(directory);
         let (_, rx) = mpsc::channel(1);
         let (tx, _) = mpsc::channel(1);
-        let table = MemTable::new(MEMTABLE_SIZE, rx, tx);
-        assert_eq!(None, table.delete(b"abc").await);
+        let write_controller = Arc::new(
+            WriteController::default().with_memory_budget(MemoryBudget::new(MEMTABLE_SIZE)),
+        );
+        // Indexes leave little room for `MemTable`.
+        write_controller
+            .memory_budget()
+            .set_indexes(MEMTABLE_SIZE - 64);
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            write_controller.clone(),
+            SyncMode::None,
+            rx,
+            tx,
+        )
+        .await?;
+        // The contents do not fit in the room, but they are too small to flush.
+        table.put(b"a".to_vec(), vec![b'v'; 100]).await?;
+        assert!(write_controller.memory_budget().should_flush());
+        assert!(table.view().immutables.is_empty());
+
+        table
+            .put(b"b".to_vec(), vec![b'v'; MEMTABLE_SIZE / 4])
+            .await?;
+        assert_eq!(1, table.view().immutables.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn write_batch() -> io::Result<()> {
+        let directory = "test_memtable_write_batch";
+        let table = prepare_memtable(directory).await;
+        table.put(b"abc".to_vec(), b"def".to_vec()).await?;
+        table
+            .write_batch(vec![
+                InternalPair::new(b"abc", None),
+                InternalPair::new(b"xyz", Some(b"xxx")),
+                InternalPair::new(b"xyz", Some(b"yyy")),
+            ])
+            .await?;
         assert_eq!(None, table.get(b"abc").await);
+        assert_eq!(Some(b"yyy".to_vec()), table.get(b"xyz").await);
+        drop(table);
+
+        let (_, rx) = mpsc::channel(1);
+        let (tx, _) = mpsc::channel(1);
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
+        assert_eq!(None, table.get(b"abc").await);
+        assert_eq!(Some(b"yyy".to_vec()), table.get(b"xyz").await);
+        let expected = BTreeMapBackend::default();
+        expected.delete(b"abc", 2);
+        expected.put(b"xyz", b"yyy", 4);
+        assert_eq!(expected.memory_usage(), table.active_size());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_swap() -> io::Result<()> {
+        prepare_directory("test_memtable_compare_and_swap");
+        let (_, rx) = mpsc::channel(1);
+        let (tx, mut sstable_rx) = mpsc::channel::<Message>(1);
+        // Pretend that "old" is in SSTables.
+        tokio::spawn(async move {
+            while let Some((command, tx)) = sstable_rx.recv().await {
+                let value = match command {
+                    Command::Get { key, .. } if key == b"old" => Some(b"sstable".to_vec()),
+                    _ => None,
+                };
+                tx.send(Ok(value)).unwrap();
+            }
+        });
+        let table = MemTable::new(
+            "test_memtable_compare_and_swap",
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Always,
+            rx,
+            tx,
+        )
+        .await?;
+
+        let cas = |key: &[u8], expected: Option<&[u8]>, new: &[u8]| {
+            table.compare_and_swap(key.to_vec(), expected.map(<[u8]>::to_vec), new.to_vec())
+        };
+        assert_eq!(Ok(None), cas(b"abc", None, b"1").await);
+        assert_eq!(Err(Error::AlreadyExists), cas(b"abc", None, b"2").await);
+        assert_eq!(
+            Err(Error::ValueMismatch),
+            cas(b"abc", Some(b"2"), b"3").await
+        );
+        assert_eq!(Ok(Some(b"1".to_vec())), cas(b"abc", Some(b"1"), b"2").await);
+        assert_eq!(Some(b"2".to_vec()), table.get(b"abc").await);
+
+        assert_eq!(Err(Error::AlreadyExists), cas(b"old", None, b"1").await);
+        assert_eq!(
+            Ok(Some(b"sstable".to_vec())),
+            cas(b"old", Some(b"sstable"), b"new").await
+        );
+        // A deletion in `MemTable` hides the value in SSTables.
+        table.delete(b"old").await?;
+        assert_eq!(
+            Err(Error::ValueMismatch),
+            cas(b"old", Some(b"sstable"), b"1").await
+        );
+        assert_eq!(Ok(None), cas(b"old", None, b"1").await);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan() -> io::Result<()> {
+        let table = prepare_memtable("test_memtable_scan").await;
+        for key in ["abc", "abd", "abe", "abf", "xyz"] {
+            table.put(key.as_bytes().to_vec(), b"v".to_vec()).await?;
+        }
+        table.delete(b"abd").await?;
+        assert_eq!(6, table.last_sequence());
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc", Some(b"v")).with_sequence(1),
+                InternalPair::new(b"abd", None).with_sequence(6),
+                InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+            ],
+            table.scan(b"ab", Some(b"abf"), 10, false).await
+        );
+        // A deleted pair is not counted in `limit`.
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abd", None).with_sequence(6),
+                InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+            ],
+            table.scan(b"abd", None, 1, false).await
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+                InternalPair::new(b"abd", None).with_sequence(6),
+                InternalPair::new(b"abc", Some(b"v")).with_sequence(1),
+            ],
+            table.scan(b"ab", Some(b"abf"), 2, true).await
+        );
+        assert_eq!(
+            Vec::<InternalPair>::new(),
+            table.scan(b"x", Some(b"a"), 10, false).await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_snapshot() -> io::Result<()> {
+        let table = prepare_memtable("test_memtable_read_snapshot").await;
+        table.put(b"abc".to_vec(), b"v1".to_vec()).await?;
+        let id = table.create_snapshot(Duration::from_secs(60)).await;
+        let sequence = table.snapshot_sequence(id).await.unwrap();
+        assert_eq!(1, sequence);
+        table.put(b"abc".to_vec(), b"v2".to_vec()).await?;
+        table.put(b"abc".to_vec(), b"v3".to_vec()).await?;
+        table.put(b"xyz".to_vec(), b"v4".to_vec()).await?;
+        assert_eq!(Some(b"v1".to_vec()), table.get_at(b"abc", sequence).await);
+        assert_eq!(Some(b"v3".to_vec()), table.get(b"abc").await);
+        assert_eq!(None, table.get_at(b"xyz", sequence).await);
+        assert_eq!(
+            vec![InternalPair::new(b"abc", Some(b"v1")).with_sequence(1)],
+            table.scan_at(b"", None, 10, false, sequence).await
+        );
+        // Versions are kept until they are flushed.
+        assert_eq!(4, table.view().active.iter().count());
+        let expected = BTreeMapBackend::default();
+        for (i, value) in [b"v1", b"v2", b"v3"].iter().enumerate() {
+            expected.put(b"abc", *value, i as u64 + 1);
+        }
+        expected.put(b"xyz", b"v4", 4);
+        assert_eq!(
+            MemoryUsage {
+                active: expected.memory_usage(),
+                immutables: 0,
+            },
+            table.memory_usage()
+        );
+
+        assert_eq!(Ok(None), table.apply(Command::ReleaseSnapshot { id }).await);
+        assert_eq!(
+            Err(Error::UnknownSnapshot(id)),
+            table
+                .apply(Command::Get {
+                    key: b"abc".to_vec(),
+                    snapshot: Some(id),
+                })
+                .await
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge() {
+        let newer = vec![
+            InternalPair::new(b"abc01", None),
+            InternalPair::new(b"abc02", Some(b"new")),
+            InternalPair::new(b"abc04", Some(b"new")),
+        ];
+        let older = vec![
+            InternalPair::new(b"abc00", Some(b"old")),
+            InternalPair::new(b"abc01", Some(b"old")),
+            InternalPair::new(b"abc02", Some(b"old")),
+            InternalPair::new(b"abc03", Some(b"old")),
+        ];
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc00", Some(b"old")),
+                InternalPair::new(b"abc02", Some(b"new")),
+                InternalPair::new(b"abc03", Some(b"old")),
+            ],
+            merge_pairs(newer.clone(), older.clone(), 3, false)
+        );
+        let newer: Vec<_> = newer.into_iter().rev().collect();
+        let older: Vec<_> = older.into_iter().rev().collect();
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc04", Some(b"new")),
+                InternalPair::new(b"abc03", Some(b"old")),
+                InternalPair::new(b"abc02", Some(b"new")),
+            ],
+            merge_pairs(newer, older, 3, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn group_commit() -> io::Result<()> {
+        let directory = "test_memtable_group_commit";
+        prepare_directory(directory);
+        let (command_tx, command_rx) = mpsc::channel(32);
+        let (tx, _) = mpsc::channel(1);
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Batch,
+            command_rx,
+            tx,
+        )
+        .await?;
+
+        let mut receivers = Vec::new();
+        for i in 0..8u8 {
+            let (tx, rx) = oneshot::channel();
+            let command = Command::Put {
+                key: vec![i],
+                value: b"value".to_vec(),
+            };
+            command_tx.send((command, tx)).await.unwrap();
+            receivers.push(rx);
+        }
+        tokio::spawn(Arc::new(table).listen());
+        for rx in receivers {
+            assert_eq!(Ok(None), rx.await.unwrap());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        command_tx
+            .send((
+                Command::Get {
+                    key: vec![3],
+                    snapshot: None,
+                },
+                tx,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(Ok(Some(b"value".to_vec())), rx.await.unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn show_writes_after_sync() -> io::Result<()> {
+        let directory = "test_memtable_show_writes_after_sync";
+        prepare_directory(directory);
+        let (_, rx) = mpsc::channel(1);
+        let (tx, _) = mpsc::channel(1);
+        let table = MemTable::new(
+            directory,
+            MemTableBackend::default(),
+            MEMTABLE_SIZE,
+            limit_immutables(2),
+            SyncMode::Batch,
+            rx,
+            tx,
+        )
+        .await?;
+        table.put(b"abc".to_vec(), b"def".to_vec()).await?;
+        assert_eq!(0, table.last_sequence());
+        assert_eq!(None, table.get_at(b"abc", table.last_sequence()).await);
+        // A write waiting for synchronization is seen by the next write to keep their order.
+        assert_eq!(
+            Err(Error::AlreadyExists),
+            table
+                .compare_and_swap(b"abc".to_vec(), None, b"ghi".to_vec())
+                .await
+        );
+
+        table.sync_log().await?;
+        assert_eq!(1, table.last_sequence());
+        assert_eq!(
+            Some(b"def".to_vec()),
+            table.get_at(b"abc", table.last_sequence()).await
+        );
+
+        // Contents are synchronized before they become immutable.
+        table
+            .put(b"xyz".to_vec(), vec![b'x'; MEMTABLE_SIZE])
+            .await?;
+        assert_eq!(1, table.view().immutables.len());
+        assert_eq!(2, table.last_sequence());
+        Ok(())
     }
 }
@@ -0,0 +1,643 @@
+use crate::format::InternalPair;
+use std::alloc::{self, Layout};
+use std::cmp::Ordering as KeyOrdering;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Maximum height of towers of nodes.
+const MAX_HEIGHT: usize = 12;
+
+/// Size of a chunk `Arena` allocates at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Alignment of every allocation in `Arena`.
+const ALIGN: usize = mem::align_of::<Node>();
+
+/// `value_len` of a deletion.
+const DELETED: u32 = u32::MAX;
+
+/// A region of memory `Arena` allocates from.
+struct Chunk {
+    memory: *mut u8,
+    capacity: usize,
+
+    /// Number of bytes handed out, which may exceed `capacity` after the chunk gets full.
+    used: AtomicUsize,
+}
+
+impl Chunk {
+    fn new(capacity: usize) -> Self {
+        let layout = Layout::from_size_align(capacity, ALIGN).unwrap();
+        // SAFETY: `capacity` is never 0.
+        let memory = unsafe { alloc::alloc(layout) };
+        if memory.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self {
+            memory,
+            capacity,
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.capacity, ALIGN).unwrap();
+        // SAFETY: `memory` was allocated with the same layout.
+        unsafe { alloc::dealloc(self.memory, layout) };
+    }
+}
+
+/// Bump allocator which frees everything at once when it is dropped.
+/// Allocation only bumps an offset of the current chunk, and the lock is taken only when a new
+/// chunk is needed.
+struct Arena {
+    current: AtomicPtr<Chunk>,
+
+    /// All chunks including the current one.
+    /// They are boxed so that `current` keeps pointing to the same chunk.
+    #[allow(clippy::vec_box)]
+    chunks: Mutex<Vec<Box<Chunk>>>,
+
+    /// Number of bytes of all chunks.
+    capacity: AtomicUsize,
+}
+
+impl Arena {
+    fn new() -> Self {
+        let mut chunk = Box::new(Chunk::new(CHUNK_SIZE));
+        Self {
+            current: AtomicPtr::new(&mut *chunk),
+            chunks: Mutex::new(vec![chunk]),
+            capacity: AtomicUsize::new(CHUNK_SIZE),
+        }
+    }
+
+    /// `size` rounded up to a multiple of `ALIGN`.
+    fn aligned(size: usize) -> usize {
+        (size + ALIGN - 1) & !(ALIGN - 1)
+    }
+
+    /// Allocate `size` bytes aligned to `ALIGN`. The memory is valid while `self` lives.
+    fn allocate(&self, size: usize) -> *mut u8 {
+        let size = Self::aligned(size);
+        if size > CHUNK_SIZE / 4 {
+            // A large allocation gets its own chunk not to waste the rest of the current one.
+            let mut chunks = self.chunks.lock().unwrap();
+            return self.push_chunk(&mut chunks, size, false);
+        }
+        loop {
+            let current = self.current.load(Ordering::Acquire);
+            // SAFETY: chunks are freed only when `self` is dropped.
+            let chunk = unsafe { &*current };
+            let offset = chunk.used.fetch_add(size, Ordering::Relaxed);
+            if offset + size <= chunk.capacity {
+                // SAFETY: `offset + size` is in the chunk.
+                return unsafe { chunk.memory.add(offset) };
+            }
+            let mut chunks = self.chunks.lock().unwrap();
+            // Another thread may have replaced the chunk.
+            if self.current.load(Ordering::Acquire) == current {
+                self.push_chunk(&mut chunks, CHUNK_SIZE, true);
+            }
+        }
+    }
+
+    /// Allocate a new chunk, make it current if `current` is `true`, and return its memory.
+    /// The caller holds the lock of `chunks`.
+    #[allow(clippy::vec_box)]
+    fn push_chunk(&self, chunks: &mut Vec<Box<Chunk>>, capacity: usize, current: bool) -> *mut u8 {
+        let mut chunk = Box::new(Chunk::new(capacity));
+        let memory = chunk.memory;
+        if current {
+            self.current.store(&mut *chunk, Ordering::Release);
+        } else {
+            chunk.used.store(capacity, Ordering::Relaxed);
+        }
+        chunks.push(chunk);
+        self.capacity.fetch_add(capacity, Ordering::Relaxed);
+        memory
+    }
+}
+
+/// Header of a node, which is followed by `height` pointers to next nodes, the key and the
+/// value in `Arena`.
+#[repr(C)]
+struct Node {
+    sequence: u64,
+    key_len: u32,
+
+    /// Length of the value, or `DELETED` if the key is deleted.
+    value_len: u32,
+
+    height: usize,
+}
+
+impl Node {
+    /// Number of bytes a node occupies.
+    fn size(height: usize, key_len: usize, value_len: usize) -> usize {
+        mem::size_of::<Node>() + height * mem::size_of::<AtomicPtr<Node>>() + key_len + value_len
+    }
+
+    fn tower(&self) -> &[AtomicPtr<Node>] {
+        // SAFETY: the tower follows the header in the same allocation.
+        unsafe {
+            let tower = (self as *const Node).add(1) as *const AtomicPtr<Node>;
+            slice::from_raw_parts(tower, self.height)
+        }
+    }
+
+    fn next(&self, level: usize) -> *mut Node {
+        self.tower()[level].load(Ordering::Acquire)
+    }
+
+    fn key(&self) -> &[u8] {
+        // SAFETY: the key follows the tower in the same allocation.
+        unsafe {
+            let key = self.tower().as_ptr().add(self.height) as *const u8;
+            slice::from_raw_parts(key, self.key_len as usize)
+        }
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        if self.value_len == DELETED {
+            return None;
+        }
+        let key = self.key();
+        // SAFETY: the value follows the key in the same allocation.
+        unsafe {
+            let value = key.as_ptr().add(key.len());
+            Some(slice::from_raw_parts(value, self.value_len as usize))
+        }
+    }
+
+    /// Order of this node against a version of `key` at `sequence`.
+    /// Nodes are sorted by keys, and versions of a key are sorted from the newest one.
+    fn compare(&self, key: &[u8], sequence: u64) -> KeyOrdering {
+        self.key()
+            .cmp(key)
+            .then_with(|| sequence.cmp(&self.sequence))
+    }
+
+    fn to_pair(&self) -> InternalPair {
+        InternalPair::new(self.key(), self.value()).with_sequence(self.sequence)
+    }
+}
+
+/// Concurrent skip list which holds versions of keys.
+/// Versions are only inserted, and a deletion is inserted as a version without a value.
+/// Nodes are linked with compare-and-swap, so readers and writers never wait for each other.
+/// Nodes are never removed and all of them are freed at once with `Arena`.
+pub struct SkipList {
+    arena: Arena,
+    head: *mut Node,
+
+    /// Height of the highest tower.
+    height: AtomicUsize,
+
+    /// Number of bytes of inserted nodes.
+    memory_usage: AtomicUsize,
+
+    /// State of the random number generator for heights of towers.
+    seed: AtomicU32,
+}
+
+// SAFETY: nodes are immutable except atomic pointers of towers, and they live as long as `Arena`.
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        let arena = Arena::new();
+        let head = Self::allocate_node(&arena, b"", None, 0, MAX_HEIGHT);
+        Self {
+            arena,
+            head,
+            height: AtomicUsize::new(1),
+            memory_usage: AtomicUsize::new(0),
+            seed: AtomicU32::new(0x2545_f491),
+        }
+    }
+
+    /// Insert a version of `key` at `sequence`, which is a deletion if `value` is `None`.
+    /// A version at the same sequence number must not be inserted twice.
+    pub fn insert(&self, key: &[u8], value: Option<&[u8]>, sequence: u64) {
+        let height = self.random_height();
+        let node = Self::allocate_node(&self.arena, key, value, sequence, height);
+        let mut list_height = self.height.load(Ordering::Relaxed);
+        while height > list_height {
+            match self.height.compare_exchange_weak(
+                list_height,
+                height,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => list_height = current,
+            }
+        }
+
+        let mut prevs = [self.head; MAX_HEIGHT];
+        let mut nexts = [ptr::null_mut(); MAX_HEIGHT];
+        let mut prev = self.head;
+        for level in (0..height.max(list_height)).rev() {
+            let (found_prev, found_next) = self.find_splice(key, sequence, level, prev);
+            prevs[level] = found_prev;
+            nexts[level] = found_next;
+            prev = found_prev;
+        }
+        // Link from the bottom so that the node is reachable at lower levels first.
+        // SAFETY: nodes live as long as `self`.
+        let tower = unsafe { (*node).tower() };
+        for level in 0..height {
+            loop {
+                tower[level].store(nexts[level], Ordering::Relaxed);
+                let prev_tower = unsafe { (*prevs[level]).tower() };
+                match prev_tower[level].compare_exchange(
+                    nexts[level],
+                    node,
+                    Ordering::Release,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => break,
+                    // Another node was linked there, so search again from the previous node.
+                    Err(_) => {
+                        let (found_prev, found_next) =
+                            self.find_splice(key, sequence, level, prevs[level]);
+                        prevs[level] = found_prev;
+                        nexts[level] = found_next;
+                    }
+                }
+            }
+        }
+        let value_len = value.map_or(0, <[u8]>::len);
+        self.memory_usage.fetch_add(
+            Arena::aligned(Node::size(height, key.len(), value_len)),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The newest version of `key` whose sequence number is not greater than `sequence`.
+    pub fn get(&self, key: &[u8], sequence: u64) -> Option<InternalPair> {
+        self.seek(key, sequence)
+            .filter(|node| node.key() == key)
+            .map(Node::to_pair)
+    }
+
+    /// Get pairs whose keys are in `[start, end)` in order of keys, or in descending order if
+    /// `reverse` is `true`, until `limit` pairs which are not deleted are found.
+    /// The newest version of each key whose sequence number is not greater than `sequence` is
+    /// returned, including deletions.
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> Vec<InternalPair> {
+        let mut pairs = Vec::new();
+        if matches!(end, Some(end) if end <= start) {
+            return pairs;
+        }
+        let mut found = 0;
+        let mut push = |node: &Node| {
+            pairs.push(node.to_pair());
+            usize::from(node.value_len != DELETED)
+        };
+        if reverse {
+            let mut bound = end;
+            while found < limit {
+                let key = match self.seek_before(bound) {
+                    Some(node) if node.key() >= start => node.key(),
+                    _ => break,
+                };
+                if let Some(node) = self.seek(key, sequence).filter(|node| node.key() == key) {
+                    found += push(node);
+                }
+                bound = Some(key);
+            }
+        } else {
+            let mut taken: Option<&[u8]> = None;
+            for node in self.nodes_from(self.seek(start, u64::MAX)) {
+                if matches!(end, Some(end) if node.key() >= end) || found == limit {
+                    break;
+                }
+                if taken == Some(node.key()) || node.sequence > sequence {
+                    continue;
+                }
+                taken = Some(node.key());
+                found += push(node);
+            }
+        }
+        pairs
+    }
+
+    /// Iterate all versions in order of keys, from the newest version of each key.
+    pub fn iter(&self) -> impl Iterator<Item = InternalPair> + '_ {
+        // SAFETY: nodes live as long as `self`.
+        let first = unsafe { (*self.head).next(0).as_ref() };
+        self.nodes_from(first).map(Node::to_pair)
+    }
+
+    /// Number of bytes of memory which all versions occupy, including keys of deletions and
+    /// towers. Unused space of the arena is not included.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// Return `true` if no version has been inserted.
+    pub fn is_empty(&self) -> bool {
+        // SAFETY: the head lives as long as `self`.
+        unsafe { (*self.head).next(0).is_null() }
+    }
+
+    /// Number of bytes allocated for nodes, including unused space of the arena.
+    pub fn allocated(&self) -> usize {
+        self.arena.capacity.load(Ordering::Relaxed)
+    }
+
+    fn allocate_node(
+        arena: &Arena,
+        key: &[u8],
+        value: Option<&[u8]>,
+        sequence: u64,
+        height: usize,
+    ) -> *mut Node {
+        let value_len = value.map_or(0, <[u8]>::len);
+        assert!(key.len() < DELETED as usize && value_len < DELETED as usize);
+        let node = arena.allocate(Node::size(height, key.len(), value_len)) as *mut Node;
+        // SAFETY: the allocation is large enough for the header, the tower, the key and the
+        // value, and aligned for `Node`.
+        unsafe {
+            node.write(Node {
+                sequence,
+                key_len: key.len() as u32,
+                value_len: value.map_or(DELETED, |value| value.len() as u32),
+                height,
+            });
+            let tower = node.add(1) as *mut AtomicPtr<Node>;
+            for level in 0..height {
+                tower.add(level).write(AtomicPtr::new(ptr::null_mut()));
+            }
+            let key_ptr = tower.add(height) as *mut u8;
+            ptr::copy_nonoverlapping(key.as_ptr(), key_ptr, key.len());
+            if let Some(value) = value {
+                ptr::copy_nonoverlapping(value.as_ptr(), key_ptr.add(key.len()), value.len());
+            }
+        }
+        node
+    }
+
+    /// Height of a new tower, where each level is taken with probability 1/4.
+    fn random_height(&self) -> usize {
+        // Xorshift. Racing updates only make heights less random.
+        let mut x = self.seed.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed.store(x, Ordering::Relaxed);
+        let mut height = 1;
+        while height < MAX_HEIGHT && x & 3 == 0 {
+            height += 1;
+            x >>= 2;
+        }
+        height
+    }
+
+    /// Find adjacent nodes at `level` between which a version of `key` at `sequence` is
+    /// inserted, searching from `start` which comes before it.
+    fn find_splice(
+        &self,
+        key: &[u8],
+        sequence: u64,
+        level: usize,
+        start: *mut Node,
+    ) -> (*mut Node, *mut Node) {
+        let mut prev = start;
+        loop {
+            // SAFETY: nodes live as long as `self`.
+            let next = unsafe { (*prev).next(level) };
+            match unsafe { next.as_ref() } {
+                Some(node) if node.compare(key, sequence) == KeyOrdering::Less => prev = next,
+                _ => return (prev, next),
+            }
+        }
+    }
+
+    /// The first node which is not before a version of `key` at `sequence`.
+    fn seek(&self, key: &[u8], sequence: u64) -> Option<&Node> {
+        let mut prev = self.head;
+        let mut next = ptr::null_mut();
+        for level in (0..self.height.load(Ordering::Relaxed)).rev() {
+            let (found_prev, found_next) = self.find_splice(key, sequence, level, prev);
+            prev = found_prev;
+            next = found_next;
+        }
+        // SAFETY: nodes live as long as `self`.
+        unsafe { next.as_ref() }
+    }
+
+    /// The last node whose key is less than `bound`, or the last node if it is `None`.
+    fn seek_before(&self, bound: Option<&[u8]>) -> Option<&Node> {
+        let mut prev = self.head;
+        for level in (0..self.height.load(Ordering::Relaxed)).rev() {
+            loop {
+                // SAFETY: nodes live as long as `self`.
+                let next = unsafe { (*prev).next(level) };
+                match unsafe { next.as_ref() } {
+                    Some(node) if bound.is_none_or(|bound| node.key() < bound) => prev = next,
+                    _ => break,
+                }
+            }
+        }
+        if prev == self.head {
+            None
+        } else {
+            // SAFETY: nodes live as long as `self`.
+            unsafe { prev.as_ref() }
+        }
+    }
+
+    /// Iterate nodes at the bottom level from `first`.
+    fn nodes_from<'a>(&'a self, first: Option<&'a Node>) -> Nodes<'a> {
+        Nodes {
+            node: first.map_or(ptr::null(), |node| node as *const Node),
+            _list: PhantomData,
+        }
+    }
+}
+
+/// Iterator over nodes at the bottom level of `SkipList`.
+struct Nodes<'a> {
+    node: *const Node,
+    _list: PhantomData<&'a SkipList>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // SAFETY: nodes live as long as the list.
+        let node = unsafe { self.node.as_ref() }?;
+        self.node = node.next(0);
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn get_versions() {
+        let list = SkipList::new();
+        assert!(list.is_empty());
+        list.insert(b"abc", Some(b"v1"), 1);
+        list.insert(b"xyz", Some(b"v2"), 2);
+        list.insert(b"abc", None, 3);
+        list.insert(b"abc", Some(b"v4"), 4);
+        assert!(!list.is_empty());
+        assert_eq!(
+            Some(InternalPair::new(b"abc", Some(b"v4")).with_sequence(4)),
+            list.get(b"abc", u64::MAX)
+        );
+        assert_eq!(
+            Some(InternalPair::new(b"abc", None).with_sequence(3)),
+            list.get(b"abc", 3)
+        );
+        assert_eq!(
+            Some(InternalPair::new(b"abc", Some(b"v1")).with_sequence(1)),
+            list.get(b"abc", 2)
+        );
+        assert_eq!(None, list.get(b"abc", 0));
+        assert_eq!(None, list.get(b"ab", u64::MAX));
+        assert_eq!(None, list.get(b"abcd", u64::MAX));
+        assert_eq!(
+            vec![
+                (b"abc".to_vec(), 4),
+                (b"abc".to_vec(), 3),
+                (b"abc".to_vec(), 1)
+            ],
+            list.iter()
+                .take(3)
+                .map(|pair| (pair.key, pair.sequence))
+                .collect::<Vec<_>>()
+        );
+        // 4 nodes with headers, towers, 12 bytes of keys and 6 bytes of values.
+        let smallest = 4 * Node::size(1, 0, 0) + 18;
+        assert!((smallest..=list.allocated()).contains(&list.memory_usage()));
+    }
+
+    #[test]
+    fn scan() {
+        let list = SkipList::new();
+        for (i, key) in ["abc", "abd", "abe", "abf", "xyz"].iter().enumerate() {
+            list.insert(key.as_bytes(), Some(b"v"), i as u64 + 1);
+        }
+        list.insert(b"abd", None, 6);
+        list.insert(b"abe", Some(b"w"), 7);
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc", Some(b"v")).with_sequence(1),
+                InternalPair::new(b"abd", None).with_sequence(6),
+                InternalPair::new(b"abe", Some(b"w")).with_sequence(7),
+            ],
+            list.scan(b"ab", Some(b"abf"), 10, false, u64::MAX)
+        );
+        // A deleted pair is not counted in `limit`.
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abd", None).with_sequence(6),
+                InternalPair::new(b"abe", Some(b"w")).with_sequence(7),
+            ],
+            list.scan(b"abd", None, 1, false, u64::MAX)
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abd", Some(b"v")).with_sequence(2),
+                InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+            ],
+            list.scan(b"abd", Some(b"abf"), 10, false, 5)
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"xyz", Some(b"v")).with_sequence(5),
+                InternalPair::new(b"abf", Some(b"v")).with_sequence(4),
+                InternalPair::new(b"abe", Some(b"w")).with_sequence(7),
+            ],
+            list.scan(b"", None, 3, true, u64::MAX)
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abe", Some(b"v")).with_sequence(3),
+                InternalPair::new(b"abd", Some(b"v")).with_sequence(2),
+            ],
+            list.scan(b"abd", Some(b"abf"), 10, true, 5)
+        );
+        // Keys which have no version old enough are skipped.
+        assert_eq!(
+            vec![InternalPair::new(b"abc", Some(b"v")).with_sequence(1)],
+            list.scan(b"", None, 10, true, 1)
+        );
+        assert!(list.scan(b"x", Some(b"a"), 10, false, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn large_values() {
+        let list = SkipList::new();
+        let value = vec![7; CHUNK_SIZE * 2];
+        list.insert(b"large", Some(&value), 1);
+        for i in 0..1000u32 {
+            list.insert(&i.to_be_bytes(), Some(&[0; 100]), u64::from(i) + 2);
+        }
+        assert_eq!(
+            Some(value),
+            list.get(b"large", 1).and_then(|pair| pair.value)
+        );
+        assert_eq!(1001, list.iter().count());
+        assert!(list.allocated() >= CHUNK_SIZE * 2 + 1000 * 100);
+    }
+
+    #[test]
+    fn insert_in_parallel() {
+        let list = Arc::new(SkipList::new());
+        let threads: Vec<_> = (0..8u64)
+            .map(|thread| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..500u64 {
+                        let key = (i % 100).to_be_bytes();
+                        let sequence = thread * 500 + i + 1;
+                        list.insert(&key, Some(&sequence.to_le_bytes()), sequence);
+                        assert!(list.get(&key, u64::MAX).unwrap().sequence >= sequence);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let pairs: Vec<_> = list.iter().collect();
+        assert_eq!(4000, pairs.len());
+        assert!(pairs.windows(2).all(|pair| {
+            (&pair[0].key, std::cmp::Reverse(pair[0].sequence))
+                < (&pair[1].key, std::cmp::Reverse(pair[1].sequence))
+        }));
+        assert_eq!(100, list.scan(b"", None, 1000, false, u64::MAX).len());
+    }
+}
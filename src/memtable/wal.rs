@@ -0,0 +1,455 @@
+use crate::error::Corruption;
+use crate::format::{
+    frame_block, verify_block, InternalPair, FILTER_FORMAT_VERSION, FORMAT_VERSION,
+    RECORD_CHECKSUM_FORMAT_VERSION, SEQUENCE_FORMAT_VERSION,
+};
+use crate::sstable::{sync_directory, temporary_path};
+use log::{info, warn};
+use std::convert::TryInto;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Name of the log file in the data directory.
+const WAL_FILE_NAME: &str = "wal.log";
+
+/// Tag at the head of the header of the log, which is distinguished from records.
+const HEADER_TAG: u8 = 0xfe;
+
+/// Length of the header of the log.
+const HEADER_LENGTH: usize = 13;
+
+/// Tag at the head of a record of a batch, which is distinguished from entry types of a
+/// serialized `InternalPair`.
+const BATCH_RECORD_TAG: u8 = 0xff;
+
+/// Length of the tag and the length of pairs at the head of a record of a batch.
+const BATCH_HEADER_LENGTH: usize = 9;
+
+/// Length of the checksum at the end of a record of a batch.
+const BATCH_TRAILER_LENGTH: usize = 4;
+
+/// Write-ahead log of `MemTable`.
+/// Every mutation is appended to this log before it is applied to `MemTable`,
+/// so that contents not yet flushed to an SSTable can be recovered after a crash.
+/// Records are serialized in the same way as `InternalPair` in an SSTable file.
+///
+/// The log starts with a header, which holds the version of the encoding of records and the
+/// last sequence number when the log was truncated, so that sequence numbers keep increasing
+/// after records are flushed:
+/// ```text
+/// +-------------+----------------+---------------------------------+
+/// | 0xfe(1byte) | version(4byte) | last sequence number(8byte)     |
+/// +-------------+----------------+---------------------------------+
+/// ```
+/// A log without the header was written before `SEQUENCE_FORMAT_VERSION`, and it is rewritten
+/// in the current version when it is opened.
+///
+/// Pairs written by a batch are recorded together, so that either all or none of them are
+/// replayed. Such a record is laid out as follows:
+/// ```text
+/// +-------------+---------------------+------------------+----------------+
+/// | 0xff(1byte) | pairs length(8byte) | serialized pairs | CRC-32C(4byte) |
+/// +-------------+---------------------+------------------+----------------+
+/// ```
+/// Since `RECORD_CHECKSUM_FORMAT_VERSION`, a single pair is recorded as a batch of one pair,
+/// so that every record is verified by its checksum. Before that, it is a serialized pair.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    /// Path of the log file.
+    path: PathBuf,
+
+    /// Log file.
+    file: File,
+
+    /// The last sequence number in the header.
+    last_sequence: u64,
+
+    /// Whether the log is synchronized to a disk when it is rewritten.
+    sync: bool,
+}
+
+impl WriteAheadLog {
+    /// Open the log in `directory` and return pairs recorded in it in written order.
+    /// An incomplete record at the end of the log, which is left by a crash in the middle of
+    /// appending, is discarded. A broken record of a batch discards all pairs in it.
+    pub async fn open<P: AsRef<Path>>(
+        directory: P,
+        sync: bool,
+    ) -> io::Result<(Self, Vec<InternalPair>)> {
+        let mut path = PathBuf::new();
+        path.push(directory);
+        path.push(WAL_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await?;
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).await?;
+        let (version, last_sequence, header_length) = match buffer.first() {
+            Some(&HEADER_TAG) => {
+                if buffer.len() < HEADER_LENGTH {
+                    return Err(Corruption("Truncated header of the log".to_string()).into());
+                }
+                let version = u32::from_le_bytes(buffer[1..5].try_into().unwrap());
+                let last_sequence = u64::from_le_bytes(buffer[5..13].try_into().unwrap());
+                (version, last_sequence, HEADER_LENGTH)
+            }
+            // Records in a log without the header are encoded like SSTables in this version.
+            _ => (FILTER_FORMAT_VERSION, 0, 0),
+        };
+        let mut pairs = Vec::new();
+        let mut reader = &buffer[header_length..];
+        let mut valid_length = header_length;
+        while !reader.is_empty() {
+            let record = if reader[0] == BATCH_RECORD_TAG {
+                read_batch(&mut reader, version).await
+            } else if version >= RECORD_CHECKSUM_FORMAT_VERSION {
+                Err("Record without checksum".to_string())
+            } else {
+                InternalPair::deserialize_with_version(&mut reader, version)
+                    .await
+                    .map(|pair| vec![pair])
+                    .map_err(|err| err.to_string())
+            };
+            match record {
+                Ok(mut record) => {
+                    valid_length = buffer.len() - reader.len();
+                    pairs.append(&mut record);
+                }
+                Err(err) => {
+                    warn!("Discard a broken record at the end of the log: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let mut wal = Self {
+            path,
+            file,
+            last_sequence,
+            sync,
+        };
+        if header_length == 0 || version != FORMAT_VERSION {
+            if version < SEQUENCE_FORMAT_VERSION {
+                // Number pairs in written order.
+                for (pair, sequence) in pairs.iter_mut().zip(last_sequence + 1..) {
+                    pair.sequence = sequence;
+                }
+            }
+            if !buffer.is_empty() {
+                info!("Rewrite the log in version {}", FORMAT_VERSION);
+            }
+            wal.rewrite(&pairs, last_sequence).await?;
+        } else {
+            if valid_length < buffer.len() {
+                wal.file.set_len(valid_length as u64).await?;
+            }
+            wal.file.seek(SeekFrom::Start(valid_length as u64)).await?;
+        }
+        Ok((wal, pairs))
+    }
+
+    /// The last sequence number when the log was truncated.
+    /// Sequence numbers of pairs in the log are bigger than this.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Append a record of a pair to the log.
+    /// The record is handed to OS, but not synchronized to a disk until `sync()` is called.
+    pub async fn append(&mut self, pair: &InternalPair) -> io::Result<()> {
+        self.append_batch(std::slice::from_ref(pair)).await
+    }
+
+    /// Append a record of pairs written by a batch to the log.
+    pub async fn append_batch(&mut self, pairs: &[InternalPair]) -> io::Result<()> {
+        self.file.write_all(&encode_batch(pairs)).await?;
+        self.file.flush().await
+    }
+
+    /// Synchronize appended records to a disk.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data().await
+    }
+
+    /// Discard all records and remember `last_sequence` as the last sequence number, keeping
+    /// `pairs` written after it.
+    /// This is called after contents of `MemTable` up to `last_sequence` are persisted in an
+    /// SSTable.
+    pub async fn truncate(&mut self, last_sequence: u64, pairs: &[InternalPair]) -> io::Result<()> {
+        self.rewrite(pairs, last_sequence).await
+    }
+
+    /// Atomically replace the log with one which holds `pairs` in the current version.
+    async fn rewrite(&mut self, pairs: &[InternalPair], last_sequence: u64) -> io::Result<()> {
+        let mut contents = vec![HEADER_TAG];
+        contents.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        contents.extend_from_slice(&last_sequence.to_le_bytes());
+        if !pairs.is_empty() {
+            contents.append(&mut encode_batch(pairs));
+        }
+        let temporary_path = temporary_path(&self.path);
+        let mut file = File::create(&temporary_path).await?;
+        file.write_all(&contents).await?;
+        file.flush().await?;
+        if self.sync {
+            file.sync_all().await?;
+        }
+        fs::rename(&temporary_path, &self.path).await?;
+        if self.sync {
+            sync_directory(self.path.parent().unwrap()).await?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .await?;
+        file.seek(SeekFrom::End(0)).await?;
+        self.file = file;
+        self.last_sequence = last_sequence;
+        Ok(())
+    }
+}
+
+/// Encode a record of a batch.
+fn encode_batch(pairs: &[InternalPair]) -> Vec<u8> {
+    let mut record = vec![BATCH_RECORD_TAG];
+    record.append(&mut frame_block(InternalPair::serialize_flatten(pairs)));
+    record
+}
+
+/// Read a record of a batch from the head of `reader` and advance it past the record.
+async fn read_batch(reader: &mut &[u8], version: u32) -> Result<Vec<InternalPair>, String> {
+    if reader.len() < BATCH_HEADER_LENGTH {
+        return Err("Truncated batch header".to_string());
+    }
+    let data_length = u64::from_le_bytes(reader[1..BATCH_HEADER_LENGTH].try_into().unwrap());
+    let record_length = (data_length as usize)
+        .checked_add(BATCH_HEADER_LENGTH + BATCH_TRAILER_LENGTH)
+        .filter(|length| *length <= reader.len())
+        .ok_or_else(|| format!("Invalid batch length: {}", data_length))?;
+    let data =
+        verify_block(&reader[1..record_length], version).map_err(|Corruption(message)| message)?;
+    let pairs = InternalPair::deserialize_from_bytes(data, version)
+        .await
+        .map_err(|err| err.to_string())?;
+    *reader = &reader[record_length..];
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::tests::prepare_directory;
+
+    #[tokio::test]
+    async fn append_and_replay() -> io::Result<()> {
+        let path = "test_wal_append_and_replay";
+        prepare_directory(path);
+        let pairs = vec![
+            InternalPair::new(b"abc", Some(b"def")),
+            InternalPair::new(b"xyz", None),
+            InternalPair::new(b"abc", Some(b"ghi")),
+        ];
+        let (mut wal, replayed) = WriteAheadLog::open(path, false).await?;
+        assert!(replayed.is_empty());
+        for pair in pairs.iter() {
+            wal.append(pair).await?;
+        }
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(pairs, replayed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn truncate() -> io::Result<()> {
+        let path = "test_wal_truncate";
+        prepare_directory(path);
+        let (mut wal, _) = WriteAheadLog::open(path, false).await?;
+        wal.append(&InternalPair::new(b"abc", Some(b"def")).with_sequence(1))
+            .await?;
+        wal.append(&InternalPair::new(b"xyz", Some(b"xxx")).with_sequence(2))
+            .await?;
+        wal.truncate(
+            1,
+            &[InternalPair::new(b"xyz", Some(b"xxx")).with_sequence(2)],
+        )
+        .await?;
+        wal.append(&InternalPair::new(b"xyz", None).with_sequence(3))
+            .await?;
+        drop(wal);
+
+        let (wal, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(
+            vec![
+                InternalPair::new(b"xyz", Some(b"xxx")).with_sequence(2),
+                InternalPair::new(b"xyz", None).with_sequence(3),
+            ],
+            replayed
+        );
+        assert_eq!(1, wal.last_sequence());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_broken_tail() -> io::Result<()> {
+        let path = "test_wal_discard_broken_tail";
+        prepare_directory(path);
+        let (mut wal, _) = WriteAheadLog::open(path, false).await?;
+        wal.append(&InternalPair::new(b"abc", Some(b"def"))).await?;
+        // Simulate a crash while appending a record.
+        wal.file
+            .write_all(&encode_batch(&[InternalPair::new(b"xyz", Some(b"xxx"))])[..20])
+            .await?;
+        wal.file.flush().await?;
+        drop(wal);
+
+        let (mut wal, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(vec![InternalPair::new(b"abc", Some(b"def"))], replayed);
+        wal.append(&InternalPair::new(b"xyz", Some(b"xxx"))).await?;
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc", Some(b"def")),
+                InternalPair::new(b"xyz", Some(b"xxx")),
+            ],
+            replayed
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_corrupted_record() -> io::Result<()> {
+        let path = "test_wal_discard_corrupted_record";
+        prepare_directory(path);
+        let (mut wal, _) = WriteAheadLog::open(path, false).await?;
+        wal.append(&InternalPair::new(b"abc", Some(b"def"))).await?;
+        wal.append(&InternalPair::new(b"xyz", Some(b"xxx"))).await?;
+        drop(wal);
+        // Flip a bit in the value of the last record.
+        let log_path = format!("{}/{}", path, WAL_FILE_NAME);
+        let mut contents = fs::read(&log_path).await?;
+        let position = contents.len() - BATCH_TRAILER_LENGTH - 1;
+        contents[position] ^= 1;
+        fs::write(&log_path, &contents).await?;
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(vec![InternalPair::new(b"abc", Some(b"def"))], replayed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upgrade_log_without_record_checksums() -> io::Result<()> {
+        let path = "test_wal_upgrade_log_without_record_checksums";
+        prepare_directory(path);
+        let pairs = vec![
+            InternalPair::new(b"abc", Some(b"def")).with_sequence(4),
+            InternalPair::new(b"xyz", None).with_sequence(5),
+        ];
+        let mut contents = vec![HEADER_TAG];
+        contents.extend_from_slice(&SEQUENCE_FORMAT_VERSION.to_le_bytes());
+        contents.extend_from_slice(&3u64.to_le_bytes());
+        contents.append(&mut InternalPair::serialize_flatten_with_version(
+            &pairs,
+            SEQUENCE_FORMAT_VERSION,
+        ));
+        fs::write(format!("{}/{}", path, WAL_FILE_NAME), &contents).await?;
+
+        let (mut wal, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(pairs, replayed);
+        assert_eq!(3, wal.last_sequence());
+        wal.append(&InternalPair::new(b"ghi", Some(b"jkl")).with_sequence(6))
+            .await?;
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(3, replayed.len());
+        assert_eq!(pairs, replayed[..2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_batch() -> io::Result<()> {
+        let path = "test_wal_replay_batch";
+        prepare_directory(path);
+        let batch = vec![
+            InternalPair::new(b"abc", Some(b"def")),
+            InternalPair::new(b"xyz", None),
+        ];
+        let (mut wal, _) = WriteAheadLog::open(path, false).await?;
+        wal.append(&InternalPair::new(b"xyz", Some(b"xxx"))).await?;
+        wal.append_batch(&batch).await?;
+        wal.append(&InternalPair::new(b"ghi", Some(b"jkl"))).await?;
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(
+            vec![
+                InternalPair::new(b"xyz", Some(b"xxx")),
+                InternalPair::new(b"abc", Some(b"def")),
+                InternalPair::new(b"xyz", None),
+                InternalPair::new(b"ghi", Some(b"jkl")),
+            ],
+            replayed
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_broken_batch() -> io::Result<()> {
+        let path = "test_wal_discard_broken_batch";
+        prepare_directory(path);
+        let (mut wal, _) = WriteAheadLog::open(path, false).await?;
+        wal.append(&InternalPair::new(b"xyz", Some(b"xxx"))).await?;
+        let position = wal.file.seek(SeekFrom::Current(0)).await?;
+        wal.append_batch(&[
+            InternalPair::new(b"abc", Some(b"def")),
+            InternalPair::new(b"ghi", Some(b"jkl")),
+        ])
+        .await?;
+        // Simulate a crash after the first pair in the batch is written.
+        wal.file.set_len(position + 40).await?;
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(vec![InternalPair::new(b"xyz", Some(b"xxx"))], replayed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upgrade_log_without_header() -> io::Result<()> {
+        let path = "test_wal_upgrade_log_without_header";
+        prepare_directory(path);
+        let pairs = vec![
+            InternalPair::new(b"abc", Some(b"def")),
+            InternalPair::new(b"xyz", None),
+        ];
+        let contents = InternalPair::serialize_flatten_with_version(&pairs, FILTER_FORMAT_VERSION);
+        fs::write(format!("{}/{}", path, WAL_FILE_NAME), &contents).await?;
+
+        let numbered = vec![
+            InternalPair::new(b"abc", Some(b"def")).with_sequence(1),
+            InternalPair::new(b"xyz", None).with_sequence(2),
+        ];
+        let (mut wal, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(numbered, replayed);
+        wal.append(&InternalPair::new(b"ghi", Some(b"jkl")).with_sequence(3))
+            .await?;
+        drop(wal);
+
+        let (_, replayed) = WriteAheadLog::open(path, false).await?;
+        assert_eq!(3, replayed.len());
+        assert_eq!(numbered, replayed[..2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A snapshot taken by a client.
+#[derive(Debug)]
+struct Registration {
+    /// The sequence number of the last write when the snapshot is taken.
+    sequence: u64,
+
+    /// When the snapshot is released even if the client does not release it.
+    expires_at: Instant,
+}
+
+/// Live snapshots taken by clients.
+/// A snapshot sees exactly the versions whose sequence numbers are not greater than its
+/// sequence number. Each snapshot has its own id even if several are taken at the same sequence
+/// number, and ids are hard to guess, so that a client cannot release a snapshot of another
+/// one.
+/// Expired snapshots are removed lazily when the registry is looked up.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    registrations: HashMap<u64, Registration>,
+
+    /// Number of snapshots taken so far, which is hashed into the next id.
+    taken: u64,
+
+    /// Randomly keyed hasher which turns `taken` into ids.
+    ids: RandomState,
+}
+
+impl Snapshots {
+    /// Take a snapshot at `sequence` which expires after `ttl` from `now`, and return its id.
+    pub fn create(&mut self, sequence: u64, ttl: Duration, now: Instant) -> u64 {
+        self.remove_expired(now);
+        let id = loop {
+            self.taken += 1;
+            let id = self.ids.hash_one(self.taken);
+            if !self.registrations.contains_key(&id) {
+                break id;
+            }
+        };
+        let expires_at = now + ttl;
+        self.registrations.insert(
+            id,
+            Registration {
+                sequence,
+                expires_at,
+            },
+        );
+        id
+    }
+
+    /// Release the snapshot `id` taken by `create()`.
+    /// Return `false` if there is no such snapshot.
+    pub fn release(&mut self, id: u64, now: Instant) -> bool {
+        self.remove_expired(now);
+        self.registrations.remove(&id).is_some()
+    }
+
+    /// The sequence number of the snapshot `id`, or `None` if it is not live.
+    pub fn sequence(&mut self, id: u64, now: Instant) -> Option<u64> {
+        self.remove_expired(now);
+        self.registrations
+            .get(&id)
+            .map(|registration| registration.sequence)
+    }
+
+    /// Sequence numbers of live snapshots in ascending order without duplicates.
+    pub fn sequences(&mut self, now: Instant) -> Vec<u64> {
+        self.remove_expired(now);
+        let mut sequences: Vec<_> = self
+            .registrations
+            .values()
+            .map(|registration| registration.sequence)
+            .collect();
+        sequences.sort_unstable();
+        sequences.dedup();
+        sequences
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        self.registrations
+            .retain(|_, registration| registration.expires_at > now);
+    }
+}
+
+/// Number of reads which are pinned without a lock.
+const PIN_SLOTS: usize = 256;
+
+/// Value of a slot which no read uses.
+const FREE: u64 = u64::MAX;
+
+/// Sequence numbers which reads in progress see.
+/// They are kept apart from snapshots of clients, so that clients cannot release them.
+/// Each read takes one of fixed slots without a lock. Reads beyond the slots are counted under
+/// a lock.
+#[derive(Debug)]
+pub struct ReadPins {
+    /// Sequence number of the read using each slot, or `FREE`.
+    slots: Box<[AtomicU64]>,
+
+    /// Slot which the next read tries first, which spreads reads over slots.
+    next: AtomicUsize,
+
+    /// Number of reads for each sequence number which found no free slot.
+    overflow: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Default for ReadPins {
+    fn default() -> Self {
+        Self {
+            slots: (0..PIN_SLOTS).map(|_| AtomicU64::new(FREE)).collect(),
+            next: AtomicUsize::new(0),
+            overflow: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl ReadPins {
+    /// Register a read at the sequence number which `sequence` returns.
+    /// The read is published before `sequence` is called again to check that the number has not
+    /// changed, so `sequences()` called after a newer number is published never misses a read
+    /// at an older one. `sequence` must load the number with `Ordering::SeqCst`.
+    pub fn pin(&self, sequence: impl Fn() -> u64) -> ReadPin<'_> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.slots.len() {
+            let slot = (start + i) % self.slots.len();
+            let mut pinned = sequence();
+            if self.slots[slot]
+                .compare_exchange(FREE, pinned, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            loop {
+                let latest = sequence();
+                if latest == pinned {
+                    return ReadPin {
+                        pins: self,
+                        slot: Some(slot),
+                        sequence: pinned,
+                    };
+                }
+                self.slots[slot].store(latest, Ordering::SeqCst);
+                pinned = latest;
+            }
+        }
+        let mut overflow = self.overflow.lock().unwrap();
+        let sequence = sequence();
+        *overflow.entry(sequence).or_insert(0) += 1;
+        ReadPin {
+            pins: self,
+            slot: None,
+            sequence,
+        }
+    }
+
+    /// Sequence numbers of reads in progress in ascending order.
+    pub fn sequences(&self) -> Vec<u64> {
+        let mut sequences: Vec<_> = self
+            .slots
+            .iter()
+            .map(|slot| slot.load(Ordering::SeqCst))
+            .filter(|sequence| *sequence != FREE)
+            .collect();
+        sequences.extend(self.overflow.lock().unwrap().keys());
+        sequences.sort_unstable();
+        sequences.dedup();
+        sequences
+    }
+}
+
+/// A read registered by `ReadPins::pin()`, which is unregistered when dropped.
+#[derive(Debug)]
+pub struct ReadPin<'a> {
+    pins: &'a ReadPins,
+
+    /// Slot the read uses, or `None` if it is counted in `overflow`.
+    slot: Option<usize>,
+
+    sequence: u64,
+}
+
+impl ReadPin<'_> {
+    /// The sequence number the read sees.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl Drop for ReadPin<'_> {
+    fn drop(&mut self) {
+        match self.slot {
+            Some(slot) => self.pins.slots[slot].store(FREE, Ordering::SeqCst),
+            None => {
+                let mut overflow = self.pins.overflow.lock().unwrap();
+                if let Some(count) = overflow.get_mut(&self.sequence) {
+                    *count -= 1;
+                    if *count == 0 {
+                        overflow.remove(&self.sequence);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Return `true` if a version at `sequence` must be kept for a snapshot in `snapshots`.
+/// `newer` is the sequence number of the next newer version of the same key, which is `None`
+/// if the version is the newest one. A version is needed if it is the newest one or a snapshot
+/// sees it, that is, the snapshot was taken after it but before the newer version.
+pub fn is_visible_version(sequence: u64, newer: Option<u64>, snapshots: &[u64]) -> bool {
+    match newer {
+        Some(newer) => snapshots
+            .iter()
+            .any(|snapshot| sequence <= *snapshot && *snapshot < newer),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_and_release() {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+        let mut snapshots = Snapshots::default();
+        let first = snapshots.create(3, ttl, now);
+        let second = snapshots.create(3, ttl, now);
+        let third = snapshots.create(7, ttl, now);
+        assert_ne!(first, second);
+        assert_eq!(vec![3, 7], snapshots.sequences(now));
+        assert_eq!(Some(7), snapshots.sequence(third, now));
+
+        // Releasing a snapshot does not affect another one at the same sequence number.
+        assert!(snapshots.release(first, now));
+        assert!(!snapshots.release(first, now));
+        assert_eq!(None, snapshots.sequence(first, now));
+        assert_eq!(Some(3), snapshots.sequence(second, now));
+        assert!(snapshots.release(second, now));
+        assert_eq!(vec![7], snapshots.sequences(now));
+    }
+
+    #[test]
+    fn expire() {
+        let now = Instant::now();
+        let mut snapshots = Snapshots::default();
+        let first = snapshots.create(1, Duration::from_secs(10), now);
+        let second = snapshots.create(2, Duration::from_secs(20), now);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(None, snapshots.sequence(first, later));
+        assert_eq!(Some(2), snapshots.sequence(second, later));
+        assert_eq!(vec![2], snapshots.sequences(later));
+        assert!(snapshots
+            .sequences(now + Duration::from_secs(20))
+            .is_empty());
+    }
+
+    #[test]
+    fn pin_reads() {
+        let pins = ReadPins::default();
+        let first = pins.pin(|| 5);
+        assert_eq!(5, first.sequence());
+        let second = pins.pin(|| 5);
+        let third = pins.pin(|| 8);
+        assert_eq!(vec![5, 8], pins.sequences());
+        drop(first);
+        assert_eq!(vec![5, 8], pins.sequences());
+        drop(second);
+        drop(third);
+        assert!(pins.sequences().is_empty());
+    }
+
+    #[test]
+    fn pin_latest_sequence() {
+        // The sequence number changes once after the read is published.
+        let calls = std::cell::Cell::new(0);
+        let pins = ReadPins::default();
+        let pin = pins.pin(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                3
+            } else {
+                4
+            }
+        });
+        assert_eq!(4, pin.sequence());
+        assert_eq!(vec![4], pins.sequences());
+    }
+
+    #[test]
+    fn pin_reads_beyond_slots() {
+        let pins = ReadPins::default();
+        let mut pinned: Vec<_> = (0..PIN_SLOTS as u64).map(|i| pins.pin(|| i)).collect();
+        let first = pins.pin(|| 1000);
+        let second = pins.pin(|| 1000);
+        assert_eq!(PIN_SLOTS + 1, pins.sequences().len());
+        drop(first);
+        assert_eq!(Some(&1000), pins.sequences().last());
+        drop(second);
+        assert_eq!(PIN_SLOTS, pins.sequences().len());
+
+        // A freed slot is used again.
+        pinned.truncate(1);
+        let pin = pins.pin(|| 2000);
+        assert!(pin.slot.is_some());
+        assert_eq!(vec![0, 2000], pins.sequences());
+    }
+
+    #[test]
+    fn visible_versions() {
+        assert!(is_visible_version(5, None, &[]));
+        assert!(!is_visible_version(5, Some(8), &[]));
+        assert!(is_visible_version(5, Some(8), &[5]));
+        assert!(is_visible_version(5, Some(8), &[1, 7]));
+        assert!(!is_visible_version(5, Some(8), &[4, 8]));
+        // Versions with the same sequence number, like ones written before sequence numbers
+        // were introduced, are not needed except the newest.
+        assert!(!is_visible_version(0, Some(0), &[0]));
+    }
+}
@@ -0,0 +1,160 @@
+/// Maximum number of probes for a key.
+/// A filter claiming more probes is regarded as an unknown encoding and matches every key.
+const MAX_PROBES: u8 = 30;
+
+/// Bloom filter for keys in an SSTable.
+/// If `may_contain()` returns `false`, the key is never in the table, so reading the table can
+/// be skipped.
+///
+/// Encoded filter is laid out as follows:
+/// ```text
+/// +---------------+------------------------+
+/// | bit array     | number of probes(1byte)|
+/// +---------------+------------------------+
+/// ```
+/// An empty filter matches every key, which is used for tables without a filter.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BloomFilter {
+    /// Bit array of the filter.
+    bits: Vec<u8>,
+
+    /// Number of bits set for each key.
+    probes: u8,
+}
+
+impl BloomFilter {
+    /// Create a filter for `keys` using `bits_per_key` bits for each key.
+    /// If `bits_per_key` is 0, the filter is empty.
+    pub fn new<'a>(keys: impl ExactSizeIterator<Item = &'a [u8]>, bits_per_key: usize) -> Self {
+        if bits_per_key == 0 {
+            return Self::default();
+        }
+        // ln(2) * bits_per_key minimizes the false positive rate.
+        let probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, MAX_PROBES);
+        // Too small array results in a high false positive rate for a few keys.
+        let bit_length = (keys.len() * bits_per_key).max(64);
+        let byte_length = bit_length.div_ceil(8);
+        let bit_length = byte_length * 8;
+        let mut bits = vec![0; byte_length];
+        for key in keys {
+            for position in probe_positions(key, probes, bit_length) {
+                bits[position / 8] |= 1 << (position % 8);
+            }
+        }
+        Self { bits, probes }
+    }
+
+    /// Number of bytes of memory the bit array uses.
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Return `false` if `key` is definitely not in the set of keys of this filter.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() || self.probes > MAX_PROBES {
+            return true;
+        }
+        let bit_length = self.bits.len() * 8;
+        probe_positions(key, self.probes, bit_length)
+            .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    /// Encode the filter to be persisted in an SSTable file.
+    pub fn encode(&self) -> Vec<u8> {
+        if self.bits.is_empty() {
+            return Vec::new();
+        }
+        let mut buffer = self.bits.clone();
+        buffer.push(self.probes);
+        buffer
+    }
+
+    /// Decode a filter encoded by `encode()`.
+    pub fn decode(mut bytes: Vec<u8>) -> Self {
+        match bytes.pop() {
+            Some(probes) => Self {
+                bits: bytes,
+                probes,
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+/// Positions of bits for `key` using double hashing.
+fn probe_positions(key: &[u8], probes: u8, bit_length: usize) -> impl Iterator<Item = usize> {
+    let mut h = hash(key);
+    let delta = h.rotate_right(17);
+    (0..probes).map(move |_| {
+        let position = h as usize % bit_length;
+        h = h.wrapping_add(delta);
+        position
+    })
+}
+
+/// Hash function similar to MurmurHash, which is also used by LevelDB's Bloom filter.
+fn hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        h = h.wrapping_add(word).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| format!("key{:08}", i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn contain_added_keys() {
+        let keys = keys(1000);
+        let filter = BloomFilter::new(keys.iter().map(|key| key.as_slice()), 10);
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+    }
+
+    #[test]
+    fn false_positive_rate() {
+        let keys = keys(1000);
+        let filter = BloomFilter::new(keys.iter().map(|key| key.as_slice()), 10);
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("absent{:08}", i).as_bytes()))
+            .count();
+        // About 1% is expected with 10 bits per key.
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn empty_filter() {
+        let keys = keys(10);
+        let filter = BloomFilter::new(keys.iter().map(|key| key.as_slice()), 0);
+        assert!(filter.may_contain(b"anything"));
+        assert!(filter.encode().is_empty());
+        assert_eq!(filter, BloomFilter::decode(Vec::new()));
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let keys = keys(100);
+        let filter = BloomFilter::new(keys.iter().map(|key| key.as_slice()), 8);
+        assert_eq!(filter, BloomFilter::decode(filter.encode()));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::format::InternalPair;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Identifies a block by the number of its table and its position in the file.
+/// Table numbers are never reused, so a block of a removed table is never hit and is evicted
+/// eventually.
+type BlockKey = (u64, usize);
+
+#[derive(Debug)]
+struct CacheEntry {
+    pairs: Arc<Vec<InternalPair>>,
+
+    /// Bytes charged to the capacity for this entry.
+    charge: usize,
+
+    /// When this entry is used last.
+    last_used: u64,
+}
+
+/// Cache of deserialized SSTable blocks shared by all tables.
+/// When total charge of blocks exceeds the capacity, the least recently used blocks are evicted.
+#[derive(Debug)]
+pub struct BlockCache {
+    /// Capacity in bytes. If it is 0, nothing is cached.
+    capacity: usize,
+
+    /// Total charge of cached blocks.
+    usage: usize,
+
+    entries: HashMap<BlockKey, CacheEntry>,
+
+    /// Keys of cached blocks ordered by when they are used.
+    recency: BTreeMap<u64, BlockKey>,
+
+    /// Counter incremented each time a block is used.
+    clock: u64,
+
+    /// Number of lookups which found a block.
+    hits: u64,
+
+    /// Number of lookups which did not find a block.
+    misses: u64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            usage: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look up a block and mark it as the most recently used.
+    pub fn get(&mut self, table_number: u64, position: usize) -> Option<Arc<Vec<InternalPair>>> {
+        let key = (table_number, position);
+        let clock = self.tick();
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                self.recency.remove(&entry.last_used);
+                self.recency.insert(clock, key);
+                entry.last_used = clock;
+                self.hits += 1;
+                Some(Arc::clone(&entry.pairs))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache a block charging `charge` bytes and evict old blocks if the capacity is exceeded.
+    /// A block larger than the capacity is not cached.
+    pub fn insert(
+        &mut self,
+        table_number: u64,
+        position: usize,
+        pairs: Arc<Vec<InternalPair>>,
+        charge: usize,
+    ) {
+        if charge > self.capacity {
+            return;
+        }
+        let key = (table_number, position);
+        let clock = self.tick();
+        let entry = CacheEntry {
+            pairs,
+            charge,
+            last_used: clock,
+        };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.recency.remove(&old.last_used);
+            self.usage -= old.charge;
+        }
+        self.recency.insert(clock, key);
+        self.usage += charge;
+        self.evict_to(self.capacity);
+    }
+
+    /// Evict the least recently used blocks until their total charge is at most `usage`.
+    pub fn evict_to(&mut self, usage: usize) {
+        while self.usage > usage {
+            let (&last_used, _) = self.recency.iter().next().unwrap();
+            let key = self.recency.remove(&last_used).unwrap();
+            let entry = self.entries.remove(&key).unwrap();
+            self.usage -= entry.charge;
+        }
+    }
+
+    /// Total charge of cached blocks.
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+
+    /// Number of lookups which found a block.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups which did not find a block.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(key: &[u8]) -> Arc<Vec<InternalPair>> {
+        Arc::new(vec![InternalPair::new(key, Some(b"value"))])
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let mut cache = BlockCache::new(100);
+        assert_eq!(None, cache.get(0, 0));
+        cache.insert(0, 0, block(b"abc"), 10);
+        assert_eq!(Some(block(b"abc")), cache.get(0, 0));
+        assert_eq!(None, cache.get(1, 0));
+        assert_eq!(None, cache.get(0, 10));
+        assert_eq!(1, cache.hits());
+        assert_eq!(3, cache.misses());
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = BlockCache::new(30);
+        cache.insert(0, 0, block(b"a"), 10);
+        cache.insert(0, 10, block(b"b"), 10);
+        cache.insert(0, 20, block(b"c"), 10);
+        // Block at 0 becomes the most recently used.
+        assert!(cache.get(0, 0).is_some());
+        cache.insert(1, 0, block(b"d"), 10);
+        assert_eq!(30, cache.usage);
+        assert!(cache.get(0, 10).is_none());
+        assert!(cache.get(0, 0).is_some());
+        assert!(cache.get(0, 20).is_some());
+        assert!(cache.get(1, 0).is_some());
+    }
+
+    #[test]
+    fn evict_to_usage() {
+        let mut cache = BlockCache::new(30);
+        cache.insert(0, 0, block(b"a"), 10);
+        cache.insert(0, 10, block(b"b"), 10);
+        cache.insert(0, 20, block(b"c"), 10);
+        cache.evict_to(15);
+        assert_eq!(10, cache.usage());
+        assert!(cache.get(0, 20).is_some());
+        cache.evict_to(0);
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn replace_block() {
+        let mut cache = BlockCache::new(30);
+        cache.insert(0, 0, block(b"a"), 10);
+        cache.insert(0, 0, block(b"b"), 20);
+        assert_eq!(20, cache.usage);
+        assert_eq!(Some(block(b"b")), cache.get(0, 0));
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = BlockCache::new(0);
+        cache.insert(0, 0, block(b"a"), 10);
+        assert_eq!(0, cache.usage);
+        assert_eq!(None, cache.get(0, 0));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::format::InternalPair;
+use crate::error::Corruption;
+use crate::format::{self, InternalPair};
+use std::convert::TryInto;
+use std::mem;
 
 /// Block is a group of keys.
 /// This has a first key of the block, position at a disk and length of the block.
@@ -31,6 +34,7 @@ impl Block {
 /// Entries in SSTable's index.  
 /// Holds array of `Block` which is a group of key-value pairs.  
 /// Divide all pairs into `Block`s and every `Block` occupies at most `block_stride` pairs.
+/// Versions of the same key are not divided, so a `Block` may exceed `block_stride` by them.
 ///
 /// Example inner structure:
 /// ```text
@@ -50,20 +54,119 @@ pub struct Index {
 }
 
 impl Index {
+    /// Approximate number of bytes of memory this index uses.
+    pub fn memory_usage(&self) -> usize {
+        self.items
+            .iter()
+            .map(|block| mem::size_of::<Block>() + block.key.len())
+            .sum()
+    }
+
     /// Create index for key-value pairs stored in a disk.  
-    /// Assume `pairs` is sorted.  
-    pub fn new(pairs: Vec<InternalPair>, block_stride: usize) -> Self {
+    /// Assume `pairs` is sorted and encoded in `version`.  
+    pub fn new(pairs: &[InternalPair], block_stride: usize, version: u32) -> Self {
+        Self::build(pairs, block_stride, version).0
+    }
+
+    /// Encode `pairs` into blocks in `version` and return index for them and the encoded blocks.
+    /// All versions of a key are put in the same block so that a lookup reads only one block.
+    pub fn build(pairs: &[InternalPair], block_stride: usize, version: u32) -> (Self, Vec<u8>) {
         let mut items = Vec::new();
         let mut read_data = Vec::new();
 
-        for pair_chunk in pairs.chunks(block_stride) {
+        let mut rest = pairs;
+        while !rest.is_empty() {
+            let mut length = block_stride.max(1).min(rest.len());
+            while length < rest.len() && rest[length].key == rest[length - 1].key {
+                length += 1;
+            }
+            let (pair_chunk, next) = rest.split_at(length);
+            rest = next;
             let mut block = Block::new(&pair_chunk[0].key, read_data.len(), 0);
-            let mut block_data = InternalPair::serialize_flatten(pair_chunk);
+            let mut block_data = format::encode_block(pair_chunk, version);
             block.set_length(block_data.len());
             items.push(block);
             read_data.append(&mut block_data);
         }
-        Self { items }
+        (Self { items }, read_data)
+    }
+
+    /// Encode the index as a block to be persisted in an SSTable file.
+    ///
+    /// Each `Block` is laid out in the block as follows:
+    /// ```text
+    /// +-------------------+-----+------------------+----------------+
+    /// | key length(8byte) | key | position(8byte)  | length(8byte)  |
+    /// +-------------------+-----+------------------+----------------+
+    /// ```
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for block in self.items.iter() {
+            data.extend_from_slice(&(block.key.len() as u64).to_le_bytes());
+            data.extend_from_slice(&block.key);
+            data.extend_from_slice(&(block.position as u64).to_le_bytes());
+            data.extend_from_slice(&(block.length as u64).to_le_bytes());
+        }
+        format::frame_block(data)
+    }
+
+    /// Decode contents of a block encoded by `encode()`, which is already verified.
+    pub fn decode(mut bytes: &[u8]) -> Result<Self, Corruption> {
+        let mut items = Vec::new();
+        while !bytes.is_empty() {
+            let key_length = read_u64(&mut bytes)? as usize;
+            if bytes.len() < key_length {
+                return Err(Corruption(format!("Invalid key length: {}", key_length)));
+            }
+            let (key, rest) = bytes.split_at(key_length);
+            bytes = rest;
+            let position = read_u64(&mut bytes)? as usize;
+            let length = read_u64(&mut bytes)? as usize;
+            items.push(Block::new(key, position, length));
+        }
+        Ok(Self { items })
+    }
+
+    /// Positions and lengths of all blocks in order of their keys.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.items
+            .iter()
+            .map(|block| (block.position, block.length))
+    }
+
+    /// Position and length of the `n`th block.
+    pub fn block(&self, n: usize) -> Option<(usize, usize)> {
+        self.items
+            .get(n)
+            .map(|block| (block.position, block.length))
+    }
+
+    /// Ordinal of the first block which may contain `key` or keys greater than it.
+    pub fn seek(&self, key: &[u8]) -> usize {
+        match self
+            .items
+            .binary_search_by_key(&key, |entry| entry.key.as_slice())
+        {
+            Ok(n) => n,
+            Err(n) => n.saturating_sub(1),
+        }
+    }
+
+    /// Ordinal of the last block which may contain `key` or keys less than it.
+    /// Return `None` if all keys in the index are greater than `key`.
+    pub fn seek_last(&self, key: &[u8]) -> Option<usize> {
+        match self
+            .items
+            .binary_search_by_key(&key, |entry| entry.key.as_slice())
+        {
+            Ok(n) => Some(n),
+            Err(n) => n.checked_sub(1),
+        }
+    }
+
+    /// Ordinal of the last block.
+    pub fn last(&self) -> Option<usize> {
+        self.items.len().checked_sub(1)
     }
 
     /// Get a position of a key(`pair.key`) in a SSTable file.
@@ -78,9 +181,20 @@ impl Index {
     }
 }
 
+/// Read a little-endian `u64` at the front of `bytes` and advance it.
+fn read_u64(bytes: &mut &[u8]) -> Result<u64, Corruption> {
+    if bytes.len() < 8 {
+        return Err(Corruption("Truncated index".to_string()));
+    }
+    let (number, rest) = bytes.split_at(8);
+    *bytes = rest;
+    Ok(u64::from_le_bytes(number.try_into().unwrap()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::format::FORMAT_VERSION;
 
     #[test]
     fn index_creation() {
@@ -102,15 +216,15 @@ mod tests {
             InternalPair::new(b"abc14", None),
             InternalPair::new(b"abc15", None),
         ];
-        let index = Index::new(pairs, 3);
+        let index = Index::new(&pairs, 3, FORMAT_VERSION);
         assert_eq!(
             vec![
-                Block::new(&[97, 98, 99, 48, 48], 0, 72),
-                Block::new(&[97, 98, 99, 48, 51], 72, 79),
-                Block::new(&[97, 98, 99, 48, 54], 151, 71),
-                Block::new(&[97, 98, 99, 48, 57], 222, 63),
-                Block::new(&[97, 98, 99, 49, 50], 285, 63),
-                Block::new(&[97, 98, 99, 49, 53], 348, 21),
+                Block::new(&[97, 98, 99, 48, 48], 0, 111),
+                Block::new(&[97, 98, 99, 48, 51], 111, 118),
+                Block::new(&[97, 98, 99, 48, 54], 229, 110),
+                Block::new(&[97, 98, 99, 48, 57], 339, 102),
+                Block::new(&[97, 98, 99, 49, 50], 441, 102),
+                Block::new(&[97, 98, 99, 49, 53], 543, 42),
             ],
             index.items
         );
@@ -136,10 +250,63 @@ mod tests {
             InternalPair::new(b"abc14", None),
             InternalPair::new(b"abc15", None),
         ];
-        let index = Index::new(pairs, 3);
+        let index = Index::new(&pairs, 3, FORMAT_VERSION);
         assert_eq!(None, index.get(b"a"));
-        assert_eq!(Some((0, 72)), index.get(b"abc01"));
-        assert_eq!(Some((72, 79)), index.get(b"abc03"));
-        assert_eq!(Some((348, 21)), index.get(b"abc15"));
+        assert_eq!(Some((0, 111)), index.get(b"abc01"));
+        assert_eq!(Some((111, 118)), index.get(b"abc03"));
+        assert_eq!(Some((543, 42)), index.get(b"abc15"));
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc01", Some(b"defg")),
+            InternalPair::new(b"abc02", None),
+            InternalPair::new("日本語".as_bytes(), Some(b"")),
+        ];
+        let index = Index::new(&pairs, 3, FORMAT_VERSION);
+        let block = index.encode();
+        let data = format::verify_block(&block, FORMAT_VERSION).unwrap();
+        assert_eq!(index.items, Index::decode(data).unwrap().items);
+        assert!(Index::decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn keep_versions_in_a_block() {
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"new")).with_sequence(3),
+            InternalPair::new(b"abc01", Some(b"new")).with_sequence(4),
+            InternalPair::new(b"abc01", Some(b"old")).with_sequence(2),
+            InternalPair::new(b"abc01", None).with_sequence(1),
+            InternalPair::new(b"abc02", Some(b"def")).with_sequence(5),
+        ];
+        let index = Index::new(&pairs, 2, FORMAT_VERSION);
+        assert_eq!(
+            vec![b"abc00".to_vec(), b"abc02".to_vec()],
+            index
+                .items
+                .iter()
+                .map(|block| block.key.clone())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn seek_blocks() {
+        let pairs: Vec<_> = (0..6)
+            .map(|i| InternalPair::new(format!("abc{:02}", i * 2).as_bytes(), None))
+            .collect();
+        // Blocks start with abc00, abc04 and abc08.
+        let index = Index::new(&pairs, 2, FORMAT_VERSION);
+        assert_eq!(0, index.seek(b"a"));
+        assert_eq!(0, index.seek(b"abc03"));
+        assert_eq!(1, index.seek(b"abc04"));
+        assert_eq!(2, index.seek(b"abc99"));
+        assert_eq!(None, index.seek_last(b"a"));
+        assert_eq!(Some(0), index.seek_last(b"abc03"));
+        assert_eq!(Some(1), index.seek_last(b"abc04"));
+        assert_eq!(Some(2), index.seek_last(b"abc99"));
+        assert_eq!(Some(2), index.last());
     }
 }
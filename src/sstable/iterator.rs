@@ -0,0 +1,361 @@
+use super::table::SSTable;
+use crate::format::InternalPair;
+use std::collections::VecDeque;
+use std::io;
+
+/// Iterator over pairs in an SSTable in order of keys, or in reverse order.
+/// Blocks are read from the file one by one when pairs in the previous block are consumed.
+pub struct TableIterator<'a> {
+    table: &'a mut SSTable,
+
+    /// Ordinal of the block to be read next. `None` if no block is left.
+    next_block: Option<usize>,
+
+    /// Pairs read from the file but not yet returned.
+    pairs: VecDeque<InternalPair>,
+
+    /// Whether pairs are returned in descending order of keys.
+    reverse: bool,
+}
+
+impl<'a> TableIterator<'a> {
+    /// Create an iterator which starts from the first key not less than `start`.
+    pub async fn new(table: &'a mut SSTable, start: &[u8]) -> io::Result<TableIterator<'a>> {
+        let next_block = Some(table.index.seek(start));
+        let mut iterator = Self {
+            table,
+            next_block,
+            pairs: VecDeque::new(),
+            reverse: false,
+        };
+        iterator.fill().await?;
+        while let Some(pair) = iterator.pairs.front() {
+            if pair.key.as_slice() >= start {
+                break;
+            }
+            iterator.pairs.pop_front();
+            iterator.fill().await?;
+        }
+        Ok(iterator)
+    }
+
+    /// Create an iterator which starts from the last key less than `end` and returns pairs in
+    /// descending order of keys.
+    /// If `end` is `None`, it starts from the last key in the table.
+    pub async fn new_reverse(
+        table: &'a mut SSTable,
+        end: Option<&[u8]>,
+    ) -> io::Result<TableIterator<'a>> {
+        let next_block = match end {
+            Some(end) => table.index.seek_last(end),
+            None => table.index.last(),
+        };
+        let mut iterator = Self {
+            table,
+            next_block,
+            pairs: VecDeque::new(),
+            reverse: true,
+        };
+        iterator.fill().await?;
+        if let Some(end) = end {
+            while let Some(pair) = iterator.pairs.back() {
+                if pair.key.as_slice() < end {
+                    break;
+                }
+                iterator.pairs.pop_back();
+                iterator.fill().await?;
+            }
+        }
+        Ok(iterator)
+    }
+
+    /// Return the next pair, or `None` if all pairs are consumed.
+    pub async fn next(&mut self) -> io::Result<Option<InternalPair>> {
+        self.fill().await?;
+        if self.reverse {
+            Ok(self.pairs.pop_back())
+        } else {
+            Ok(self.pairs.pop_front())
+        }
+    }
+
+    /// Read blocks until there is a pair to return or no block is left.
+    async fn fill(&mut self) -> io::Result<()> {
+        while self.pairs.is_empty() {
+            let n = match self.next_block {
+                Some(n) => n,
+                None => return Ok(()),
+            };
+            let (position, length) = match self.table.index.block(n) {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            self.pairs = self.table.read_block(position, length).await?.into();
+            self.next_block = if self.reverse {
+                n.checked_sub(1)
+            } else {
+                Some(n + 1)
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Iterator which merges pairs of multiple SSTables in order of keys.
+/// For each key, the newest version whose sequence number is not greater than `sequence` is
+/// returned. If some tables have versions of the same sequence number, the one in the newest
+/// table is returned. Keys which have no such version are skipped.
+/// Deleted pairs are also returned, so a caller can tell they hide older pairs.
+pub struct MergingIterator<'a> {
+    /// Iterators sorted from the newest table to the oldest one.
+    iterators: Vec<TableIterator<'a>>,
+
+    /// Current first pair of each iterator.
+    heads: Vec<Option<InternalPair>>,
+
+    /// Whether pairs are returned in descending order of keys.
+    /// All of `iterators` must iterate in the same direction.
+    reverse: bool,
+
+    /// Versions with greater sequence numbers are invisible.
+    sequence: u64,
+}
+
+impl<'a> MergingIterator<'a> {
+    /// Create an iterator over `iterators` sorted from the newest table to the oldest one,
+    /// which sees versions whose sequence numbers are not greater than `sequence`.
+    pub async fn new(
+        mut iterators: Vec<TableIterator<'a>>,
+        reverse: bool,
+        sequence: u64,
+    ) -> io::Result<MergingIterator<'a>> {
+        let mut heads = Vec::new();
+        for iterator in iterators.iter_mut() {
+            heads.push(iterator.next().await?);
+        }
+        Ok(Self {
+            iterators,
+            heads,
+            reverse,
+            sequence,
+        })
+    }
+
+    /// Return the pair with the smallest key among tables, or the largest one in reverse order.
+    pub async fn next(&mut self) -> io::Result<Option<InternalPair>> {
+        loop {
+            let mut first: Option<&[u8]> = None;
+            for pair in self.heads.iter().flatten() {
+                let precedes = match first {
+                    Some(key) if self.reverse => pair.key.as_slice() > key,
+                    Some(key) => pair.key.as_slice() < key,
+                    None => true,
+                };
+                if precedes {
+                    first = Some(&pair.key);
+                }
+            }
+            let key = match first {
+                Some(key) => key.to_vec(),
+                None => return Ok(None),
+            };
+            // Consume all versions of the key and keep the newest visible one.
+            // On a tie, the former one, which is in the newer table, is kept.
+            let mut newest: Option<InternalPair> = None;
+            for (i, head) in self.heads.iter_mut().enumerate() {
+                while let Some(pair) = head.take_if(|pair| pair.key == key) {
+                    let visible = pair.sequence <= self.sequence
+                        && newest
+                            .as_ref()
+                            .is_none_or(|newest| pair.sequence > newest.sequence);
+                    if visible {
+                        newest = Some(pair);
+                    }
+                    *head = self.iterators[i].next().await?;
+                }
+            }
+            if newest.is_some() {
+                return Ok(newest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn create_table(path: &str, pairs: &[InternalPair]) -> io::Result<SSTable> {
+        SSTable::create(0, path, pairs, 0, 2, 10, false).await
+    }
+
+    #[tokio::test]
+    async fn iterate_from_start() -> io::Result<()> {
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc02", None),
+            InternalPair::new(b"abc04", Some(b"ghi")),
+            InternalPair::new(b"abc06", Some(b"jkl")),
+            InternalPair::new(b"abc08", Some(b"mno")),
+        ];
+        let mut table = create_table("test_iterate_from_start", &pairs).await?;
+        for (start, skip) in [(&b""[..], 0), (b"abc02", 1), (b"abc03", 2), (b"abc08", 4)] {
+            let mut iterator = TableIterator::new(&mut table, start).await?;
+            for pair in pairs.iter().skip(skip) {
+                assert_eq!(Some(pair.clone()), iterator.next().await?);
+            }
+            assert_eq!(None, iterator.next().await?);
+        }
+        let mut iterator = TableIterator::new(&mut table, b"abc09").await?;
+        assert_eq!(None, iterator.next().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn iterate_backward_from_end() -> io::Result<()> {
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc02", None),
+            InternalPair::new(b"abc04", Some(b"ghi")),
+            InternalPair::new(b"abc06", Some(b"jkl")),
+            InternalPair::new(b"abc08", Some(b"mno")),
+        ];
+        let mut table = create_table("test_iterate_backward_from_end", &pairs).await?;
+        for (end, take) in [
+            (None, 5),
+            (Some(&b"abc99"[..]), 5),
+            (Some(b"abc08"), 4),
+            (Some(b"abc05"), 3),
+            (Some(b"abc04"), 2),
+            (Some(b"abc00"), 0),
+        ] {
+            let mut iterator = TableIterator::new_reverse(&mut table, end).await?;
+            for pair in pairs.iter().take(take).rev() {
+                assert_eq!(Some(pair.clone()), iterator.next().await?);
+            }
+            assert_eq!(None, iterator.next().await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merge_tables() -> io::Result<()> {
+        let mut old = create_table(
+            "test_merge_tables_old",
+            &[
+                InternalPair::new(b"abc00", Some(b"old")),
+                InternalPair::new(b"abc01", Some(b"old")),
+                InternalPair::new(b"abc03", Some(b"old")),
+            ],
+        )
+        .await?;
+        let mut new = create_table(
+            "test_merge_tables_new",
+            &[
+                InternalPair::new(b"abc01", None),
+                InternalPair::new(b"abc02", Some(b"new")),
+                InternalPair::new(b"abc03", Some(b"new")),
+            ],
+        )
+        .await?;
+        let iterators = vec![
+            TableIterator::new(&mut new, b"").await?,
+            TableIterator::new(&mut old, b"").await?,
+        ];
+        let mut iterator = MergingIterator::new(iterators, false, u64::MAX).await?;
+        let mut pairs = Vec::new();
+        while let Some(pair) = iterator.next().await? {
+            pairs.push(pair);
+        }
+        let expected = vec![
+            InternalPair::new(b"abc00", Some(b"old")),
+            InternalPair::new(b"abc01", None),
+            InternalPair::new(b"abc02", Some(b"new")),
+            InternalPair::new(b"abc03", Some(b"new")),
+        ];
+        assert_eq!(expected, pairs);
+
+        let iterators = vec![
+            TableIterator::new_reverse(&mut new, None).await?,
+            TableIterator::new_reverse(&mut old, None).await?,
+        ];
+        let mut iterator = MergingIterator::new(iterators, true, u64::MAX).await?;
+        let mut pairs = Vec::new();
+        while let Some(pair) = iterator.next().await? {
+            pairs.push(pair);
+        }
+        assert_eq!(expected.into_iter().rev().collect::<Vec<_>>(), pairs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merge_versions() -> io::Result<()> {
+        let mut old = create_table(
+            "test_merge_versions_old",
+            &[
+                InternalPair::new(b"abc00", Some(b"v1")).with_sequence(1),
+                InternalPair::new(b"abc01", Some(b"v3")).with_sequence(3),
+                InternalPair::new(b"abc01", Some(b"v2")).with_sequence(2),
+            ],
+        )
+        .await?;
+        let mut new = create_table(
+            "test_merge_versions_new",
+            &[
+                InternalPair::new(b"abc00", None).with_sequence(5),
+                InternalPair::new(b"abc01", Some(b"v6")).with_sequence(6),
+                InternalPair::new(b"abc02", Some(b"v4")).with_sequence(4),
+            ],
+        )
+        .await?;
+        for (sequence, expected) in [
+            (
+                u64::MAX,
+                vec![
+                    InternalPair::new(b"abc00", None).with_sequence(5),
+                    InternalPair::new(b"abc01", Some(b"v6")).with_sequence(6),
+                    InternalPair::new(b"abc02", Some(b"v4")).with_sequence(4),
+                ],
+            ),
+            (
+                4,
+                vec![
+                    InternalPair::new(b"abc00", Some(b"v1")).with_sequence(1),
+                    InternalPair::new(b"abc01", Some(b"v3")).with_sequence(3),
+                    InternalPair::new(b"abc02", Some(b"v4")).with_sequence(4),
+                ],
+            ),
+            (
+                2,
+                vec![
+                    InternalPair::new(b"abc00", Some(b"v1")).with_sequence(1),
+                    InternalPair::new(b"abc01", Some(b"v2")).with_sequence(2),
+                ],
+            ),
+        ] {
+            for reverse in [false, true] {
+                let iterators = if reverse {
+                    vec![
+                        TableIterator::new_reverse(&mut new, None).await?,
+                        TableIterator::new_reverse(&mut old, None).await?,
+                    ]
+                } else {
+                    vec![
+                        TableIterator::new(&mut new, b"").await?,
+                        TableIterator::new(&mut old, b"").await?,
+                    ]
+                };
+                let mut iterator = MergingIterator::new(iterators, reverse, sequence).await?;
+                let mut pairs = Vec::new();
+                while let Some(pair) = iterator.next().await? {
+                    pairs.push(pair);
+                }
+                if reverse {
+                    pairs.reverse();
+                }
+                assert_eq!(expected, pairs);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,70 @@
-use super::storage::PersistedFile;
+use super::cache::BlockCache;
+use super::iterator::{MergingIterator, TableIterator};
+use super::manifest::{Manifest, TableMeta, VersionEdit, COMPACTED_LEVEL, FLUSHED_LEVEL};
+use super::storage;
 use super::table::SSTable;
 use crate::command::Command;
-use crate::format::InternalPair;
+use crate::config::SyncMode;
+use crate::error::Error;
+use crate::format::{InternalPair, LEGACY_FORMAT_VERSION};
+use crate::snapshot;
+use crate::stall::{Backlog, WriteController};
 use crate::Message;
+use bincode::serialize;
 use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, MissedTickBehavior};
+
+/// Prefix of SSTable file names.
+const TABLE_FILE_PREFIX: &str = "table_";
+
+/// Interval to check whether tables pile up and need compaction while no flush arrives.
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counters of SSTable reads reported to operators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableStats {
+    /// Number of times a Bloom filter saved reading a table.
+    pub filter_useful: u64,
+
+    /// Number of lookups which found a block in the block cache.
+    pub block_cache_hits: u64,
+
+    /// Number of lookups which did not find a block in the block cache.
+    pub block_cache_misses: u64,
+}
 
 /// Manage multiple SSTable instances.
 /// All operation to an SSTalbe is taken via this struct.
 #[derive(Debug)]
 pub struct SSTableManager {
     /// Directory to store SSTable's files.
-    /// Files in the directory is sorted by thier name(like table_0, table_1, table_2...).
-    /// File with bigger number at the end of the file name is newer one.
+    /// SSTable files are named like table_0, table_1, table_2...
+    /// Which of them are live is recorded in `manifest`.
     table_directory: PathBuf,
 
+    /// Record of live SSTables and the number for the next SSTable file.
+    manifest: Manifest,
+
     /// Every `block_stride` pair, `SSTable` creates an index entry.
     block_stride: usize,
 
+    /// Number of bits for each key in Bloom filters of new SSTables.
+    bloom_bits_per_key: usize,
+
+    /// Number of times a Bloom filter told a key is not in a table, which saved reading it.
+    filter_useful: u64,
+
+    /// Cache of blocks shared by all tables.
+    block_cache: BlockCache,
+
     /// Array of SSTables this struct manages.
     /// Descending order by thier age (back elements is the newer).
     tables: Vec<SSTable>,
@@ -29,103 +72,310 @@ pub struct SSTableManager {
     /// Threshold to determine compaction should be acted.
     compaction_trigger_ratio: f64,
 
+    /// Whether SSTable files and the directory are synchronized to a disk.
+    sync_mode: SyncMode,
+
+    /// Gate of writes which is told the number and sizes of tables.
+    write_controller: Arc<WriteController>,
+
+    /// Snapshots live when the last flush arrived, which compaction outside flushes keeps.
+    /// Snapshots taken later see only the newest versions in tables.
+    snapshots: Vec<u64>,
+
     /// Receiver to receive command.
     command_rx: mpsc::Receiver<Message>,
 }
 
 impl SSTableManager {
     /// Open existing SSTable files.
+    /// Live tables are read from the manifest. If the directory has no manifest,
+    /// all files named like SSTables are opened in order of their numbers and a manifest is
+    /// created for them.
     pub async fn new<P: AsRef<Path>>(
         directory: P,
         block_stride: usize,
+        bloom_bits_per_key: usize,
         compaction_trigger_ratio: u64,
+        block_cache_size: usize,
+        sync_mode: SyncMode,
         command_rx: mpsc::Receiver<Message>,
     ) -> io::Result<Self> {
         let mut table_directory = PathBuf::new();
         table_directory.push(directory);
 
-        let mut paths: Vec<_> = fs::read_dir(&table_directory)?
-            .into_iter()
-            .filter_map(|path| path.ok())
-            .collect();
-        paths.sort_by_key(|path| path.path());
+        let removed = storage::remove_temporary_files(&table_directory).await?;
+        if removed > 0 {
+            info!("Removed {} incomplete SSTable files", removed);
+        }
+
+        let (metas, next_table_number) = match Manifest::load(&table_directory).await? {
+            Some(state) => state,
+            None => Self::discover_tables(&table_directory)?,
+        };
+        Self::remove_obsolete_tables(&table_directory, &metas)?;
+        // Rewrite the manifest to discard records which are no longer needed.
+        let manifest = Manifest::create(
+            &table_directory,
+            metas,
+            next_table_number,
+            sync_mode.syncs_files(),
+        )
+        .await?;
+
         let mut tables = Vec::new();
-        for path in paths.iter() {
-            tables.push(SSTable::open(path.path(), block_stride).await?)
+        for meta in manifest.tables() {
+            let path = Self::table_path(&table_directory, meta.number);
+            tables.push(SSTable::open(meta.number, path, meta.version, block_stride).await?)
         }
         let compaction_trigger_rate = compaction_trigger_ratio as f64 / 100.0;
 
         Ok(Self {
             table_directory,
+            manifest,
             block_stride,
+            bloom_bits_per_key,
+            filter_useful: 0,
+            block_cache: BlockCache::new(block_cache_size),
             tables,
             compaction_trigger_ratio: compaction_trigger_rate,
+            sync_mode,
+            write_controller: Arc::new(WriteController::default()),
+            snapshots: Vec::new(),
             command_rx,
         })
     }
 
+    /// Report tables to `write_controller` so that writes are slowed down if too many tables
+    /// pile up, and keep the block cache in its memory budget.
+    pub fn with_write_controller(mut self, write_controller: Arc<WriteController>) -> Self {
+        self.write_controller = write_controller;
+        self.report_backlog();
+        self.fit_memory_budget();
+        self
+    }
+
+    /// Tell `write_controller` the number of tables and bytes which compaction has not merged
+    /// into the oldest table, and memory their indexes use.
+    fn report_backlog(&self) {
+        // Memory is updated first because writes waiting for it are woken by `set_tables()`.
+        let indexes = self.tables.iter().map(SSTable::memory_usage).sum();
+        self.write_controller.memory_budget().set_indexes(indexes);
+        let backlog = self.backlog();
+        self.write_controller
+            .set_tables(backlog.tables, backlog.pending_compaction_bytes);
+    }
+
+    /// Tables and bytes which compaction has not merged into the oldest table.
+    fn backlog(&self) -> Backlog {
+        Backlog {
+            tables: self.tables.len(),
+            pending_compaction_bytes: self.tables.iter().skip(1).map(SSTable::get_size).sum(),
+            immutables: 0,
+        }
+    }
+
+    /// Evict blocks from the block cache if memory exceeds the budget, and report memory the
+    /// cache uses.
+    /// Blocks are evicted when the cache is used, so the cache may exceed the budget until the
+    /// next command arrives.
+    fn fit_memory_budget(&mut self) {
+        let memory_budget = self.write_controller.memory_budget();
+        memory_budget.set_block_cache(self.block_cache.usage());
+        if let Some(target) = memory_budget.block_cache_target() {
+            debug!("Evict the block cache to {} bytes", target);
+            self.block_cache.evict_to(target);
+            memory_budget.set_block_cache(self.block_cache.usage());
+        }
+    }
+
+    /// Find SSTable files in a directory without a manifest.
+    /// Return the tables sorted from the oldest to the newest and the next table number.
+    fn discover_tables(directory: &Path) -> io::Result<(Vec<TableMeta>, u64)> {
+        let mut numbers: Vec<_> = fs::read_dir(directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| parse_table_number(&entry.file_name().to_string_lossy()))
+            .collect();
+        numbers.sort_unstable();
+        let next_table_number = numbers.last().map_or(0, |number| number + 1);
+        let metas = numbers
+            .into_iter()
+            .map(|number| TableMeta {
+                // Tables created before the manifest was introduced.
+                version: LEGACY_FORMAT_VERSION,
+                ..TableMeta::new(number, FLUSHED_LEVEL)
+            })
+            .collect();
+        Ok((metas, next_table_number))
+    }
+
+    /// Remove SSTable files which are not live.
+    /// Such files are left when a crash happens after a table is written but before it is
+    /// recorded in the manifest, or after compaction is recorded but before its inputs are
+    /// removed.
+    fn remove_obsolete_tables(directory: &Path, metas: &[TableMeta]) -> io::Result<()> {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if let Some(number) = parse_table_number(&entry.file_name().to_string_lossy()) {
+                if metas.iter().all(|meta| meta.number != number) {
+                    info!("Remove obsolete SSTable file {:?}", entry.path());
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Create a new SSTable with given pairs.
     pub async fn create(&mut self, pairs: Vec<InternalPair>, size: usize) -> io::Result<()> {
-        let table_path = self.new_table_path();
-        let file = PersistedFile::new(table_path, &pairs).await?;
-        let table = SSTable::new(file, pairs, size, self.block_stride).unwrap();
+        let table = self.write_table(pairs, size).await?;
+        self.manifest
+            .log(vec![VersionEdit::AddTable(TableMeta::new(
+                table.number,
+                FLUSHED_LEVEL,
+            ))])
+            .await?;
         self.tables.push(table);
         Ok(())
     }
 
-    /// Generate a path name for a new SSTable.
-    fn new_table_path(&self) -> PathBuf {
-        let mut table_path = self.table_directory.clone();
-        table_path.push(format!("table_{}", self.tables.len()));
+    /// Write a new SSTable file with given pairs without registering it to the manifest.
+    async fn write_table(&mut self, pairs: Vec<InternalPair>, size: usize) -> io::Result<SSTable> {
+        let number = self.manifest.allocate_table_number();
+        let table_path = Self::table_path(&self.table_directory, number);
+        SSTable::create(
+            number,
+            table_path,
+            &pairs,
+            size,
+            self.block_stride,
+            self.bloom_bits_per_key,
+            self.sync_mode.syncs_files(),
+        )
+        .await
+    }
+
+    /// Generate a path name for a SSTable with `number`.
+    fn table_path(directory: &Path, number: u64) -> PathBuf {
+        let mut table_path = directory.to_path_buf();
+        table_path.push(format!("{}{}", TABLE_FILE_PREFIX, number));
         table_path
     }
 
     /// Listen to channel to receive instruction to get data or create a new table with flushed
     /// data.
+    /// Tables are also checked periodically, so that they are compacted when they pile up even
+    /// if writes are stopped and no flush arrives.
     pub async fn listen(&mut self) {
+        let mut compaction_check = time::interval(COMPACTION_CHECK_INTERVAL);
+        compaction_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
-            match self.command_rx.recv().await {
-                Some((command, tx)) => match command {
-                    Command::Get { key } => {
-                        let entry = self
-                            .get(&key)
-                            .await
-                            .unwrap()
-                            .map(|pair| pair.value)
-                            .flatten();
-                        if tx.send(entry).is_err() {
-                            warn!("The receiver already dropped");
-                        }
+            tokio::select! {
+                message = self.command_rx.recv() => match message {
+                    Some((command, tx)) => self.handle(command, tx).await,
+                    None => warn!("The channel disconnected"),
+                },
+                _ = compaction_check.tick() => {
+                    let snapshots = self.snapshots.clone();
+                    if let Err(err) = self.compact(&snapshots).await {
+                        warn!("{}", err);
                     }
-                    // If `Command` does not include `Flush`
-                    // * when this loop waits for an instruction to get a content or flush with
-                    // async channel, contents in one of the two channel will never be received.
-                    // * with sync channel, `Handler::apply()` does not wait for sending back
-                    // result from here to receive it. This results in missing key-value pair which
-                    // actually exists.
-                    Command::Flush { pairs, size } => {
-                        if let Err(err) = self.create(pairs, size).await {
-                            warn!("{}", err);
-                        }
-                        if let Err(err) = self.compact().await {
-                            warn!("{}", err);
-                        }
-                        // Just notify flush completion.
-                        if tx.send(None).is_err() {
-                            warn!("The receiver already dropped");
-                        }
+                    self.report_backlog();
+                }
+            }
+            self.fit_memory_budget();
+        }
+    }
+
+    /// Apply `command` and send back its result to `tx`.
+    async fn handle(
+        &mut self,
+        command: Command,
+        tx: oneshot::Sender<Result<Option<Vec<u8>>, Error>>,
+    ) {
+        match command {
+            Command::Get { key, snapshot } => {
+                let entry = self
+                    .get_at(&key, snapshot.unwrap_or(u64::MAX))
+                    .await
+                    .map(|pair| pair.and_then(|pair| pair.value))
+                    .map_err(Error::from);
+                if tx.send(entry).is_err() {
+                    warn!("The receiver already dropped");
+                }
+            }
+            Command::Scan {
+                start,
+                end,
+                limit,
+                reverse,
+                snapshot,
+            } => {
+                let sequence = snapshot.unwrap_or(u64::MAX);
+                let pairs = self
+                    .scan_at(&start, end.as_deref(), limit, reverse, sequence)
+                    .await
+                    .map(|pairs| Some(InternalPair::serialize_flatten(&pairs)))
+                    .map_err(Error::from);
+                if tx.send(pairs).is_err() {
+                    warn!("The receiver already dropped");
+                }
+            }
+            // If `Command` does not include `Flush`
+            // * when this loop waits for an instruction to get a content or flush with
+            // async channel, contents in one of the two channel will never be received.
+            // * with sync channel, `Handler::apply()` does not wait for sending back
+            // result from here to receive it. This results in missing key-value pair which
+            // actually exists.
+            Command::Flush {
+                pairs,
+                size,
+                snapshots,
+            } => {
+                // `MemTable` discards flushed contents only if the SSTable is created.
+                let result = match self.create(pairs, size).await {
+                    Ok(()) => Ok(None),
+                    Err(err) => {
+                        warn!("{}", err);
+                        Err(Error::from(err))
                     }
-                    _ => (),
-                },
-                None => warn!("The channel disconnected"),
+                };
+                if let Err(err) = self.compact(&snapshots).await {
+                    warn!("{}", err);
+                }
+                self.snapshots = snapshots;
+                self.report_backlog();
+                // Notify flush completion.
+                if tx.send(result).is_err() {
+                    warn!("The receiver already dropped");
+                }
+            }
+            Command::Stats => {
+                let stats = serialize(&self.stats()).map_err(|err| Error::Io(err.to_string()));
+                if tx.send(stats.map(Some)).is_err() {
+                    warn!("The receiver already dropped");
+                }
             }
+            _ => (),
         }
     }
 
     /// Get a pair by given key from SSTables.
     pub async fn get(&mut self, key: &[u8]) -> io::Result<Option<InternalPair>> {
+        self.get_at(key, u64::MAX).await
+    }
+
+    /// Get the newest version of a pair by given key whose sequence number is not greater than
+    /// `sequence` from SSTables.
+    /// Versions in a newer table are newer than ones in older tables, so the first version
+    /// found from the newest table is returned.
+    /// Tables whose Bloom filter tells the key is absent are skipped without reading them.
+    pub async fn get_at(&mut self, key: &[u8], sequence: u64) -> io::Result<Option<InternalPair>> {
         for table in self.tables.iter_mut().rev() {
-            let pair = table.get(key).await?;
+            if !table.may_contain(key) {
+                self.filter_useful += 1;
+                continue;
+            }
+            let pair = table.get(key, sequence, &mut self.block_cache).await?;
             if pair.is_some() {
                 return Ok(pair);
             }
@@ -133,8 +383,89 @@ impl SSTableManager {
         Ok(None)
     }
 
+    /// Get at most `limit` pairs whose keys are in `[start, end)` from SSTables in order of keys,
+    /// or in descending order if `reverse` is `true`.
+    /// Deleted pairs are not returned.
+    pub async fn scan(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> io::Result<Vec<InternalPair>> {
+        self.scan_at(start, end, limit, reverse, u64::MAX).await
+    }
+
+    /// Same as `scan()` but reads the newest versions whose sequence numbers are not greater
+    /// than `sequence`.
+    pub async fn scan_at(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+        sequence: u64,
+    ) -> io::Result<Vec<InternalPair>> {
+        let mut iterators = Vec::new();
+        for table in self.tables.iter_mut().rev() {
+            let iterator = if reverse {
+                TableIterator::new_reverse(table, end).await?
+            } else {
+                TableIterator::new(table, start).await?
+            };
+            iterators.push(iterator);
+        }
+        let mut iterator = MergingIterator::new(iterators, reverse, sequence).await?;
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let pair = match iterator.next().await? {
+                Some(pair) => pair,
+                None => break,
+            };
+            let in_range = if reverse {
+                pair.key.as_slice() >= start
+            } else {
+                end.is_none_or(|end| pair.key.as_slice() < end)
+            };
+            if !in_range {
+                break;
+            }
+            if pair.value.is_some() {
+                pairs.push(pair);
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Number of times a Bloom filter saved reading a table.
+    pub fn filter_useful(&self) -> u64 {
+        self.filter_useful
+    }
+
+    /// Number of lookups which found a block in the block cache.
+    pub fn block_cache_hits(&self) -> u64 {
+        self.block_cache.hits()
+    }
+
+    /// Number of lookups which did not find a block in the block cache.
+    pub fn block_cache_misses(&self) -> u64 {
+        self.block_cache.misses()
+    }
+
+    /// Counters of reads to report to operators.
+    pub fn stats(&self) -> TableStats {
+        TableStats {
+            filter_useful: self.filter_useful,
+            block_cache_hits: self.block_cache.hits(),
+            block_cache_misses: self.block_cache.misses(),
+        }
+    }
+
     /// Compact current all SSTables into a new one if a criteria is met.
-    async fn compact(&mut self) -> io::Result<()> {
+    /// Versions seen by live `snapshots` are kept and other older versions are discarded.
+    /// Old tables are deleted only after the compacted table is durably written,
+    /// so that a crash during compaction does not lose any data.
+    async fn compact(&mut self, snapshots: &[u64]) -> io::Result<()> {
         let compacted_size = match self.should_compact() {
             Some(size) => size,
             None => {
@@ -143,18 +474,31 @@ impl SSTableManager {
         };
         info!("Compactions has started");
 
-        let mut tables = mem::replace(&mut self.tables, Vec::new());
         let mut table_iterators = Vec::new();
-        for table in tables.iter_mut().rev() {
+        for table in self.tables.iter_mut().rev() {
             let pairs = table.get_all().await?;
             table_iterators.push(pairs.into_iter());
         }
-        let pairs = Self::compact_inner(table_iterators);
+        let pairs = Self::compact_inner(table_iterators, snapshots);
 
+        let compacted_table = self.write_table(pairs, compacted_size).await?;
+        let mut edits: Vec<_> = self
+            .tables
+            .iter()
+            .map(|table| VersionEdit::RemoveTable(table.number))
+            .collect();
+        edits.push(VersionEdit::AddTable(TableMeta::new(
+            compacted_table.number,
+            COMPACTED_LEVEL,
+        )));
+        self.manifest.log(edits).await?;
+        let mut tables = mem::replace(&mut self.tables, vec![compacted_table]);
         for table in tables.iter_mut() {
             table.delete().await?;
         }
-        self.create(pairs, compacted_size).await?;
+        if self.sync_mode.syncs_files() {
+            storage::sync_directory(&self.table_directory).await?;
+        }
         Ok(())
     }
 
@@ -166,6 +510,8 @@ impl SSTableManager {
     /// Define `amplification_ratio` as (T1 + T2 + ... + Tn-1) / Tn.
     /// If `amplification_ratio` is greater than `self.compaction_trigger_rate`, compaction should
     /// be acted.
+    /// Compaction is also acted regardless of the ratio if tables reach the soft or the hard
+    /// limit of `write_controller`, because writes are slowed down or stopped until it runs.
     /// This functions returns a size of compacted SSTable.
     fn should_compact(&self) -> Option<usize> {
         let oldest_table_size = match self.tables.first() {
@@ -179,6 +525,14 @@ impl SSTableManager {
             .iter()
             .map(|table| table.get_size())
             .sum::<usize>();
+        let backlog = self.backlog();
+        if backlog.tables > 1
+            && (backlog.reaches(self.write_controller.soft_limit())
+                || backlog.reaches(self.write_controller.hard_limit()))
+        {
+            debug!("Tables reach the limit of writes: {:?}", backlog);
+            return Some(tables_total_size);
+        }
         let newer_tables_total_size = tables_total_size - oldest_table_size;
         let amplification_ratio = newer_tables_total_size as f64 / oldest_table_size as f64;
 
@@ -195,9 +549,12 @@ impl SSTableManager {
 
     /// Read SSTable elements one by one for each SSTable and hold them as `merge_candidate`.
     /// Select a minimum key of them to keep sorted order.
-    /// If there are multiple key of the same order, the newer one is selected.
+    /// All versions of the key are collected from the newer tables to the older ones, and the
+    /// newest version and ones seen by `snapshots` are kept from the newest to the oldest.
+    /// If there are versions of the same sequence number, the newer one is selected.
     fn compact_inner(
         mut table_iterators: Vec<impl Iterator<Item = InternalPair>>,
+        snapshots: &[u64],
     ) -> Vec<InternalPair> {
         // Array of current first elements for each SSTable.
         let mut merge_candidates = (0..table_iterators.len())
@@ -205,35 +562,40 @@ impl SSTableManager {
             .collect::<Vec<Option<InternalPair>>>();
 
         let mut pairs = Vec::new();
-        loop {
-            let min_pair = merge_candidates
-                .iter()
-                .filter(|pair| pair.is_some())
-                .min_by_key(|pair| &pair.as_ref().unwrap().key)
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .clone();
-            let min_key = min_pair.key.clone();
-            pairs.push(min_pair);
-            merge_candidates
-                .iter_mut()
-                .enumerate()
-                .for_each(|(i, pair_opt)| {
-                    if let Some(pair) = pair_opt {
-                        if pair.key == min_key {
-                            *pair_opt = table_iterators[i].next();
-                        }
-                    }
-                });
-            if merge_candidates.iter().all(|x| x.is_none()) {
-                break;
+        while let Some(min_key) = merge_candidates
+            .iter()
+            .flatten()
+            .map(|pair| &pair.key)
+            .min()
+            .cloned()
+        {
+            let mut versions = Vec::new();
+            for (i, pair_opt) in merge_candidates.iter_mut().enumerate() {
+                while let Some(pair) = pair_opt.take_if(|pair| pair.key == min_key) {
+                    versions.push(pair);
+                    *pair_opt = table_iterators[i].next();
+                }
+            }
+            // The sort is stable, so the version in the newer table comes first on a tie.
+            versions.sort_by_key(|pair| std::cmp::Reverse(pair.sequence));
+            let mut newer = None;
+            for pair in versions {
+                let sequence = pair.sequence;
+                if snapshot::is_visible_version(sequence, newer, snapshots) {
+                    pairs.push(pair);
+                }
+                newer = Some(sequence);
             }
         }
         pairs
     }
 }
 
+/// Extract the number from a file name of an SSTable like `table_42`.
+fn parse_table_number(file_name: &str) -> Option<u64> {
+    file_name.strip_prefix(TABLE_FILE_PREFIX)?.parse().ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,23 +604,31 @@ mod tests {
     #[tokio::test]
     async fn open_existing_files() -> io::Result<()> {
         let path = "test_open_existing_files";
-        let _ = std::fs::create_dir(path);
-        let data0 = InternalPair::serialize_flatten(&vec![
-            InternalPair::new(b"abc00", Some(b"def")),
-            InternalPair::new(b"abc01", Some(b"defg")),
-        ]);
-        let data1 = InternalPair::serialize_flatten(&vec![
-            InternalPair::new(b"abc00", Some(b"xyz")),
-            InternalPair::new(b"abc01", None),
-        ]);
-        let data2 =
-            InternalPair::serialize_flatten(&vec![InternalPair::new(b"abc02", Some(b"def"))]);
+        prepare_directory(path);
+        let data0 = InternalPair::serialize_flatten_with_version(
+            &[
+                InternalPair::new(b"abc00", Some(b"def")),
+                InternalPair::new(b"abc01", Some(b"defg")),
+            ],
+            LEGACY_FORMAT_VERSION,
+        );
+        let data1 = InternalPair::serialize_flatten_with_version(
+            &[
+                InternalPair::new(b"abc00", Some(b"xyz")),
+                InternalPair::new(b"abc01", None),
+            ],
+            LEGACY_FORMAT_VERSION,
+        );
+        let data2 = InternalPair::serialize_flatten_with_version(
+            &[InternalPair::new(b"abc02", Some(b"def"))],
+            LEGACY_FORMAT_VERSION,
+        );
         prepare_sstable_file("test_open_existing_files/table_0", &data0)?;
         prepare_sstable_file("test_open_existing_files/table_1", &data1)?;
         prepare_sstable_file("test_open_existing_files/table_2", &data2)?;
 
         let (_, crx) = mpsc::channel(4);
-        let mut manager = SSTableManager::new(path, 2, 1000, crx).await?;
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
         assert_eq!(
             InternalPair::new(b"abc00", Some(b"xyz")),
             manager.get(b"abc00").await?.unwrap()
@@ -277,9 +647,9 @@ mod tests {
     #[tokio::test]
     async fn get_pairs() -> io::Result<()> {
         let path = "test_get_create";
-        let _ = std::fs::create_dir(path);
+        prepare_directory(path);
         let (_, crx) = mpsc::channel(4);
-        let mut manager = SSTableManager::new(path, 2, 1000, crx).await?;
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
         manager
             .create(
                 vec![
@@ -354,15 +724,113 @@ mod tests {
             InternalPair::new(b"abc05", None),
         ];
         let table_iterators = tables.into_iter().map(|table| table.into_iter()).collect();
-        assert_eq!(expected, SSTableManager::compact_inner(table_iterators));
+        assert_eq!(
+            expected,
+            SSTableManager::compact_inner(table_iterators, &[])
+        );
+    }
+
+    #[test]
+    fn compaction_with_snapshots() {
+        let tables = [
+            vec![
+                InternalPair::new(b"abc00", Some(b"v1")).with_sequence(1),
+                InternalPair::new(b"abc01", Some(b"v2")).with_sequence(2),
+            ],
+            vec![
+                InternalPair::new(b"abc00", Some(b"v5")).with_sequence(5),
+                InternalPair::new(b"abc00", Some(b"v3")).with_sequence(3),
+            ],
+            vec![
+                InternalPair::new(b"abc00", None).with_sequence(7),
+                InternalPair::new(b"abc01", Some(b"v6")).with_sequence(6),
+            ],
+        ];
+        let compact = |snapshots: &[u64]| {
+            let table_iterators = tables
+                .iter()
+                .rev()
+                .map(|table| table.clone().into_iter())
+                .collect();
+            SSTableManager::compact_inner(table_iterators, snapshots)
+        };
+        // Only the newest versions are kept without snapshots.
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc00", None).with_sequence(7),
+                InternalPair::new(b"abc01", Some(b"v6")).with_sequence(6),
+            ],
+            compact(&[])
+        );
+        // A snapshot at 4 sees "v3" and "v2", and one at 6 sees "v5" and "v6".
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc00", None).with_sequence(7),
+                InternalPair::new(b"abc00", Some(b"v5")).with_sequence(5),
+                InternalPair::new(b"abc00", Some(b"v3")).with_sequence(3),
+                InternalPair::new(b"abc01", Some(b"v6")).with_sequence(6),
+                InternalPair::new(b"abc01", Some(b"v2")).with_sequence(2),
+            ],
+            compact(&[4, 6])
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_reads_after_compaction() -> io::Result<()> {
+        let path = "test_snapshot_reads_after_compaction";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(
+                vec![
+                    InternalPair::new(b"abc", Some(b"1")).with_sequence(1),
+                    InternalPair::new(b"xyz", Some(b"2")).with_sequence(2),
+                ],
+                8,
+            )
+            .await?;
+        manager
+            .create(vec![InternalPair::new(b"abc", None).with_sequence(3)], 3)
+            .await?;
+        manager.compact(&[2]).await?;
+        assert_eq!(1, manager.tables.len());
+        assert_eq!(None, manager.get(b"abc").await?.unwrap().value);
+        assert_eq!(
+            Some(InternalPair::new(b"abc", Some(b"1")).with_sequence(1)),
+            manager.get_at(b"abc", 2).await?
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc", Some(b"1")).with_sequence(1),
+                InternalPair::new(b"xyz", Some(b"2")).with_sequence(2),
+            ],
+            manager.scan_at(b"", None, 10, false, 2).await?
+        );
+        assert_eq!(
+            vec![InternalPair::new(b"xyz", Some(b"2")).with_sequence(2)],
+            manager.scan_at(b"", None, 10, false, 3).await?
+        );
+
+        // The old version is discarded once the snapshot is released.
+        manager
+            .create(
+                vec![InternalPair::new(b"xyz", Some(b"4")).with_sequence(4)],
+                4,
+            )
+            .await?;
+        manager.compact(&[]).await?;
+        assert_eq!(1, manager.tables.len());
+        assert_eq!(None, manager.get_at(b"abc", 2).await?);
+        Ok(())
     }
 
     #[tokio::test]
     async fn should_act_compact() -> io::Result<()> {
         let path = "test_should_act_compact";
-        let _ = std::fs::create_dir(path);
+        prepare_directory(path);
         let (_, crx) = mpsc::channel(4);
-        let mut manager = SSTableManager::new(path, 2, 25, crx).await?;
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
         manager
             .create(vec![InternalPair::new(b"0123", None)], 4)
             .await?;
@@ -380,9 +848,9 @@ mod tests {
     #[tokio::test]
     async fn should_not_act_compact() -> io::Result<()> {
         let path = "test_should_not_act_compact";
-        let _ = std::fs::create_dir(path);
+        prepare_directory(path);
         let (_, crx) = mpsc::channel(4);
-        let mut manager = SSTableManager::new(path, 2, 25, crx).await?;
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
         manager
             .create(vec![InternalPair::new(b"012345", None)], 6)
             .await?;
@@ -393,4 +861,317 @@ mod tests {
         assert_eq!(None, manager.should_compact());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn compact_at_backlog_limit() -> io::Result<()> {
+        let path = "test_compact_at_backlog_limit";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let soft_limit = Backlog {
+            tables: 3,
+            ..Backlog::UNLIMITED
+        };
+        let write_controller = Arc::new(WriteController::new(
+            soft_limit,
+            Backlog::UNLIMITED,
+            Duration::ZERO,
+            Duration::ZERO,
+        ));
+        let mut manager = SSTableManager::new(path, 2, 10, u64::MAX, 1024, SyncMode::None, crx)
+            .await?
+            .with_write_controller(write_controller);
+        for size in [6, 1] {
+            manager
+                .create(vec![InternalPair::new(b"0", None)], size)
+                .await?;
+        }
+        assert_eq!(None, manager.should_compact());
+        manager
+            .create(vec![InternalPair::new(b"0", None)], 1)
+            .await?;
+        assert_eq!(Some(8), manager.should_compact());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_tables() -> io::Result<()> {
+        let path = "test_compact_tables";
+        prepare_directory(path);
+        prepare_sstable_file("test_compact_tables/table_9.tmp", b"broken")?;
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::Always, crx).await?;
+        assert!(!Path::new("test_compact_tables/table_9.tmp").exists());
+        manager
+            .create(vec![InternalPair::new(b"abc00", Some(b"def"))], 8)
+            .await?;
+        manager
+            .create(vec![InternalPair::new(b"abc00", Some(b"xyz"))], 8)
+            .await?;
+        manager.compact(&[]).await?;
+
+        let mut file_names: Vec<_> = fs::read_dir(path)?
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        file_names.sort();
+        assert_eq!(vec!["MANIFEST", "table_2"], file_names);
+        drop(manager);
+
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::Always, crx).await?;
+        assert_eq!(
+            InternalPair::new(b"abc00", Some(b"xyz")),
+            manager.get(b"abc00").await?.unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_tables_in_number_order() -> io::Result<()> {
+        let path = "test_open_tables_in_number_order";
+        prepare_directory(path);
+        for number in 0..11 {
+            let data = InternalPair::serialize_flatten_with_version(
+                &[InternalPair::new(
+                    b"abc",
+                    Some(number.to_string().as_bytes()),
+                )],
+                LEGACY_FORMAT_VERSION,
+            );
+            prepare_sstable_file(format!("{}/table_{}", path, number), &data)?;
+        }
+        prepare_sstable_file(format!("{}/table_backup", path), b"not a table")?;
+
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        assert_eq!(
+            InternalPair::new(b"abc", Some(b"10")),
+            manager.get(b"abc").await?.unwrap()
+        );
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"11"))], 5)
+            .await?;
+        assert!(Path::new("test_open_tables_in_number_order/table_11").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reopen_with_manifest() -> io::Result<()> {
+        let path = "test_reopen_with_manifest";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"0"))], 4)
+            .await?;
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"1"))], 4)
+            .await?;
+        manager.compact(&[]).await?;
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"3"))], 4)
+            .await?;
+        drop(manager);
+        // An SSTable which is not recorded in the manifest, such as an output of an interrupted
+        // flush, is removed.
+        let data = InternalPair::serialize_flatten(&[InternalPair::new(b"abc", Some(b"4"))]);
+        prepare_sstable_file("test_reopen_with_manifest/table_4", &data)?;
+
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
+        assert!(!Path::new("test_reopen_with_manifest/table_4").exists());
+        assert_eq!(
+            vec![2, 3],
+            manager
+                .tables
+                .iter()
+                .map(|table| table.number)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            InternalPair::new(b"abc", Some(b"3")),
+            manager.get(b"abc").await?.unwrap()
+        );
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"5"))], 4)
+            .await?;
+        assert!(Path::new("test_reopen_with_manifest/table_4").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_tables_with_broken_manifest() -> io::Result<()> {
+        let path = "test_keep_tables_with_broken_manifest";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(vec![InternalPair::new(b"abc", Some(b"0"))], 4)
+            .await?;
+        drop(manager);
+        // Break the first record, which lists live tables.
+        let manifest_path = Path::new(path).join("MANIFEST");
+        let mut manifest = fs::read(&manifest_path)?;
+        manifest[12] ^= 1;
+        fs::write(&manifest_path, manifest)?;
+
+        let (_, crx) = mpsc::channel(4);
+        let err = SSTableManager::new(path, 2, 10, 25, 1024, SyncMode::None, crx)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(Path::new(path).join("table_0").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_empty_value() -> io::Result<()> {
+        let path = "test_keep_empty_value";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(
+                vec![
+                    InternalPair::new(b"abc00", Some(b"")),
+                    InternalPair::new(b"abc01", None),
+                ],
+                10,
+            )
+            .await?;
+        drop(manager);
+
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        assert_eq!(
+            InternalPair::new(b"abc00", Some(b"")),
+            manager.get(b"abc00").await?.unwrap()
+        );
+        assert_eq!(
+            InternalPair::new(b"abc01", None),
+            manager.get(b"abc01").await?.unwrap()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_tables_by_filter() -> io::Result<()> {
+        let path = "test_skip_tables_by_filter";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        for i in 0..3 {
+            let key = format!("abc{:02}", i);
+            manager
+                .create(vec![InternalPair::new(key.as_bytes(), Some(b"def"))], 8)
+                .await?;
+        }
+        assert_eq!(
+            InternalPair::new(b"abc00", Some(b"def")),
+            manager.get(b"abc00").await?.unwrap()
+        );
+        // Newer two tables do not have the key.
+        assert_eq!(2, manager.filter_useful());
+        assert_eq!(None, manager.get(b"xyz").await?);
+        assert_eq!(5, manager.filter_useful());
+        drop(manager);
+
+        // Filters are loaded from files.
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 0, 1000, 1024, SyncMode::None, crx).await?;
+        assert_eq!(None, manager.get(b"xyz").await?);
+        assert_eq!(3, manager.filter_useful());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_blocks() -> io::Result<()> {
+        let path = "test_cache_blocks";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(
+                vec![
+                    InternalPair::new(b"abc00", Some(b"def")),
+                    InternalPair::new(b"abc01", Some(b"xyz")),
+                    InternalPair::new(b"abc02", Some(b"ghi")),
+                ],
+                24,
+            )
+            .await?;
+        for _ in 0..3 {
+            assert_eq!(
+                InternalPair::new(b"abc01", Some(b"xyz")),
+                manager.get(b"abc01").await?.unwrap()
+            );
+        }
+        assert_eq!(
+            InternalPair::new(b"abc00", Some(b"def")),
+            manager.get(b"abc00").await?.unwrap()
+        );
+        assert_eq!(1, manager.block_cache_misses());
+        assert_eq!(3, manager.block_cache_hits());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scan_tables() -> io::Result<()> {
+        let path = "test_scan_tables";
+        prepare_directory(path);
+        let (_, crx) = mpsc::channel(4);
+        let mut manager = SSTableManager::new(path, 2, 10, 1000, 1024, SyncMode::None, crx).await?;
+        manager
+            .create(
+                vec![
+                    InternalPair::new(b"abc00", Some(b"old")),
+                    InternalPair::new(b"abc01", Some(b"old")),
+                    InternalPair::new(b"abc02", Some(b"old")),
+                    InternalPair::new(b"abc03", Some(b"old")),
+                ],
+                32,
+            )
+            .await?;
+        manager
+            .create(
+                vec![
+                    InternalPair::new(b"abc01", None),
+                    InternalPair::new(b"abc02", Some(b"new")),
+                    InternalPair::new(b"abc04", Some(b"new")),
+                ],
+                21,
+            )
+            .await?;
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc00", Some(b"old")),
+                InternalPair::new(b"abc02", Some(b"new")),
+                InternalPair::new(b"abc03", Some(b"old")),
+                InternalPair::new(b"abc04", Some(b"new")),
+            ],
+            manager.scan(b"", None, 10, false).await?
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc02", Some(b"new")),
+                InternalPair::new(b"abc03", Some(b"old")),
+            ],
+            manager.scan(b"abc01", Some(b"abc04"), 10, false).await?
+        );
+        assert_eq!(
+            vec![InternalPair::new(b"abc00", Some(b"old"))],
+            manager.scan(b"", None, 1, false).await?
+        );
+        assert_eq!(
+            vec![
+                InternalPair::new(b"abc03", Some(b"old")),
+                InternalPair::new(b"abc02", Some(b"new")),
+            ],
+            manager.scan(b"abc01", Some(b"abc04"), 10, true).await?
+        );
+        assert_eq!(
+            vec![InternalPair::new(b"abc04", Some(b"new"))],
+            manager.scan(b"", None, 1, true).await?
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,417 @@
+use super::storage;
+use crate::error::Corruption;
+use crate::format::{frame_block, verify_block, FORMAT_VERSION};
+use bincode::{deserialize, serialize};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+/// Name of the manifest file in the data directory.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// Bytes at the head of a manifest whose records have checksums.
+/// Read as the length of a record, they are far longer than any manifest, so they are
+/// distinguished from a manifest written before checksums were introduced.
+const MAGIC: &[u8; 8] = b"HRMMNFST";
+
+/// Length of the length of edits at the head of a record.
+const RECORD_HEADER_LENGTH: usize = 8;
+
+/// Length of the checksum at the end of a record.
+const RECORD_TRAILER_LENGTH: usize = 4;
+
+/// Level of a table created by flushing `MemTable`.
+pub const FLUSHED_LEVEL: u32 = 0;
+
+/// Level of a table created by compaction.
+pub const COMPACTED_LEVEL: u32 = 1;
+
+/// Metadata of a live SSTable.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TableMeta {
+    /// Number in the file name of the table.
+    pub number: u64,
+
+    /// Tables in a higher level hold older data.
+    /// Among tables in the same level, one with a bigger number is newer.
+    pub level: u32,
+
+    /// Version of the encoding of pairs in the table file.
+    pub version: u32,
+}
+
+impl TableMeta {
+    /// Metadata of a table written in the current format.
+    pub fn new(number: u64, level: u32) -> Self {
+        Self {
+            number,
+            level,
+            version: FORMAT_VERSION,
+        }
+    }
+}
+
+/// A change to the set of live SSTables.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum VersionEdit {
+    AddTable(TableMeta),
+    RemoveTable(u64),
+    SetNextTableNumber(u64),
+}
+
+/// Log of `VersionEdit`s which records which SSTable files are live.
+/// A group of edits is appended as one record and applied atomically.
+///
+/// The manifest starts with `MAGIC`, and each record is laid out as follows:
+/// ```text
+/// +------------------------+--------------------------------+----------------+
+/// | length of edits(8byte) | serialized `Vec<VersionEdit>`  | CRC-32C(4byte) |
+/// +------------------------+--------------------------------+----------------+
+/// ```
+/// A manifest without `MAGIC` was written before records had checksums, and its records have
+/// no checksum. It is rewritten in the current layout when it is opened.
+#[derive(Debug)]
+pub struct Manifest {
+    /// Manifest file opened for appending records.
+    file: File,
+
+    /// Live tables sorted from the oldest to the newest.
+    tables: Vec<TableMeta>,
+
+    /// Number for the name of a SSTable file created next.
+    /// This is never reused even after tables are removed by compaction.
+    next_table_number: u64,
+
+    /// Whether records are synchronized to a disk.
+    sync: bool,
+}
+
+impl Manifest {
+    /// Read the manifest in `directory` and return live tables and the next table number.
+    /// Return `None` if there is no manifest.
+    /// Only the last record may be broken by a crash in the middle of appending, and it is
+    /// discarded. A broken record anywhere else, or in the first record, which is written
+    /// atomically, is an error, because missing tables would be deleted as obsolete.
+    pub async fn load<P: AsRef<Path>>(directory: P) -> io::Result<Option<(Vec<TableMeta>, u64)>> {
+        let mut buffer = Vec::new();
+        match File::open(manifest_path(directory)).await {
+            Ok(mut file) => file.read_to_end(&mut buffer).await?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let (mut reader, checksummed) = match buffer.strip_prefix(MAGIC) {
+            Some(records) => (records, true),
+            None => (buffer.as_slice(), false),
+        };
+        let mut tables = Vec::new();
+        let mut next_table_number = 0;
+        let mut is_first = true;
+        while !reader.is_empty() {
+            match read_record(reader, checksummed) {
+                Ok((edits, length)) => {
+                    for edit in edits {
+                        apply(&mut tables, &mut next_table_number, edit);
+                    }
+                    reader = &reader[length..];
+                }
+                Err(Corruption(message))
+                    if !is_first
+                        && record_length(reader, checksummed)
+                            .is_none_or(|length| length >= reader.len()) =>
+                {
+                    // A record being appended when a crash happened.
+                    warn!(
+                        "Discard a broken record at the end of the manifest: {}",
+                        message
+                    );
+                    break;
+                }
+                Err(Corruption(message)) => {
+                    return Err(
+                        Corruption(format!("Broken record in the manifest: {}", message)).into(),
+                    );
+                }
+            }
+            is_first = false;
+        }
+        sort_tables(&mut tables);
+        Ok(Some((tables, next_table_number)))
+    }
+
+    /// Create a new manifest in `directory` which holds the given state.
+    /// An existing manifest is atomically replaced, which also discards its old records.
+    pub async fn create<P: AsRef<Path>>(
+        directory: P,
+        tables: Vec<TableMeta>,
+        next_table_number: u64,
+        sync: bool,
+    ) -> io::Result<Self> {
+        let path = manifest_path(&directory);
+        let temporary_path = storage::temporary_path(&path);
+        let mut edits = vec![VersionEdit::SetNextTableNumber(next_table_number)];
+        edits.extend(tables.iter().map(|meta| VersionEdit::AddTable(*meta)));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&temporary_path)
+            .await?;
+        file.write_all(MAGIC).await?;
+        file.write_all(&encode_record(&edits)).await?;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        fs::rename(&temporary_path, &path).await?;
+        if sync {
+            storage::sync_directory(&directory).await?;
+        }
+
+        let mut tables = tables;
+        sort_tables(&mut tables);
+        Ok(Self {
+            file: OpenOptions::new().append(true).open(&path).await?,
+            tables,
+            next_table_number,
+            sync,
+        })
+    }
+
+    /// Live tables sorted from the oldest to the newest.
+    pub fn tables(&self) -> &[TableMeta] {
+        &self.tables
+    }
+
+    /// Reserve a number for a new table file.
+    /// The reservation is recorded with the next `log()` call.
+    pub fn allocate_table_number(&mut self) -> u64 {
+        let number = self.next_table_number;
+        self.next_table_number += 1;
+        number
+    }
+
+    /// Record `edits` and apply them to the live tables.
+    pub async fn log(&mut self, mut edits: Vec<VersionEdit>) -> io::Result<()> {
+        edits.push(VersionEdit::SetNextTableNumber(self.next_table_number));
+        self.file.write_all(&encode_record(&edits)).await?;
+        self.file.flush().await?;
+        if self.sync {
+            self.file.sync_data().await?;
+        }
+        for edit in edits {
+            apply(&mut self.tables, &mut self.next_table_number, edit);
+        }
+        sort_tables(&mut self.tables);
+        Ok(())
+    }
+}
+
+/// Path to the manifest in `directory`.
+fn manifest_path<P: AsRef<Path>>(directory: P) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(directory);
+    path.push(MANIFEST_FILE_NAME);
+    path
+}
+
+/// Apply an edit to the state of a manifest.
+fn apply(tables: &mut Vec<TableMeta>, next_table_number: &mut u64, edit: VersionEdit) {
+    match edit {
+        VersionEdit::AddTable(meta) => tables.push(meta),
+        VersionEdit::RemoveTable(number) => tables.retain(|meta| meta.number != number),
+        VersionEdit::SetNextTableNumber(number) => *next_table_number = number,
+    }
+}
+
+/// Sort tables from the oldest to the newest.
+fn sort_tables(tables: &mut [TableMeta]) {
+    tables.sort_by(|a, b| b.level.cmp(&a.level).then(a.number.cmp(&b.number)));
+}
+
+fn encode_record(edits: &[VersionEdit]) -> Vec<u8> {
+    frame_block(serialize(edits).unwrap())
+}
+
+/// Length of the record at the front of `bytes` which its header tells, or `None` if the
+/// header is truncated.
+fn record_length(bytes: &[u8], checksummed: bool) -> Option<usize> {
+    let header = bytes.get(..RECORD_HEADER_LENGTH)?;
+    let edits_length = u64::from_le_bytes(header.try_into().unwrap());
+    let trailer_length = if checksummed {
+        RECORD_TRAILER_LENGTH
+    } else {
+        0
+    };
+    (edits_length as usize).checked_add(RECORD_HEADER_LENGTH + trailer_length)
+}
+
+/// Decode a record at the front of `bytes` and return edits in it and the length of the record.
+/// The checksum of the record is verified if `checksummed` is `true`.
+fn read_record(bytes: &[u8], checksummed: bool) -> Result<(Vec<VersionEdit>, usize), Corruption> {
+    let length = record_length(bytes, checksummed)
+        .filter(|length| *length <= bytes.len())
+        .ok_or_else(|| Corruption("Truncated record".to_string()))?;
+    let content = if checksummed {
+        verify_block(&bytes[..length], FORMAT_VERSION)?
+    } else {
+        &bytes[RECORD_HEADER_LENGTH..length]
+    };
+    let edits = deserialize(content).map_err(|err| Corruption(err.to_string()))?;
+    Ok((edits, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sstable::tests::prepare_directory;
+
+    #[tokio::test]
+    async fn no_manifest() -> io::Result<()> {
+        let directory = "test_no_manifest";
+        prepare_directory(directory);
+        assert_eq!(None, Manifest::load(directory).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn log_and_load() -> io::Result<()> {
+        let directory = "test_manifest_log_and_load";
+        prepare_directory(directory);
+        let mut manifest = Manifest::create(directory, vec![], 0, true).await?;
+        for _ in 0..3 {
+            let number = manifest.allocate_table_number();
+            manifest
+                .log(vec![VersionEdit::AddTable(TableMeta::new(
+                    number,
+                    FLUSHED_LEVEL,
+                ))])
+                .await?;
+        }
+        let number = manifest.allocate_table_number();
+        manifest
+            .log(vec![
+                VersionEdit::RemoveTable(0),
+                VersionEdit::RemoveTable(1),
+                VersionEdit::AddTable(TableMeta::new(number, COMPACTED_LEVEL)),
+            ])
+            .await?;
+        let expected = vec![
+            TableMeta::new(3, COMPACTED_LEVEL),
+            TableMeta::new(2, FLUSHED_LEVEL),
+        ];
+        assert_eq!(expected, manifest.tables());
+        drop(manifest);
+
+        assert_eq!(Some((expected, 4)), Manifest::load(directory).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discard_broken_record() -> io::Result<()> {
+        let directory = "test_manifest_discard_broken_record";
+        prepare_directory(directory);
+        let mut manifest = Manifest::create(directory, vec![], 0, false).await?;
+        let number = manifest.allocate_table_number();
+        manifest
+            .log(vec![VersionEdit::AddTable(TableMeta::new(
+                number,
+                FLUSHED_LEVEL,
+            ))])
+            .await?;
+        let record = encode_record(&[VersionEdit::RemoveTable(number)]);
+        manifest.file.write_all(&record[..record.len() - 1]).await?;
+        manifest.file.flush().await?;
+        drop(manifest);
+
+        assert_eq!(
+            Some((vec![TableMeta::new(0, FLUSHED_LEVEL)], 1)),
+            Manifest::load(directory).await?
+        );
+        Ok(())
+    }
+
+    /// Flip a bit of the manifest in `directory` at `position` from its end.
+    fn corrupt_from_end(directory: &str, position: usize) {
+        let path = manifest_path(directory);
+        let mut bytes = std::fs::read(&path).unwrap();
+        let index = bytes.len() - position;
+        bytes[index] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_broken_record() -> io::Result<()> {
+        let directory = "test_manifest_reject_broken_record";
+        prepare_directory(directory);
+        let mut manifest = Manifest::create(directory, vec![], 0, false).await?;
+        for _ in 0..2 {
+            let number = manifest.allocate_table_number();
+            manifest
+                .log(vec![VersionEdit::AddTable(TableMeta::new(
+                    number,
+                    FLUSHED_LEVEL,
+                ))])
+                .await?;
+        }
+        drop(manifest);
+        // A broken checksum of the last record is discarded as a torn write.
+        corrupt_from_end(directory, 1);
+        assert_eq!(
+            Some((vec![TableMeta::new(0, FLUSHED_LEVEL)], 1)),
+            Manifest::load(directory).await?
+        );
+
+        // The record before it is not the last one.
+        let last_record_length = encode_record(&[
+            VersionEdit::AddTable(TableMeta::new(1, FLUSHED_LEVEL)),
+            VersionEdit::SetNextTableNumber(2),
+        ])
+        .len();
+        corrupt_from_end(directory, 1);
+        corrupt_from_end(directory, last_record_length + 1);
+        let err = Manifest::load(directory).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_broken_first_record() -> io::Result<()> {
+        let directory = "test_manifest_reject_broken_first_record";
+        prepare_directory(directory);
+        let tables = vec![TableMeta::new(0, FLUSHED_LEVEL)];
+        drop(Manifest::create(directory, tables, 1, false).await?);
+        corrupt_from_end(directory, 1);
+        let err = Manifest::load(directory).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn load_without_checksums() -> io::Result<()> {
+        let directory = "test_manifest_load_without_checksums";
+        prepare_directory(directory);
+        let mut bytes = Vec::new();
+        for edits in [
+            vec![VersionEdit::SetNextTableNumber(0)],
+            vec![
+                VersionEdit::AddTable(TableMeta::new(0, FLUSHED_LEVEL)),
+                VersionEdit::SetNextTableNumber(1),
+            ],
+        ] {
+            let mut content = serialize(&edits).unwrap();
+            bytes.append(&mut serialize(&content.len()).unwrap());
+            bytes.append(&mut content);
+        }
+        std::fs::write(manifest_path(directory), bytes)?;
+        assert_eq!(
+            Some((vec![TableMeta::new(0, FLUSHED_LEVEL)], 1)),
+            Manifest::load(directory).await?
+        );
+        Ok(())
+    }
+}
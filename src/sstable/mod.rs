@@ -1,14 +1,26 @@
+mod bloom;
+mod cache;
 mod index;
+mod iterator;
 pub mod manager;
+mod manifest;
 mod storage;
 mod table;
 
+pub(crate) use storage::{sync_directory, temporary_path};
+
 #[cfg(test)]
 pub(crate) mod tests {
     use std::fs::{File, OpenOptions};
     use std::io::{self, Read, Write};
     use std::path::Path;
 
+    /// Create an empty directory, removing contents left by previous runs.
+    pub(crate) fn prepare_directory<P: AsRef<Path>>(path: P) {
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir(&path).unwrap();
+    }
+
     pub(crate) fn read_file_to_buffer<P: AsRef<Path>>(path: P) -> Vec<u8> {
         let mut file = File::open(path).unwrap();
         let mut buffer: Vec<u8> = vec![];
@@ -19,6 +31,7 @@ pub(crate) mod tests {
     pub(crate) fn prepare_sstable_file<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
         let mut file = OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .read(true)
             .open(path)?;
@@ -1,9 +1,14 @@
-use crate::format::InternalPair;
+use crate::error::Corruption;
+use crate::format::{self, InternalPair};
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 use tokio::fs::{self, File, OpenOptions};
 use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+/// Extension of a file which is being written.
+/// Such a file left in a directory means a crash happened while writing it.
+const TEMPORARY_FILE_EXTENSION: &str = "tmp";
+
 /// Represents manipulating an SSTable file.
 /// Contents of the file will never be modified.
 #[derive(Debug)]
@@ -14,56 +19,111 @@ pub struct PersistedFile {
     /// SSTable file name.
     /// This is because file name cannot be extracted `std::tokio::fs::File`.
     file_name: PathBuf,
+
+    /// Version of the encoding of pairs in the file.
+    version: u32,
 }
 
 impl PersistedFile {
-    /// Serialize and write array of `InternalePair` and return a new `PersistedFile` instance.
-    pub async fn new<P: AsRef<Path>>(path: P, pairs: &[InternalPair]) -> io::Result<Self> {
+    /// Write `contents` encoded in `version` and return a new `PersistedFile` instance.
+    /// Data is written to a temporary file first and the file is renamed to `path` after all
+    /// data is written, so a file at `path` never has partial contents.
+    /// If `sync` is `true`, the file and the rename are synchronized to a disk.
+    pub async fn new<P: AsRef<Path>>(
+        path: P,
+        contents: &[u8],
+        version: u32,
+        sync: bool,
+    ) -> io::Result<Self> {
         let mut path_buf = PathBuf::new();
         path_buf.push(path);
+        let temporary_path = temporary_path(&path_buf);
         let mut file = OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .read(true)
-            .open(&path_buf)
+            .open(&temporary_path)
             .await?;
 
-        let data = InternalPair::serialize_flatten(&pairs);
-        file.write_all(&data).await?;
+        file.write_all(contents).await?;
+        file.flush().await?;
+        if sync {
+            file.sync_all().await?;
+        }
+        fs::rename(&temporary_path, &path_buf).await?;
+        if sync {
+            sync_directory(parent_directory(&path_buf)).await?;
+        }
         file.seek(SeekFrom::Start(0)).await?;
         Ok(Self {
             file,
             file_name: path_buf,
+            version,
         })
     }
 
-    /// Create an instance based on an existing file.
-    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    /// Create an instance based on an existing file whose pairs are encoded in `version`.
+    pub async fn open<P: AsRef<Path>>(path: P, version: u32) -> io::Result<Self> {
         let mut path_buf = PathBuf::new();
         path_buf.push(path);
         let file = File::open(path_buf.as_path()).await?;
         Ok(Self {
             file,
             file_name: path_buf,
+            version,
         })
     }
 
-    /// Read file contents at `position` by `length`.
-    pub async fn read_at(&mut self, position: usize, length: usize) -> io::Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(position as u64)).await?;
+    /// Version of the encoding of pairs in the file.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Length of the file in bytes.
+    pub async fn file_size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata().await?.len())
+    }
+
+    /// Read bytes at `position` by `length` as they are.
+    pub async fn read_exact_at(&mut self, position: u64, length: usize) -> io::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(position)).await?;
         let mut bytes = vec![0; length];
         self.file.read_exact(&mut bytes).await?;
         Ok(bytes)
     }
 
-    /// Read all file contents.
+    /// Read a block at `position` by `length`, verify its checksum and return serialized data
+    /// in it.
+    /// If the block is broken, return an error which holds `Corruption`.
+    pub async fn read_at(&mut self, position: usize, length: usize) -> io::Result<Vec<u8>> {
+        let bytes = self.read_exact_at(position as u64, length).await?;
+        let data = format::verify_block(&bytes, self.version).map_err(|err| {
+            Corruption(format!(
+                "{:?} at position {}: {}",
+                self.file_name, position, err
+            ))
+        })?;
+        Ok(data.to_vec())
+    }
+
+    /// Read all pairs in a file which consists only of blocks of pairs.
+    /// Files since `INDEX_FORMAT_VERSION` also have an index and a footer, so their pairs must
+    /// be read block by block.
     pub async fn read_all(&mut self) -> io::Result<Vec<InternalPair>> {
         self.file.seek(SeekFrom::Start(0)).await?;
         let mut buffer = Vec::new();
         self.file.read_to_end(&mut buffer).await?;
-        Ok(InternalPair::deserialize_from_bytes(&mut buffer)
-            .await
-            .unwrap())
+        let blocks = format::split_blocks(&buffer, self.version)
+            .map_err(|err| Corruption(format!("{:?}: {}", self.file_name, err)))?;
+        let mut pairs = Vec::new();
+        for block in blocks {
+            let mut block_pairs = InternalPair::deserialize_from_bytes(block, self.version)
+                .await
+                .map_err(|err| Corruption(format!("{:?}: {}", self.file_name, err)))?;
+            pairs.append(&mut block_pairs);
+        }
+        Ok(pairs)
     }
 
     pub async fn delete(&mut self) -> io::Result<()> {
@@ -71,9 +131,44 @@ impl PersistedFile {
     }
 }
 
+/// Synchronize a directory to a disk so that creation and removal of files in it are durable.
+pub async fn sync_directory<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    File::open(path).await?.sync_all().await
+}
+
+/// Path to write contents which will be moved to `path` after completed.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    path.with_extension(TEMPORARY_FILE_EXTENSION)
+}
+
+/// Remove temporary files left in `directory` by a crash while writing SSTables.
+/// Return the number of removed files.
+pub async fn remove_temporary_files<P: AsRef<Path>>(directory: P) -> io::Result<usize> {
+    let mut removed = 0;
+    let mut entries = fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension() == Some(TEMPORARY_FILE_EXTENSION.as_ref()) {
+            fs::remove_file(&path).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Directory which contains `path`.
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::format::FORMAT_VERSION;
+    use crate::sstable::tests::{prepare_directory, prepare_sstable_file};
 
     #[tokio::test]
     async fn read() -> io::Result<()> {
@@ -81,16 +176,23 @@ mod tests {
             InternalPair::new(b"abc00", Some(b"def")),
             InternalPair::new(b"abc01", None),
         ];
-        let mut file = PersistedFile::new("test_read", &pairs).await?;
+        let block = format::encode_block(&pairs, FORMAT_VERSION);
+        let mut file = PersistedFile::new("test_read", &block, FORMAT_VERSION, false).await?;
         let mut buffer = Vec::new();
         file.file.read_to_end(&mut buffer).await?;
         assert_eq!(
             vec![
-                5, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 48, 100, 101, 102,
-                5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 49,
+                63, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 3, 0,
+                0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 48, 100, 101, 102, 1, 0, 0, 0, 0, 0, 0, 0, 0, 5,
+                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 98, 99, 48, 49, 212, 198, 136, 69,
             ],
             buffer
         );
+        assert_eq!(
+            InternalPair::serialize_flatten(&pairs),
+            file.read_at(0, block.len()).await?
+        );
+        assert_eq!(block.len() as u64, file.file_size().await?);
         Ok(())
     }
 
@@ -101,8 +203,42 @@ mod tests {
             InternalPair::new(b"abc01", Some(b"xxx")),
             InternalPair::new(b"abc02", None),
         ];
-        let mut file = PersistedFile::new("test_read_all", &pairs).await?;
+        let data: Vec<u8> = pairs
+            .chunks(2)
+            .flat_map(|chunk| format::encode_block(chunk, FORMAT_VERSION))
+            .collect();
+        let mut file = PersistedFile::new("test_read_all", &data, FORMAT_VERSION, true).await?;
         assert_eq!(pairs, file.read_all().await?);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn leave_no_temporary_file() -> io::Result<()> {
+        let directory = "test_leave_no_temporary_file";
+        prepare_directory(directory);
+        PersistedFile::new(
+            "test_leave_no_temporary_file/table_0",
+            b"data",
+            FORMAT_VERSION,
+            true,
+        )
+        .await?;
+        let file_names: Vec<_> = std::fs::read_dir(directory)?
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec!["table_0"], file_names);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_temporary() -> io::Result<()> {
+        let directory = "test_remove_temporary";
+        prepare_directory(directory);
+        prepare_sstable_file("test_remove_temporary/table_0", b"")?;
+        prepare_sstable_file("test_remove_temporary/table_1.tmp", b"")?;
+        assert_eq!(1, remove_temporary_files(directory).await?);
+        assert!(Path::new("test_remove_temporary/table_0").exists());
+        assert!(!Path::new("test_remove_temporary/table_1.tmp").exists());
+        Ok(())
+    }
 }
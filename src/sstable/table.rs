@@ -1,12 +1,21 @@
+use super::bloom::BloomFilter;
+use super::cache::BlockCache;
 use super::index::Index;
 use super::storage::PersistedFile;
-use crate::format::InternalPair;
+use crate::error::Corruption;
+use crate::format::{
+    self, Footer, InternalPair, FILTER_FORMAT_VERSION, FORMAT_VERSION, INDEX_FORMAT_VERSION,
+};
 use std::io;
 use std::path::Path;
+use std::sync::Arc;
 
 /// Represents an SSTable.
 #[derive(Debug)]
 pub struct SSTable {
+    /// Number in the file name of this table.
+    pub(crate) number: u64,
+
     /// API to access an SSTable file.
     pub(crate) file: PersistedFile,
 
@@ -15,63 +24,202 @@ pub struct SSTable {
 
     /// Stores pairs of key and position to start read the key from the file.
     pub(crate) index: Index,
+
+    /// Filter to tell a key is not in this table without reading the file.
+    filter: BloomFilter,
 }
 
 impl SSTable {
-    /// Create a new instance of `Table`.
-    pub fn new(
-        file: PersistedFile,
-        pairs: Vec<InternalPair>,
+    /// Write `pairs` to a new file at `path` in the current format and create an instance for it.
+    /// The file consists of blocks of every `block_stride` pairs, a Bloom filter using
+    /// `bits_per_key` bits for each key, the index for the blocks and a footer.
+    /// If `sync` is `true`, the file is synchronized to a disk.
+    pub async fn create<P: AsRef<Path>>(
+        number: u64,
+        path: P,
+        pairs: &[InternalPair],
         size: usize,
         block_stride: usize,
+        bits_per_key: usize,
+        sync: bool,
     ) -> io::Result<Self> {
-        let index = Index::new(pairs, block_stride);
-        Ok(Self { file, size, index })
+        let (index, mut contents) = Index::build(pairs, block_stride, FORMAT_VERSION);
+        let filter = BloomFilter::new(pairs.iter().map(|pair| pair.key.as_slice()), bits_per_key);
+        let filter_position = contents.len() as u64;
+        let mut filter_block = format::frame_block(filter.encode());
+        let filter_length = filter_block.len() as u64;
+        contents.append(&mut filter_block);
+        let mut index_block = index.encode();
+        let footer = Footer {
+            index_position: contents.len() as u64,
+            index_length: index_block.len() as u64,
+            size: size as u64,
+            filter_position,
+            filter_length,
+            version: FORMAT_VERSION,
+        };
+        contents.append(&mut index_block);
+        contents.append(&mut footer.encode());
+        let file = PersistedFile::new(path, &contents, FORMAT_VERSION, sync).await?;
+        Ok(Self {
+            number,
+            file,
+            size,
+            index,
+            filter,
+        })
+    }
+
+    /// Open an existing file whose contents are encoded in `version`.
+    /// Since `INDEX_FORMAT_VERSION`, only the footer, the filter and the index are read.
+    /// Files in older versions have no index, so all pairs in them are read to build it.
+    pub async fn open<P: AsRef<Path>>(
+        number: u64,
+        path: P,
+        version: u32,
+        block_stride: usize,
+    ) -> io::Result<Self> {
+        let mut file = PersistedFile::open(path, version).await?;
+        if version < INDEX_FORMAT_VERSION {
+            return Self::open_without_index(number, file, block_stride).await;
+        }
+
+        let file_size = file.file_size().await?;
+        let footer_length = Footer::length(version);
+        let footer_position = file_size
+            .checked_sub(footer_length as u64)
+            .ok_or_else(|| Corruption(format!("Too short table {}", number)))?;
+        let footer_bytes = file.read_exact_at(footer_position, footer_length).await?;
+        let footer = Footer::decode(&footer_bytes, version)
+            .map_err(|err| Corruption(format!("Broken footer in table {}: {}", number, err)))?;
+        if footer.version != version {
+            return Err(Corruption(format!(
+                "Table {} is in version {} but expected {}",
+                number, footer.version, version
+            ))
+            .into());
+        }
+        if footer.index_position.checked_add(footer.index_length) != Some(footer_position) {
+            return Err(Corruption(format!(
+                "Invalid index location in table {}: position {}, length {}",
+                number, footer.index_position, footer.index_length
+            ))
+            .into());
+        }
+        let index_data = file
+            .read_at(footer.index_position as usize, footer.index_length as usize)
+            .await?;
+        let index = Index::decode(&index_data)
+            .map_err(|err| Corruption(format!("Broken index in table {}: {}", number, err)))?;
+        let filter = if version >= FILTER_FORMAT_VERSION {
+            if footer.filter_position.checked_add(footer.filter_length)
+                != Some(footer.index_position)
+            {
+                return Err(Corruption(format!(
+                    "Invalid filter location in table {}: position {}, length {}",
+                    number, footer.filter_position, footer.filter_length
+                ))
+                .into());
+            }
+            let filter_data = file
+                .read_at(
+                    footer.filter_position as usize,
+                    footer.filter_length as usize,
+                )
+                .await?;
+            BloomFilter::decode(filter_data)
+        } else {
+            BloomFilter::default()
+        };
+
+        Ok(Self {
+            number,
+            file,
+            size: footer.size as usize,
+            index,
+            filter,
+        })
     }
 
-    /// Open existing file and load key-value pairs in it.
-    pub async fn open<P: AsRef<Path>>(path: P, block_stride: usize) -> io::Result<Self> {
-        let mut file = PersistedFile::open(path).await?;
+    /// Load all key-value pairs in a file without an index and build the index.
+    async fn open_without_index(
+        number: u64,
+        mut file: PersistedFile,
+        block_stride: usize,
+    ) -> io::Result<Self> {
         let pairs = file.read_all().await?;
-        let size = pairs
-            .iter()
-            .map(|pair| {
-                pair.key.len()
-                    + match pair.value.as_ref() {
-                        Some(value) => value.len(),
-                        None => 0,
-                    }
-            })
-            .sum();
-        let index = Index::new(pairs, block_stride);
+        let size = pairs.iter().map(InternalPair::size).sum();
+        let index = Index::new(&pairs, block_stride, file.version());
 
-        Ok(Self { file, size, index })
+        Ok(Self {
+            number,
+            file,
+            size,
+            index,
+            filter: BloomFilter::default(),
+        })
     }
 
-    /// Get key-value pair from SSTable file.
+    /// Return `false` if `key` is definitely not in this table.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.filter.may_contain(key)
+    }
+
+    /// Get the newest version of a key-value pair whose sequence number is not greater than
+    /// `sequence` from SSTable file.
     /// First, find block which stores the target pair.
-    /// Then search the block from the front.
-    pub async fn get(&mut self, key: &[u8]) -> io::Result<Option<InternalPair>> {
+    /// Then search the block, where versions of a key are sorted from the newest one.
+    /// Blocks are looked up in `cache` first and cached after read from the file.
+    pub async fn get(
+        &mut self,
+        key: &[u8],
+        sequence: u64,
+        cache: &mut BlockCache,
+    ) -> io::Result<Option<InternalPair>> {
         let (search_origin, length) = match self.index.get(key) {
             Some(pos) => pos,
             None => return Ok(None),
         };
-        let mut block_bytes = self.file.read_at(search_origin, length).await?;
-
-        // Handle this Result
-        let pairs = InternalPair::deserialize_from_bytes(&mut block_bytes)
-            .await
-            .unwrap();
-        let pair = match pairs.binary_search_by_key(&key, |entry| &entry.key) {
-            Ok(pos) => Some(pairs[pos].clone()),
-            Err(_) => None,
+        let pairs = match cache.get(self.number, search_origin) {
+            Some(pairs) => pairs,
+            None => {
+                let pairs = Arc::new(self.read_block(search_origin, length).await?);
+                cache.insert(self.number, search_origin, Arc::clone(&pairs), length);
+                pairs
+            }
         };
+        let position = pairs.partition_point(|entry| {
+            entry.key.as_slice() < key || (entry.key == key && entry.sequence > sequence)
+        });
+        let pair = pairs
+            .get(position)
+            .filter(|entry| entry.key == key)
+            .cloned();
         Ok(pair)
     }
 
     /// Get all key-value pairs in the file.
+    /// Blocks read here are not cached not to evict frequently used blocks.
     pub async fn get_all(&mut self) -> io::Result<Vec<InternalPair>> {
-        self.file.read_all().await
+        let mut pairs = Vec::new();
+        let blocks: Vec<_> = self.index.blocks().collect();
+        for (position, length) in blocks {
+            pairs.append(&mut self.read_block(position, length).await?);
+        }
+        Ok(pairs)
+    }
+
+    /// Read a block at `position` by `length` and deserialize pairs in it.
+    pub async fn read_block(
+        &mut self,
+        position: usize,
+        length: usize,
+    ) -> io::Result<Vec<InternalPair>> {
+        let block_bytes = self.file.read_at(position, length).await?;
+        let pairs = InternalPair::deserialize_from_bytes(&block_bytes, self.file.version())
+            .await
+            .map_err(|err| Corruption(format!("Broken pair in table {}: {}", self.number, err)))?;
+        Ok(pairs)
     }
 
     /// Get the size of data in this SSTable.
@@ -79,6 +227,11 @@ impl SSTable {
         self.size
     }
 
+    /// Number of bytes of memory the index and the filter use.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.index.memory_usage() + self.filter.memory_usage()
+    }
+
     /// Delete the SSTable file.
     pub async fn delete(&mut self) -> io::Result<()> {
         self.file.delete().await
@@ -88,22 +241,42 @@ impl SSTable {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::Error;
+    use crate::format::{self, BLOCK_CHECKSUM_FORMAT_VERSION};
     use crate::sstable::tests::*;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
 
     #[tokio::test]
     async fn create_table() -> io::Result<()> {
         let path = "test_create_table";
         let pairs = vec![
-            InternalPair::new(b"abc", Some(b"defg")),
-            InternalPair::new(b"abc", None),
+            InternalPair::new(b"abc", Some(b"defg")).with_sequence(2),
+            InternalPair::new(b"abc", None).with_sequence(1),
             InternalPair::new("日本語💖".as_bytes(), Some("ржавчина".as_bytes())),
         ];
-        let file = PersistedFile::new(path, &pairs).await?;
-        let _table = SSTable::new(file, pairs.clone(), 39, 1)?;
-        assert_eq!(
-            InternalPair::serialize_flatten(&pairs),
-            read_file_to_buffer(path)
-        );
+        let table = SSTable::create(0, path, &pairs, 39, 1, 10, false).await?;
+        // Versions of the same key are in the same block.
+        let mut expected: Vec<u8> = [&pairs[..2], &pairs[2..]]
+            .iter()
+            .flat_map(|chunk| format::encode_block(chunk, FORMAT_VERSION))
+            .collect();
+        let filter_position = expected.len() as u64;
+        let mut filter_block = format::frame_block(table.filter.encode());
+        let filter_length = filter_block.len() as u64;
+        expected.append(&mut filter_block);
+        let mut index_block = table.index.encode();
+        let footer = Footer {
+            index_position: expected.len() as u64,
+            index_length: index_block.len() as u64,
+            size: 39,
+            filter_position,
+            filter_length,
+            version: FORMAT_VERSION,
+        };
+        expected.append(&mut index_block);
+        expected.append(&mut footer.encode());
+        assert_eq!(expected, read_file_to_buffer(path));
         Ok(())
     }
 
@@ -128,18 +301,49 @@ mod tests {
             InternalPair::new(b"abc14", None),
             InternalPair::new(b"abc15", None),
         ];
-        let file = PersistedFile::new(path, &pairs).await?;
-        let mut table = SSTable::new(file, pairs, 113, 3)?;
+        let mut table = SSTable::create(0, path, &pairs, 113, 3, 10, false).await?;
+        let mut cache = BlockCache::new(1024);
         assert_eq!(
             Some(InternalPair::new(b"abc04", Some(b"defg"))),
-            table.get(b"abc04").await?
+            table.get(b"abc04", u64::MAX, &mut cache).await?
         );
         assert_eq!(
             Some(InternalPair::new(b"abc15", None)),
-            table.get(b"abc15").await?
+            table.get(b"abc15", u64::MAX, &mut cache).await?
         );
-        assert_eq!(None, table.get(b"abc011").await?);
-        assert_eq!(None, table.get(b"abc16").await?);
+        assert_eq!(None, table.get(b"abc011", u64::MAX, &mut cache).await?);
+        assert_eq!(None, table.get(b"abc16", u64::MAX, &mut cache).await?);
+        // The last block is read from the cache for "abc16".
+        assert_eq!(1, cache.hits());
+        assert_eq!(3, cache.misses());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_versions() -> io::Result<()> {
+        let path = "test_search_versions";
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")).with_sequence(2),
+            InternalPair::new(b"abc01", Some(b"new")).with_sequence(6),
+            InternalPair::new(b"abc01", None).with_sequence(4),
+            InternalPair::new(b"abc01", Some(b"old")).with_sequence(1),
+            InternalPair::new(b"abc02", Some(b"xyz")).with_sequence(3),
+        ];
+        let mut table = SSTable::create(0, path, &pairs, 21, 2, 10, false).await?;
+        let mut cache = BlockCache::new(1024);
+        for (sequence, expected) in [
+            (u64::MAX, Some(&pairs[1])),
+            (6, Some(&pairs[1])),
+            (5, Some(&pairs[2])),
+            (3, Some(&pairs[3])),
+            (0, None),
+        ] {
+            assert_eq!(
+                expected.cloned(),
+                table.get(b"abc01", sequence, &mut cache).await?
+            );
+        }
+        assert_eq!(None, table.get(b"abc02", 2, &mut cache).await?);
         Ok(())
     }
 
@@ -151,8 +355,7 @@ mod tests {
             InternalPair::new(b"abc01", Some(b"defg")),
             InternalPair::new(b"abc02", None),
         ];
-        let file = PersistedFile::new(path, &pairs).await?;
-        let mut table = SSTable::new(file, pairs, 22, 3)?;
+        let mut table = SSTable::create(0, path, &pairs, 22, 3, 10, false).await?;
         let mut pairs = table.get_all().await?.into_iter();
         assert_eq!(
             Some(InternalPair::new(b"abc00", Some(b"def"))),
@@ -175,12 +378,93 @@ mod tests {
             InternalPair::new(b"abc01", Some(b"defg")),
             InternalPair::new(b"abc02", None),
         ];
-        let data = InternalPair::serialize_flatten(&pairs);
+        let created = SSTable::create(0, path, &pairs, 22, 2, 10, false).await?;
+
+        let mut table = SSTable::open(0, path, FORMAT_VERSION, 2).await?;
+
+        let mut cache = BlockCache::new(1024);
+        assert_eq!(22, table.get_size());
+        assert_eq!(created.filter, table.filter);
+        assert!(table.may_contain(b"abc00"));
+        assert_eq!(
+            Some(InternalPair::new(b"abc01", Some(b"defg"))),
+            table.get(b"abc01", u64::MAX, &mut cache).await?
+        );
+        let opened_pairs = table.get_all().await?;
+        assert_eq!(pairs, opened_pairs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_file_without_index() -> io::Result<()> {
+        let path = "test_open_file_without_index";
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc01", Some(b"defg")),
+            InternalPair::new(b"abc02", None),
+        ];
+        let version = BLOCK_CHECKSUM_FORMAT_VERSION;
+        let data: Vec<u8> = pairs
+            .chunks(2)
+            .flat_map(|chunk| format::encode_block(chunk, version))
+            .collect();
         prepare_sstable_file(path, &data)?;
 
-        let mut table = SSTable::open(path, 3).await?;
+        let mut table = SSTable::open(0, path, version, 2).await?;
+
+        let mut cache = BlockCache::new(1024);
+        assert_eq!(22, table.get_size());
+        assert_eq!(
+            Some(InternalPair::new(b"abc02", None)),
+            table.get(b"abc02", u64::MAX, &mut cache).await?
+        );
         let opened_pairs = table.get_all().await?;
         assert_eq!(pairs, opened_pairs);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn detect_broken_footer() -> io::Result<()> {
+        let path = "test_detect_broken_footer";
+        let pairs = vec![InternalPair::new(b"abc00", Some(b"def"))];
+        SSTable::create(0, path, &pairs, 8, 2, 10, false).await?;
+        let mut data = read_file_to_buffer(path);
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        prepare_sstable_file(path, &data)?;
+
+        let err = SSTable::open(0, path, FORMAT_VERSION, 2).await.unwrap_err();
+        assert!(matches!(Error::from(err), Error::Corruption(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detect_corruption() -> io::Result<()> {
+        let path = "test_detect_corruption";
+        let pairs = vec![
+            InternalPair::new(b"abc00", Some(b"def")),
+            InternalPair::new(b"abc01", Some(b"defg")),
+            InternalPair::new(b"abc02", Some(b"de")),
+            InternalPair::new(b"abc03", Some(b"defgh")),
+        ];
+        let mut table = SSTable::create(0, path, &pairs, 30, 2, 10, false).await?;
+        let mut cache = BlockCache::new(1024);
+
+        // Flip a bit in the key length of the first pair.
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(9))?;
+        file.write_all(&[0x80])?;
+        file.sync_all()?;
+
+        let err = table.get(b"abc00", u64::MAX, &mut cache).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(matches!(Error::from(err), Error::Corruption(_)));
+        // The other block is still readable.
+        assert_eq!(
+            Some(InternalPair::new(b"abc03", Some(b"defgh"))),
+            table.get(b"abc03", u64::MAX, &mut cache).await?
+        );
+        assert!(table.get_all().await.is_err());
+        Ok(())
+    }
 }
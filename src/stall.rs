@@ -0,0 +1,306 @@
+use crate::budget::MemoryBudget;
+use crate::error::Error;
+use log::{debug, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+/// Work which flushes and compaction have not finished yet.
+/// This is also used as limits of the work, where a limit is reached if any of the fields is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Backlog {
+    /// Number of live SSTables, each of which a read may look up.
+    pub tables: usize,
+
+    /// Bytes of SSTables newer than the oldest one, which compaction merges into it.
+    pub pending_compaction_bytes: usize,
+
+    /// Number of full MemTables being flushed.
+    pub immutables: usize,
+}
+
+impl Backlog {
+    /// Backlog which never reaches limits.
+    pub const UNLIMITED: Backlog = Backlog {
+        tables: usize::MAX,
+        pending_compaction_bytes: usize::MAX,
+        immutables: usize::MAX,
+    };
+
+    /// Return `true` if any kind of the work reaches `limit`.
+    pub fn reaches(&self, limit: &Backlog) -> bool {
+        self.tables >= limit.tables
+            || self.pending_compaction_bytes >= limit.pending_compaction_bytes
+            || self.immutables >= limit.immutables
+    }
+}
+
+/// Gate of writes which slows them down when flushes or compaction fall behind, so that
+/// SSTables and MemTables do not pile up.
+/// If the backlog reaches the soft limit, each write is delayed. If it reaches the hard limit,
+/// or memory exceeds the budget even after the block cache is evicted, writes wait until it
+/// goes below, or fail after a deadline.
+#[derive(Debug)]
+pub struct WriteController {
+    soft_limit: Backlog,
+    hard_limit: Backlog,
+
+    /// Delay of each write while the backlog is at the soft limit.
+    delay: Duration,
+
+    /// How long a write waits at the hard limit before it fails.
+    deadline: Duration,
+
+    tables: AtomicUsize,
+    pending_compaction_bytes: AtomicUsize,
+    immutables: AtomicUsize,
+
+    /// Notified when the backlog is reduced.
+    reduced: Notify,
+
+    /// Memory shared by components which report their backlog here.
+    memory_budget: MemoryBudget,
+}
+
+impl Default for WriteController {
+    /// Never stall writes.
+    fn default() -> Self {
+        Self::new(
+            Backlog::UNLIMITED,
+            Backlog::UNLIMITED,
+            Duration::ZERO,
+            Duration::ZERO,
+        )
+    }
+}
+
+impl WriteController {
+    pub fn new(
+        soft_limit: Backlog,
+        hard_limit: Backlog,
+        delay: Duration,
+        deadline: Duration,
+    ) -> Self {
+        Self {
+            soft_limit,
+            hard_limit,
+            delay,
+            deadline,
+            tables: AtomicUsize::new(0),
+            pending_compaction_bytes: AtomicUsize::new(0),
+            immutables: AtomicUsize::new(0),
+            reduced: Notify::new(),
+            memory_budget: MemoryBudget::default(),
+        }
+    }
+
+    /// Limit memory of `MemTable` and SSTables with `memory_budget`.
+    pub fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// Memory shared by `MemTable` and SSTables.
+    pub fn memory_budget(&self) -> &MemoryBudget {
+        &self.memory_budget
+    }
+
+    /// Limit of the backlog at which writes are slowed down.
+    pub fn soft_limit(&self) -> &Backlog {
+        &self.soft_limit
+    }
+
+    /// Limit of the backlog at which writes wait.
+    pub fn hard_limit(&self) -> &Backlog {
+        &self.hard_limit
+    }
+
+    /// Current backlog.
+    pub fn backlog(&self) -> Backlog {
+        Backlog {
+            tables: self.tables.load(Ordering::Acquire),
+            pending_compaction_bytes: self.pending_compaction_bytes.load(Ordering::Acquire),
+            immutables: self.immutables.load(Ordering::Acquire),
+        }
+    }
+
+    /// Record SSTables after they are created or compacted.
+    pub(crate) fn set_tables(&self, tables: usize, pending_compaction_bytes: usize) {
+        self.tables.store(tables, Ordering::Release);
+        self.pending_compaction_bytes
+            .store(pending_compaction_bytes, Ordering::Release);
+        self.reduced.notify_waiters();
+    }
+
+    /// Record the number of MemTables being flushed.
+    pub(crate) fn set_immutables(&self, immutables: usize) {
+        self.immutables.store(immutables, Ordering::Release);
+        self.reduced.notify_waiters();
+    }
+
+    /// Wait until a write is allowed.
+    /// Writes wait for memory over the budget only while MemTables are being flushed, because
+    /// nothing else frees it. Otherwise a write may grow the active MemTable until it is large
+    /// enough to be flushed.
+    /// Return `Error::WriteStalled` if the backlog stays at the hard limit or memory stays over
+    /// the budget until the deadline.
+    pub(crate) async fn admit(&self) -> Result<(), Error> {
+        let deadline = Instant::now() + self.deadline;
+        loop {
+            // Created before checking the backlog not to miss a notification in between.
+            let reduced = self.reduced.notified();
+            let backlog = self.backlog();
+            let exceeds_memory = backlog.immutables > 0 && self.memory_budget.exceeds_limit();
+            if !backlog.reaches(&self.hard_limit) && !exceeds_memory {
+                break;
+            }
+            debug!(
+                "Writes are stopped: {:?}, exceeds memory budget: {}",
+                backlog, exceeds_memory
+            );
+            if time::timeout_at(deadline, reduced).await.is_err() {
+                warn!("Writes are stalled longer than {:?}", self.deadline);
+                return Err(Error::WriteStalled(self.retry_after()));
+            }
+        }
+        if self.backlog().reaches(&self.soft_limit) {
+            time::sleep(self.delay).await;
+        }
+        Ok(())
+    }
+
+    /// Seconds a client should wait before it retries a stalled write.
+    fn retry_after(&self) -> u64 {
+        self.deadline.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memtable::MemoryUsage;
+    use std::sync::Arc;
+
+    /// Controller which only limits the number of immutable MemTables to `max_immutables`.
+    /// Writes wait for flushes long enough not to fail.
+    pub(crate) fn limit_immutables(max_immutables: usize) -> Arc<WriteController> {
+        let hard_limit = Backlog {
+            immutables: max_immutables,
+            ..Backlog::UNLIMITED
+        };
+        Arc::new(WriteController::new(
+            Backlog::UNLIMITED,
+            hard_limit,
+            Duration::ZERO,
+            Duration::from_secs(60),
+        ))
+    }
+
+    const SOFT_LIMIT: Backlog = Backlog {
+        tables: 4,
+        pending_compaction_bytes: 1000,
+        immutables: 2,
+    };
+
+    const HARD_LIMIT: Backlog = Backlog {
+        tables: 8,
+        pending_compaction_bytes: 2000,
+        immutables: 3,
+    };
+
+    #[test]
+    fn reach_limit() {
+        let mut backlog = Backlog {
+            tables: 3,
+            pending_compaction_bytes: 999,
+            immutables: 1,
+        };
+        assert!(!backlog.reaches(&SOFT_LIMIT));
+        backlog.pending_compaction_bytes = 1000;
+        assert!(backlog.reaches(&SOFT_LIMIT));
+        assert!(!backlog.reaches(&HARD_LIMIT));
+        assert!(!backlog.reaches(&Backlog::UNLIMITED));
+    }
+
+    #[tokio::test]
+    async fn slow_down_writes() {
+        let controller = WriteController::new(
+            SOFT_LIMIT,
+            HARD_LIMIT,
+            Duration::from_millis(50),
+            Duration::from_secs(1),
+        );
+        let start = Instant::now();
+        assert_eq!(Ok(()), controller.admit().await);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        controller.set_tables(4, 0);
+        let start = Instant::now();
+        assert_eq!(Ok(()), controller.admit().await);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn stop_writes_over_memory_budget() {
+        let controller = Arc::new(
+            WriteController::new(
+                SOFT_LIMIT,
+                HARD_LIMIT,
+                Duration::ZERO,
+                Duration::from_millis(100),
+            )
+            .with_memory_budget(MemoryBudget::new(1000)),
+        );
+        let budget = controller.memory_budget();
+        // The block cache can be evicted.
+        budget.set_block_cache(2000);
+        assert_eq!(Ok(()), controller.admit().await);
+
+        budget.set_indexes(600);
+        budget.set_memtable(MemoryUsage {
+            active: 100,
+            immutables: 400,
+        });
+        controller.set_immutables(1);
+        assert_eq!(Err(Error::WriteStalled(1)), controller.admit().await);
+
+        let admitted = tokio::spawn({
+            let controller = controller.clone();
+            async move { controller.admit().await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        controller.memory_budget().set_memtable(MemoryUsage {
+            active: 100,
+            immutables: 0,
+        });
+        controller.set_immutables(0);
+        assert_eq!(Ok(()), admitted.await.unwrap());
+
+        // Without MemTables being flushed, writes go on even if indexes alone exceed the budget.
+        budget.set_indexes(2000);
+        assert_eq!(Ok(()), controller.admit().await);
+    }
+
+    #[tokio::test]
+    async fn stop_writes() {
+        let controller = Arc::new(WriteController::new(
+            SOFT_LIMIT,
+            HARD_LIMIT,
+            Duration::ZERO,
+            Duration::from_millis(100),
+        ));
+        controller.set_immutables(3);
+        let start = Instant::now();
+        assert_eq!(Err(Error::WriteStalled(1)), controller.admit().await);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let admitted = tokio::spawn({
+            let controller = controller.clone();
+            async move { controller.admit().await }
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        controller.set_immutables(2);
+        assert_eq!(Ok(()), admitted.await.unwrap());
+    }
+}